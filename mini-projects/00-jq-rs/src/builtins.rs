@@ -0,0 +1,1014 @@
+use crate::date;
+use crate::filter::{describe, type_name, FilterError};
+use crate::ops::{self, compare};
+use crate::output;
+use serde_json::Value;
+use std::cmp::Ordering;
+
+// Built-ins that map each input to exactly one output, e.g. `ascii_downcase`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Builtin {
+    ToString,
+    ToNumber,
+    ToJson,
+    FromJson,
+    AsciiDowncase,
+    AsciiUpcase,
+    Explode,
+    Implode,
+    Ascii,
+    Sort,
+    Unique,
+    Min,
+    Max,
+    Reverse,
+    Flatten,
+    Transpose,
+    Now,
+    Gmtime,
+    Mktime,
+    ToDate,
+    FromDate,
+}
+
+// Built-ins taking one argument, run once for every output of the argument,
+// e.g. `split(",")`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BuiltinArg {
+    Split,
+    Join,
+    Ltrimstr,
+    Rtrimstr,
+    Startswith,
+    Endswith,
+    Flatten,
+    Indices,
+    Index,
+    Rindex,
+    Contains,
+    Inside,
+    Strftime,
+    Strptime,
+}
+
+// Built-ins ordering the elements of an array by the outputs of their
+// argument, e.g. `sort_by(.name)`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ByOp {
+    Sort,
+    Group,
+    Unique,
+    Min,
+    Max,
+}
+
+impl Builtin {
+    pub fn from_name(name: &str) -> Option<Builtin> {
+        let builtin = match name {
+            "tostring" => Builtin::ToString,
+            "tonumber" => Builtin::ToNumber,
+            "tojson" => Builtin::ToJson,
+            "fromjson" => Builtin::FromJson,
+            "ascii_downcase" => Builtin::AsciiDowncase,
+            "ascii_upcase" => Builtin::AsciiUpcase,
+            "explode" => Builtin::Explode,
+            "implode" => Builtin::Implode,
+            "ascii" => Builtin::Ascii,
+            "sort" => Builtin::Sort,
+            "unique" => Builtin::Unique,
+            "min" => Builtin::Min,
+            "max" => Builtin::Max,
+            "reverse" => Builtin::Reverse,
+            "flatten" => Builtin::Flatten,
+            "transpose" => Builtin::Transpose,
+            "now" => Builtin::Now,
+            "gmtime" => Builtin::Gmtime,
+            "mktime" => Builtin::Mktime,
+            "todate" | "todateiso8601" | "date" => Builtin::ToDate,
+            "fromdate" | "fromdateiso8601" => Builtin::FromDate,
+            _ => return None,
+        };
+        Some(builtin)
+    }
+
+    pub fn apply(self, input: Value) -> Result<Value, FilterError> {
+        match self {
+            Builtin::ToString => Ok(Value::String(to_text(&input))),
+            Builtin::ToNumber => to_number(input),
+            Builtin::ToJson => Ok(Value::String(output::to_json(&input))),
+            Builtin::FromJson => from_json(input),
+            Builtin::AsciiDowncase => Ok(Value::String(
+                expect_string("ascii_downcase", &input)?.to_ascii_lowercase(),
+            )),
+            Builtin::AsciiUpcase => Ok(Value::String(
+                expect_string("ascii_upcase", &input)?.to_ascii_uppercase(),
+            )),
+            Builtin::Explode => Ok(Value::Array(
+                expect_string("explode", &input)?
+                    .chars()
+                    .map(|c| Value::from(c as u32))
+                    .collect(),
+            )),
+            Builtin::Implode => implode(&input),
+            Builtin::Ascii => match input.as_u64() {
+                Some(code @ 0..=127) => Ok(Value::String((code as u8 as char).to_string())),
+                _ => Err(FilterError::InputMustBe(
+                    "ascii",
+                    "an integer between 0 and 127",
+                )),
+            },
+            Builtin::Sort => {
+                let keys = sortable(&input)?.clone();
+                Ok(ByOp::Sort.apply(input, keys))
+            }
+            Builtin::Unique => {
+                let keys = sortable(&input)?.clone();
+                Ok(ByOp::Unique.apply(input, keys))
+            }
+            Builtin::Min => {
+                let keys = sortable(&input)?.clone();
+                Ok(ByOp::Min.apply(input, keys))
+            }
+            Builtin::Max => {
+                let keys = sortable(&input)?.clone();
+                Ok(ByOp::Max.apply(input, keys))
+            }
+            Builtin::Reverse => match input {
+                Value::Array(mut items) => {
+                    items.reverse();
+                    Ok(Value::Array(items))
+                }
+                Value::String(s) => Ok(Value::String(s.chars().rev().collect())),
+                Value::Null => Ok(Value::Array(Vec::new())),
+                other => Err(FilterError::CannotIndex(
+                    type_name(&other),
+                    "number".to_string(),
+                )),
+            },
+            Builtin::Flatten => flatten(input, None),
+            Builtin::Transpose => transpose(&input),
+            Builtin::Now => Ok(date::now()),
+            Builtin::Gmtime => date::gmtime(&input),
+            Builtin::Mktime => date::mktime(&input),
+            Builtin::ToDate => date::strftime(&input, &Value::from(date::ISO8601)),
+            Builtin::FromDate => {
+                date::mktime(&date::strptime(&input, &Value::from(date::ISO8601))?)
+            }
+        }
+    }
+}
+
+impl BuiltinArg {
+    pub fn from_name(name: &str) -> Option<BuiltinArg> {
+        let builtin = match name {
+            "split" => BuiltinArg::Split,
+            "join" => BuiltinArg::Join,
+            "ltrimstr" => BuiltinArg::Ltrimstr,
+            "rtrimstr" => BuiltinArg::Rtrimstr,
+            "startswith" => BuiltinArg::Startswith,
+            "endswith" => BuiltinArg::Endswith,
+            "flatten" => BuiltinArg::Flatten,
+            "indices" => BuiltinArg::Indices,
+            "index" => BuiltinArg::Index,
+            "rindex" => BuiltinArg::Rindex,
+            "contains" => BuiltinArg::Contains,
+            "inside" => BuiltinArg::Inside,
+            "strftime" => BuiltinArg::Strftime,
+            "strptime" => BuiltinArg::Strptime,
+            _ => return None,
+        };
+        Some(builtin)
+    }
+
+    pub fn apply(self, input: &Value, arg: Value) -> Result<Value, FilterError> {
+        match (self, input, &arg) {
+            (BuiltinArg::Split, Value::String(s), Value::String(separator)) => {
+                Ok(Value::Array(ops::split(s, separator)))
+            }
+            (BuiltinArg::Split, _, _) => Err(FilterError::SplitNotStrings),
+            (BuiltinArg::Join, _, _) => join(input, arg),
+            // trimming anything but a string by a string leaves it unchanged
+            (BuiltinArg::Ltrimstr, Value::String(s), Value::String(prefix)) => Ok(Value::String(
+                s.strip_prefix(prefix.as_str()).unwrap_or(s).to_string(),
+            )),
+            (BuiltinArg::Rtrimstr, Value::String(s), Value::String(suffix)) => Ok(Value::String(
+                s.strip_suffix(suffix.as_str()).unwrap_or(s).to_string(),
+            )),
+            (BuiltinArg::Ltrimstr | BuiltinArg::Rtrimstr, _, _) => Ok(input.clone()),
+            (BuiltinArg::Startswith, Value::String(s), Value::String(prefix)) => {
+                Ok(Value::Bool(s.starts_with(prefix.as_str())))
+            }
+            (BuiltinArg::Endswith, Value::String(s), Value::String(suffix)) => {
+                Ok(Value::Bool(s.ends_with(suffix.as_str())))
+            }
+            (BuiltinArg::Startswith, _, _) => Err(FilterError::RequiresStrings("startswith")),
+            (BuiltinArg::Endswith, _, _) => Err(FilterError::RequiresStrings("endswith")),
+            (BuiltinArg::Flatten, _, _) => match arg.as_f64() {
+                Some(depth) if depth >= 0.0 => flatten(input.clone(), Some(depth as usize)),
+                Some(_) => Err(FilterError::NegativeFlattenDepth),
+                None => Err(FilterError::InputMustBe("flatten depth", "a number")),
+            },
+            (BuiltinArg::Indices, _, _) => indices(input, &arg),
+            (BuiltinArg::Index, _, _) => match indices(input, &arg)? {
+                Value::Array(found) => Ok(found.into_iter().next().unwrap_or(Value::Null)),
+                other => Ok(other),
+            },
+            (BuiltinArg::Rindex, _, _) => match indices(input, &arg)? {
+                Value::Array(found) => Ok(found.into_iter().last().unwrap_or(Value::Null)),
+                other => Ok(other),
+            },
+            (BuiltinArg::Contains, _, _) => check_contains(input, &arg),
+            (BuiltinArg::Inside, _, _) => check_contains(&arg, input),
+            (BuiltinArg::Strftime, _, _) => date::strftime(input, &arg),
+            (BuiltinArg::Strptime, _, _) => date::strptime(input, &arg),
+        }
+    }
+}
+
+impl ByOp {
+    pub fn from_name(name: &str) -> Option<ByOp> {
+        let op = match name {
+            "sort_by" => ByOp::Sort,
+            "group_by" => ByOp::Group,
+            "unique_by" => ByOp::Unique,
+            "min_by" => ByOp::Min,
+            "max_by" => ByOp::Max,
+            _ => return None,
+        };
+        Some(op)
+    }
+
+    // The result for an array whose elements have the given keys; the sort is
+    // stable, `min` keeps the first smallest element and `max` the last largest
+    pub fn apply(self, input: Value, keys: Vec<Value>) -> Value {
+        let Value::Array(items) = input else {
+            unreachable!("callers check that the input is an array")
+        };
+        let mut pairs: Vec<(Value, Value)> = keys.into_iter().zip(items).collect();
+        match self {
+            ByOp::Min => pairs
+                .into_iter()
+                .reduce(|min, pair| match compare(&pair.0, &min.0) {
+                    Ordering::Less => pair,
+                    _ => min,
+                })
+                .map_or(Value::Null, |(_, item)| item),
+            ByOp::Max => pairs
+                .into_iter()
+                .reduce(|max, pair| match compare(&pair.0, &max.0) {
+                    Ordering::Less => max,
+                    _ => pair,
+                })
+                .map_or(Value::Null, |(_, item)| item),
+            ByOp::Sort => {
+                pairs.sort_by(|a, b| compare(&a.0, &b.0));
+                Value::Array(pairs.into_iter().map(|(_, item)| item).collect())
+            }
+            ByOp::Group | ByOp::Unique => {
+                pairs.sort_by(|a, b| compare(&a.0, &b.0));
+                let mut groups: Vec<(Value, Vec<Value>)> = Vec::new();
+                for (key, item) in pairs {
+                    match groups.last_mut() {
+                        Some((last, group)) if compare(last, &key) == Ordering::Equal => {
+                            group.push(item)
+                        }
+                        _ => groups.push((key, vec![item])),
+                    }
+                }
+                let groups = groups.into_iter().map(|(_, group)| group);
+                if self == ByOp::Group {
+                    Value::Array(groups.map(Value::Array).collect())
+                } else {
+                    Value::Array(
+                        groups
+                            .filter_map(|group| group.into_iter().next())
+                            .collect(),
+                    )
+                }
+            }
+        }
+    }
+}
+
+// The elements of an input that can be sorted
+pub fn sortable(input: &Value) -> Result<&Vec<Value>, FilterError> {
+    match input {
+        Value::Array(items) => Ok(items),
+        other => Err(FilterError::NotSortable(describe(other))),
+    }
+}
+
+// Nested arrays spliced into their parent, down to `depth` levels if given
+fn flatten(input: Value, depth: Option<usize>) -> Result<Value, FilterError> {
+    fn splice(items: Vec<Value>, depth: Option<usize>, flat: &mut Vec<Value>) {
+        for item in items {
+            match item {
+                Value::Array(inner) if depth != Some(0) => {
+                    splice(inner, depth.map(|depth| depth - 1), flat)
+                }
+                other => flat.push(other),
+            }
+        }
+    }
+    let items = match input {
+        Value::Array(items) => items,
+        Value::Object(map) => map.into_iter().map(|(_, value)| value).collect(),
+        other => return Err(FilterError::CannotIterate(describe(&other))),
+    };
+    let mut flat = Vec::new();
+    splice(items, depth, &mut flat);
+    Ok(Value::Array(flat))
+}
+
+// Rows turned into columns, short rows padded with null
+fn transpose(input: &Value) -> Result<Value, FilterError> {
+    let rows = sortable(input)?
+        .iter()
+        .map(|row| match row {
+            Value::Array(row) => Ok(row.as_slice()),
+            Value::Null => Ok(&[][..]),
+            other => Err(FilterError::CannotIndex(
+                type_name(other),
+                "number".to_string(),
+            )),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let width = rows.iter().map(|row| row.len()).max().unwrap_or(0);
+    let columns = (0..width)
+        .map(|i| {
+            rows.iter()
+                .map(|row| row.get(i).cloned().unwrap_or(Value::Null))
+                .collect()
+        })
+        .map(Value::Array)
+        .collect();
+    Ok(Value::Array(columns))
+}
+
+// Where `needle` occurs in `input`: codepoint offsets of a substring, or
+// positions of a subarray or element; occurrences may overlap
+fn indices(input: &Value, needle: &Value) -> Result<Value, FilterError> {
+    let positions: Vec<usize> = match (input, needle) {
+        (Value::Null, _) => return Ok(Value::Null),
+        (Value::String(s), Value::String(sub)) => {
+            let chars: Vec<char> = s.chars().collect();
+            let sub: Vec<char> = sub.chars().collect();
+            find_all(&chars, &sub, |a, b| a == b)
+        }
+        (Value::Array(items), Value::Array(sub)) => find_all(items, sub, equal),
+        (Value::Array(items), element) => find_all(items, std::slice::from_ref(element), equal),
+        (other, _) => return Err(FilterError::CannotIndex(type_name(other), describe(needle))),
+    };
+    Ok(Value::Array(
+        positions.into_iter().map(Value::from).collect(),
+    ))
+}
+
+fn find_all<T>(items: &[T], sub: &[T], eq: fn(&T, &T) -> bool) -> Vec<usize> {
+    if sub.is_empty() || sub.len() > items.len() {
+        return Vec::new();
+    }
+    items
+        .windows(sub.len())
+        .enumerate()
+        .filter(|(_, window)| window.iter().zip(sub).all(|(a, b)| eq(a, b)))
+        .map(|(i, _)| i)
+        .collect()
+}
+
+// jq equality, under which `1` and `1.0` are the same
+fn equal(a: &Value, b: &Value) -> bool {
+    compare(a, b) == Ordering::Equal
+}
+
+// `contains` for values of the same type; nested values of different types
+// are simply not contained
+fn check_contains(a: &Value, b: &Value) -> Result<Value, FilterError> {
+    if type_name(a) != type_name(b) {
+        return Err(FilterError::NotContainable(describe(a), describe(b)));
+    }
+    Ok(Value::Bool(contains(a, b)))
+}
+
+// Whether `a` contains `b`: substrings for strings, every element of `b`
+// contained in some element of `a` for arrays, and every key of `b` holding
+// a contained value in `a` for objects
+fn contains(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::String(a), Value::String(b)) => a.contains(b.as_str()),
+        (Value::Array(a), Value::Array(b)) => b
+            .iter()
+            .all(|needle| a.iter().any(|item| contains(item, needle))),
+        (Value::Object(a), Value::Object(b)) => b
+            .iter()
+            .all(|(key, needle)| a.get(key).is_some_and(|item| contains(item, needle))),
+        _ => type_name(a) == type_name(b) && equal(a, b),
+    }
+}
+
+// The cartesian product of `count` arrays, the first varying slowest, where
+// `array(i)` gives the items of the i-th. Only the current combination is
+// held, so `first(combinations(n))` does not build the copies up front.
+pub fn combinations<'a>(
+    count: usize,
+    array: &dyn Fn(usize) -> &'a [Value],
+    out: &mut dyn FnMut(Value) -> Result<(), FilterError>,
+) -> Result<(), FilterError> {
+    if (0..count).any(|i| array(i).is_empty()) {
+        return Ok(());
+    }
+    let mut indices = vec![0; count];
+    loop {
+        let combination = indices
+            .iter()
+            .enumerate()
+            .map(|(i, &index)| array(i)[index].clone())
+            .collect();
+        out(Value::Array(combination))?;
+        // advance the last position, carrying into the ones before it
+        let mut position = count;
+        loop {
+            if position == 0 {
+                return Ok(());
+            }
+            position -= 1;
+            indices[position] += 1;
+            if indices[position] < array(position).len() {
+                break;
+            }
+            indices[position] = 0;
+        }
+    }
+}
+
+const FORMATS: [&str; 9] = [
+    "text", "json", "csv", "tsv", "html", "uri", "sh", "base64", "base64d",
+];
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// Whether `@name` is a format this implementation knows
+pub fn is_format(name: &str) -> bool {
+    FORMATS.contains(&name)
+}
+
+// The text of a value under the format `@name`
+pub fn format(name: &str, value: &Value) -> Result<String, FilterError> {
+    match name {
+        "text" => Ok(to_text(value)),
+        "json" => Ok(output::to_json(value)),
+        "csv" => format_row(value, "csv"),
+        "tsv" => format_row(value, "tsv"),
+        "html" => Ok(escape_html(&to_text(value))),
+        "uri" => Ok(escape_uri(&to_text(value))),
+        "sh" => match value {
+            Value::Array(items) => Ok(items
+                .iter()
+                .map(quote_shell)
+                .collect::<Result<Vec<_>, _>>()?
+                .join(" ")),
+            other => quote_shell(other),
+        },
+        "base64" => Ok(encode_base64(to_text(value).as_bytes())),
+        "base64d" => {
+            let text = to_text(value);
+            match decode_base64(&text) {
+                Some(bytes) => Ok(String::from_utf8_lossy(&bytes).into_owned()),
+                None => Err(FilterError::InvalidBase64(describe(&Value::String(text)))),
+            }
+        }
+        _ => Err(FilterError::UnknownFormat(name.to_string())),
+    }
+}
+
+// An array as one row of comma or tab separated values; strings are quoted
+// for CSV and have tabs, line breaks and backslashes escaped for TSV
+fn format_row(value: &Value, format: &'static str) -> Result<String, FilterError> {
+    let Value::Array(items) = value else {
+        return Err(FilterError::NotFormattable(describe(value), format));
+    };
+    let fields = items
+        .iter()
+        .map(|item| match item {
+            Value::Null => Ok(String::new()),
+            Value::Bool(_) | Value::Number(_) => Ok(output::to_json(item)),
+            Value::String(s) if format == "csv" => Ok(format!("\"{}\"", s.replace('"', "\"\""))),
+            Value::String(s) => Ok(s
+                .replace('\\', "\\\\")
+                .replace('\t', "\\t")
+                .replace('\n', "\\n")
+                .replace('\r', "\\r")),
+            other => Err(FilterError::InvalidInRow(describe(other), format)),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let separator = if format == "csv" { "," } else { "\t" };
+    Ok(fields.join(separator))
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '&' => escaped.push_str("&amp;"),
+            '\'' => escaped.push_str("&#39;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Percent-encode every byte but the unreserved characters of RFC 3986
+fn escape_uri(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                escaped.push(byte as char)
+            }
+            _ => escaped.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    escaped
+}
+
+// Strings in single quotes, other scalars as they are
+fn quote_shell(value: &Value) -> Result<String, FilterError> {
+    match value {
+        Value::String(s) => Ok(format!("'{}'", s.replace('\'', "'\\''"))),
+        Value::Array(_) | Value::Object(_) => Err(FilterError::NotShellEscapable(describe(value))),
+        other => Ok(output::to_json(other)),
+    }
+}
+
+fn encode_base64(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, byte)| {
+            group | (*byte as u32) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let index = (group >> (18 - 6 * i)) & 0x3f;
+                encoded.push(BASE64_ALPHABET[index as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+// The bytes of base64 text, with or without its trailing padding; nothing
+// if it holds other characters or ends in a single stray character
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let digits = text.trim_end_matches('=');
+    let mut bytes = Vec::with_capacity(digits.len() * 3 / 4);
+    let mut group = 0u32;
+    let mut count = 0;
+    for c in digits.bytes() {
+        let digit = BASE64_ALPHABET.iter().position(|&d| d == c)? as u32;
+        group = group << 6 | digit;
+        count += 1;
+        if count == 4 {
+            bytes.extend_from_slice(&group.to_be_bytes()[1..]);
+            group = 0;
+            count = 0;
+        }
+    }
+    match count {
+        0 => {}
+        2 => bytes.push((group >> 4) as u8),
+        3 => bytes.extend_from_slice(&((group >> 2) as u16).to_be_bytes()),
+        _ => return None,
+    }
+    Some(bytes)
+}
+
+// Strings as they are, anything else as compact JSON
+pub fn to_text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => output::to_json(other),
+    }
+}
+
+fn expect_string<'a>(name: &'static str, input: &'a Value) -> Result<&'a str, FilterError> {
+    match input {
+        Value::String(s) => Ok(s),
+        _ => Err(FilterError::InputMustBe(name, "a string")),
+    }
+}
+
+fn from_json(input: Value) -> Result<Value, FilterError> {
+    let Value::String(text) = input else {
+        return Err(FilterError::OnlyStringsParsed(describe(&input)));
+    };
+    serde_json::from_str(&text).map_err(|err| FilterError::InvalidJson(err.to_string(), text))
+}
+
+fn to_number(input: Value) -> Result<Value, FilterError> {
+    match input {
+        Value::Number(_) => Ok(input),
+        Value::String(s) => match s.trim().parse() {
+            Ok(num) => Ok(Value::Number(num)),
+            Err(_) => Err(FilterError::CannotParseNumber(s)),
+        },
+        other => Err(FilterError::NotParsable(describe(&other))),
+    }
+}
+
+// Codepoints back to a string; numbers that are not valid codepoints become U+FFFD
+fn implode(input: &Value) -> Result<Value, FilterError> {
+    let Value::Array(codes) = input else {
+        return Err(FilterError::InputMustBe("implode", "an array"));
+    };
+    codes
+        .iter()
+        .map(|code| match code.as_f64() {
+            // checked before the cast, which would saturate negative codes to 0
+            Some(code) if (0.0..=0x10FFFF as f64).contains(&code) => {
+                Ok(char::from_u32(code as u32).unwrap_or(char::REPLACEMENT_CHARACTER))
+            }
+            Some(_) => Ok(char::REPLACEMENT_CHARACTER),
+            None => Err(FilterError::InputMustBe(
+                "implode",
+                "an array of codepoints",
+            )),
+        })
+        .collect::<Result<String, _>>()
+        .map(Value::String)
+}
+
+// The elements joined by `separator`; null elements are empty and numbers and
+// booleans are written as JSON
+fn join(input: &Value, separator: Value) -> Result<Value, FilterError> {
+    let elements: Vec<&Value> = match input {
+        Value::Array(items) => items.iter().collect(),
+        Value::Object(map) => map.values().collect(),
+        other => return Err(FilterError::CannotIterate(describe(other))),
+    };
+    let mut joined = Value::String(String::new());
+    for (i, element) in elements.into_iter().enumerate() {
+        if i > 0 {
+            joined = ops::add(joined, separator.clone())?;
+        }
+        let element = match element {
+            Value::Null => Value::String(String::new()),
+            Value::Bool(_) | Value::Number(_) => Value::String(output::to_json(element)),
+            other => other.clone(),
+        };
+        joined = ops::add(joined, element)?;
+    }
+    Ok(joined)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_tostring() {
+        assert_eq!(Builtin::ToString.apply(json!("s")).unwrap(), json!("s"));
+        assert_eq!(
+            Builtin::ToString.apply(json!({"a": [1, null]})).unwrap(),
+            json!("{\"a\":[1,null]}")
+        );
+    }
+
+    #[test]
+    fn test_tojson_fromjson() {
+        let value = json!({"a": [1, 2.5, null, "x\"y"]});
+        let text = Builtin::ToJson.apply(value.clone()).unwrap();
+        assert_eq!(text, json!("{\"a\":[1,2.5,null,\"x\\\"y\"]}"));
+        assert_eq!(Builtin::FromJson.apply(text).unwrap(), value);
+        let err = Builtin::FromJson.apply(json!(1)).unwrap_err();
+        assert_eq!(err.to_string(), "number (1) only strings can be parsed");
+        let err = Builtin::FromJson.apply(json!("abc")).unwrap_err();
+        assert!(err.to_string().ends_with("(while parsing 'abc')"));
+    }
+
+    #[test]
+    fn test_tonumber() {
+        assert_eq!(Builtin::ToNumber.apply(json!("12")).unwrap(), json!(12));
+        assert_eq!(Builtin::ToNumber.apply(json!(" 1.5 ")).unwrap(), json!(1.5));
+        assert_eq!(Builtin::ToNumber.apply(json!(3)).unwrap(), json!(3));
+        let err = Builtin::ToNumber.apply(json!("x")).unwrap_err();
+        assert_eq!(err.to_string(), "Cannot parse 'x' as a number");
+        let err = Builtin::ToNumber.apply(json!([1])).unwrap_err();
+        assert_eq!(err.to_string(), "array ([1]) cannot be parsed as a number");
+    }
+
+    #[test]
+    fn test_ascii_case() {
+        assert_eq!(
+            Builtin::AsciiDowncase.apply(json!("abAB1é")).unwrap(),
+            json!("abab1é")
+        );
+        assert_eq!(
+            Builtin::AsciiUpcase.apply(json!("abAB1")).unwrap(),
+            json!("ABAB1")
+        );
+        assert!(Builtin::AsciiDowncase.apply(json!(1)).is_err());
+    }
+
+    #[test]
+    fn test_explode_implode_round_trip() {
+        let codes = Builtin::Explode.apply(json!("aé😀")).unwrap();
+        assert_eq!(codes, json!([97, 233, 128512]));
+        assert_eq!(Builtin::Implode.apply(codes).unwrap(), json!("aé😀"));
+        assert_eq!(
+            Builtin::Implode.apply(json!([55296])).unwrap(),
+            json!("\u{fffd}")
+        );
+        assert_eq!(
+            Builtin::Implode.apply(json!([-1, 1114112, 65])).unwrap(),
+            json!("\u{fffd}\u{fffd}A")
+        );
+        assert!(Builtin::Implode.apply(json!("a")).is_err());
+        assert!(Builtin::Implode.apply(json!(["a"])).is_err());
+    }
+
+    #[test]
+    fn test_ascii() {
+        assert_eq!(Builtin::Ascii.apply(json!(65)).unwrap(), json!("A"));
+        assert!(Builtin::Ascii.apply(json!(128)).is_err());
+    }
+
+    #[test]
+    fn test_split() {
+        let split = |input: Value, sep: Value| BuiltinArg::Split.apply(&input, sep);
+        assert_eq!(
+            split(json!("a,b,c"), json!(",")).unwrap(),
+            json!(["a", "b", "c"])
+        );
+        assert_eq!(
+            split(json!("abc"), json!("")).unwrap(),
+            json!(["a", "b", "c"])
+        );
+        let err = split(json!(1), json!(",")).unwrap_err();
+        assert_eq!(err.to_string(), "split input and separator must be strings");
+    }
+
+    #[test]
+    fn test_join() {
+        let join = |input: Value, sep: Value| BuiltinArg::Join.apply(&input, sep);
+        assert_eq!(
+            join(json!(["a", 1, null, true]), json!("-")).unwrap(),
+            json!("a-1--true")
+        );
+        assert_eq!(join(json!([]), json!(",")).unwrap(), json!(""));
+        assert_eq!(
+            join(json!({"x": "a", "y": "b"}), json!("")).unwrap(),
+            json!("ab")
+        );
+        assert!(join(json!([[1]]), json!("-")).is_err());
+        assert!(join(json!("a"), json!("-")).is_err());
+    }
+
+    #[test]
+    fn test_trim_and_affixes() {
+        let input = json!("foobar");
+        assert_eq!(
+            BuiltinArg::Ltrimstr.apply(&input, json!("foo")).unwrap(),
+            json!("bar")
+        );
+        assert_eq!(
+            BuiltinArg::Rtrimstr.apply(&input, json!("bar")).unwrap(),
+            json!("foo")
+        );
+        assert_eq!(
+            BuiltinArg::Ltrimstr.apply(&input, json!("x")).unwrap(),
+            input
+        );
+        assert_eq!(
+            BuiltinArg::Ltrimstr.apply(&json!(1), json!("a")).unwrap(),
+            json!(1)
+        );
+        assert_eq!(
+            BuiltinArg::Startswith.apply(&input, json!("foo")).unwrap(),
+            json!(true)
+        );
+        assert_eq!(
+            BuiltinArg::Endswith.apply(&input, json!("foo")).unwrap(),
+            json!(false)
+        );
+        let err = BuiltinArg::Startswith
+            .apply(&json!(1), json!("a"))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "startswith() requires string inputs");
+    }
+
+    #[test]
+    fn test_text_format() {
+        assert!(is_format("text"));
+        assert!(!is_format("nope"));
+        assert_eq!(format("text", &json!([1, "a"])).unwrap(), "[1,\"a\"]");
+    }
+
+    #[test]
+    fn test_csv_and_tsv() {
+        let row = json!([1, "a\"b", null, true, 1.5, "x\ty\\z\n"]);
+        assert_eq!(
+            format("csv", &row).unwrap(),
+            "1,\"a\"\"b\",,true,1.5,\"x\ty\\z\n\""
+        );
+        assert_eq!(
+            format("tsv", &row).unwrap(),
+            "1\ta\"b\t\ttrue\t1.5\tx\\ty\\\\z\\n"
+        );
+        let err = format("csv", &json!({"a": 1})).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "object ({\"a\":1}) cannot be csv-formatted, only an array can be"
+        );
+        let err = format("tsv", &json!([[1]])).unwrap_err();
+        assert_eq!(err.to_string(), "array ([1]) is not valid in a tsv row");
+    }
+
+    #[test]
+    fn test_json_html_uri_and_sh() {
+        assert_eq!(format("json", &json!(["a\n", 1])).unwrap(), r#"["a\n",1]"#);
+        assert_eq!(
+            format("html", &json!("<a href='x'>&\"</a>")).unwrap(),
+            "&lt;a href=&#39;x&#39;&gt;&amp;&quot;&lt;/a&gt;"
+        );
+        assert_eq!(
+            format("uri", &json!("a b/é?-_.~!")).unwrap(),
+            "a%20b%2F%C3%A9%3F-_.~%21"
+        );
+        assert_eq!(
+            format("sh", &json!([1, "it's", null])).unwrap(),
+            r"1 'it'\''s' null"
+        );
+        assert!(format("sh", &json!([[1]])).is_err());
+    }
+
+    #[test]
+    fn test_base64_round_trip() {
+        let cases = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("wörld", "d8O2cmxk"),
+        ];
+        for (text, encoded) in cases {
+            assert_eq!(format("base64", &json!(text)).unwrap(), encoded);
+            assert_eq!(format("base64d", &json!(encoded)).unwrap(), text);
+        }
+        assert_eq!(format("base64d", &json!("Zm8")).unwrap(), "fo");
+        assert!(format("base64d", &json!("Z")).is_err());
+        assert!(format("base64d", &json!("!!")).is_err());
+    }
+
+    #[test]
+    fn test_sort_uses_jq_ordering() {
+        let input = json!([3, "a", null, true, false, [1], {"a": 1}, 1.5, {}, [], "B"]);
+        assert_eq!(
+            Builtin::Sort.apply(input).unwrap(),
+            json!([null, false, true, 1.5, 3, "B", "a", [], [1], {}, {"a": 1}])
+        );
+        assert_eq!(
+            Builtin::Unique.apply(json!([2, 1, 2.0, 1])).unwrap(),
+            json!([1, 2])
+        );
+        assert!(Builtin::Sort.apply(json!("ab")).is_err());
+    }
+
+    #[test]
+    fn test_by_ops() {
+        let input = json!([{"a": 2, "b": 1}, {"a": 1, "b": 2}, {"a": 2, "b": 0}]);
+        let keys = vec![json!([2]), json!([1]), json!([2])];
+        assert_eq!(
+            ByOp::Group.apply(input.clone(), keys.clone()),
+            json!([[{"a": 1, "b": 2}], [{"a": 2, "b": 1}, {"a": 2, "b": 0}]])
+        );
+        assert_eq!(
+            ByOp::Unique.apply(input.clone(), keys.clone()),
+            json!([{"a": 1, "b": 2}, {"a": 2, "b": 1}])
+        );
+        assert_eq!(
+            ByOp::Min.apply(input.clone(), keys.clone()),
+            json!({"a": 1, "b": 2})
+        );
+        // the last of equally large elements wins
+        assert_eq!(ByOp::Max.apply(input, keys), json!({"a": 2, "b": 0}));
+        assert_eq!(ByOp::Min.apply(json!([]), Vec::new()), json!(null));
+    }
+
+    #[test]
+    fn test_reverse_flatten_and_transpose() {
+        assert_eq!(
+            Builtin::Reverse.apply(json!([1, 2])).unwrap(),
+            json!([2, 1])
+        );
+        assert_eq!(Builtin::Reverse.apply(json!("aé")).unwrap(), json!("éa"));
+        assert_eq!(Builtin::Reverse.apply(json!(null)).unwrap(), json!([]));
+        let nested = json!([1, [2, [3, [4]]]]);
+        assert_eq!(
+            Builtin::Flatten.apply(nested.clone()).unwrap(),
+            json!([1, 2, 3, 4])
+        );
+        assert_eq!(
+            BuiltinArg::Flatten.apply(&nested, json!(1)).unwrap(),
+            json!([1, 2, [3, [4]]])
+        );
+        let err = BuiltinArg::Flatten.apply(&nested, json!(-1)).unwrap_err();
+        assert_eq!(err.to_string(), "flatten depth must not be negative");
+        assert_eq!(
+            Builtin::Transpose
+                .apply(json!([[1], [2, 3], null]))
+                .unwrap(),
+            json!([[1, 2, null], [null, 3, null]])
+        );
+    }
+
+    #[test]
+    fn test_indices() {
+        let text = json!("a,b, cd, efg");
+        assert_eq!(
+            BuiltinArg::Indices.apply(&text, json!(", ")).unwrap(),
+            json!([3, 7])
+        );
+        assert_eq!(
+            BuiltinArg::Rindex.apply(&text, json!(", ")).unwrap(),
+            json!(7)
+        );
+        assert_eq!(
+            BuiltinArg::Index.apply(&json!("aé,"), json!(",")).unwrap(),
+            json!(2)
+        );
+        let items = json!([0, 1, 2, 1, 3, 1, 2]);
+        assert_eq!(
+            BuiltinArg::Indices.apply(&items, json!(1)).unwrap(),
+            json!([1, 3, 5])
+        );
+        assert_eq!(
+            BuiltinArg::Indices.apply(&items, json!([1, 2])).unwrap(),
+            json!([1, 5])
+        );
+        assert_eq!(
+            BuiltinArg::Index.apply(&items, json!(9)).unwrap(),
+            json!(null)
+        );
+    }
+
+    #[test]
+    fn test_contains_and_inside() {
+        let input = json!({"foo": 12, "bar": [1, 2, {"barp": 12, "blip": 13}]});
+        let needle = json!({"foo": 12, "bar": [{"barp": 12}]});
+        assert_eq!(
+            BuiltinArg::Contains.apply(&input, needle.clone()).unwrap(),
+            json!(true)
+        );
+        assert_eq!(
+            BuiltinArg::Inside.apply(&needle, input).unwrap(),
+            json!(true)
+        );
+        assert_eq!(
+            BuiltinArg::Contains
+                .apply(&json!(["foobar", "baz"]), json!(["baz", "bar"]))
+                .unwrap(),
+            json!(true)
+        );
+        assert_eq!(
+            BuiltinArg::Contains.apply(&json!(1), json!(1.0)).unwrap(),
+            json!(true)
+        );
+        let err = BuiltinArg::Contains
+            .apply(&json!({"a": 1}), json!([1]))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "object ({\"a\":1}) and array ([1]) cannot have their containment checked"
+        );
+    }
+
+    #[test]
+    fn test_date_builtins() {
+        let todate = Builtin::from_name("todate").unwrap();
+        assert_eq!(
+            todate.apply(json!(1425599621)).unwrap(),
+            json!("2015-03-05T23:53:41Z")
+        );
+        assert_eq!(
+            Builtin::FromDate
+                .apply(json!("2015-03-05T23:53:41Z"))
+                .unwrap(),
+            json!(1425599621)
+        );
+        assert_eq!(
+            BuiltinArg::Strftime
+                .apply(&json!([2015, 2, 5, 23, 53, 41, 4, 63]), json!("%d.%m.%Y"))
+                .unwrap(),
+            json!("05.03.2015")
+        );
+        assert!(Builtin::FromDate.apply(json!(1425599621)).is_err());
+    }
+}
@@ -0,0 +1,656 @@
+use crate::filter::FilterError;
+use crate::ops::float_value;
+use serde_json::Value;
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// The format of `todate` and `fromdate`
+pub const ISO8601: &str = "%Y-%m-%dT%H:%M:%SZ";
+
+const WEEKDAYS: [&str; 7] = [
+    "Sunday",
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+];
+
+const MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+// Fields this large overflow the date arithmetic and are no date anyway
+const FIELD_LIMIT: f64 = 1e15;
+
+// A "broken down time", as jq's `gmtime` returns it: the year, the month
+// counted from 0, the day of the month, hours, minutes, seconds with their
+// fraction, the day of the week counted from Sunday and the day of the year
+// counted from 0. Always in UTC.
+#[derive(Clone, Debug, PartialEq)]
+struct Tm {
+    year: i64,
+    month: i64,
+    day: i64,
+    hour: i64,
+    minute: i64,
+    second: f64,
+    wday: i64,
+    yday: i64,
+}
+
+impl Tm {
+    // The broken down time of a number of seconds since the epoch
+    fn from_epoch(secs: f64) -> Result<Tm, FilterError> {
+        if !secs.is_finite() || secs.abs() >= FIELD_LIMIT * 60.0 {
+            return Err(FilterError::DateOutOfRange);
+        }
+        let whole = secs.floor();
+        let days = (whole as i64).div_euclid(86400);
+        let time = (whole as i64).rem_euclid(86400);
+        let (year, month, day) = civil_from_days(days);
+        Ok(Tm {
+            year,
+            month: month - 1,
+            day,
+            hour: time / 3600,
+            minute: time % 3600 / 60,
+            second: (time % 60) as f64 + (secs - whole),
+            wday: weekday(days),
+            yday: days - days_from_civil(year, 1, 1),
+        })
+    }
+
+    // A date with the day of the week and of the year derived from it
+    fn from_date(year: i64, month: i64, day: i64) -> Tm {
+        let days = days_from_civil(year + month.div_euclid(12), month.rem_euclid(12) + 1, day);
+        Tm {
+            year,
+            month,
+            day,
+            hour: 0,
+            minute: 0,
+            second: 0.0,
+            wday: weekday(days),
+            yday: days - days_from_civil(year, 1, 1),
+        }
+    }
+
+    // A broken down time given as an array of at least six numbers; the day
+    // of the week and of the year are derived when they are missing
+    fn from_value(value: &Value, name: &'static str) -> Result<Tm, FilterError> {
+        let invalid = || FilterError::Requires(name, "parsed datetime inputs");
+        let Value::Array(fields) = value else {
+            return Err(invalid());
+        };
+        let numbers = fields
+            .iter()
+            .map(|field| match field.as_f64() {
+                Some(n) if n.abs() < FIELD_LIMIT => Ok(n),
+                _ => Err(invalid()),
+            })
+            .collect::<Result<Vec<f64>, FilterError>>()?;
+        let [year, month, day, hour, minute, second, ..] = numbers[..] else {
+            return Err(invalid());
+        };
+        let mut tm = Tm::from_date(year as i64, month as i64, day as i64);
+        tm.hour = hour as i64;
+        tm.minute = minute as i64;
+        tm.second = second;
+        if let [_, _, _, _, _, _, wday, yday, ..] = numbers[..] {
+            tm.wday = wday as i64;
+            tm.yday = yday as i64;
+        }
+        Ok(tm)
+    }
+
+    fn to_value(&self) -> Value {
+        Value::Array(vec![
+            Value::from(self.year),
+            Value::from(self.month),
+            Value::from(self.day),
+            Value::from(self.hour),
+            Value::from(self.minute),
+            float_value(self.second),
+            Value::from(self.wday),
+            Value::from(self.yday),
+        ])
+    }
+
+    // Seconds since the epoch, with out-of-range fields carried over like
+    // `timegm` does, e.g. the 32nd of January is the 1st of February
+    fn timegm(&self) -> f64 {
+        let days = days_from_civil(
+            self.year + self.month.div_euclid(12),
+            self.month.rem_euclid(12) + 1,
+            self.day,
+        );
+        let secs = days as i128 * 86400
+            + self.hour as i128 * 3600
+            + self.minute as i128 * 60
+            + self.second.floor() as i128;
+        secs as f64
+    }
+
+    fn hour12(&self) -> i64 {
+        match self.hour.rem_euclid(12) {
+            0 => 12,
+            hour => hour,
+        }
+    }
+
+    // The ISO 8601 week-based year and week number, from the year, day of
+    // the year and day of the week fields as they are
+    fn iso_week(&self) -> (i64, i64) {
+        let weekday = (self.wday + 6).rem_euclid(7) + 1;
+        let week = (self.yday + 1 - weekday + 10).div_euclid(7);
+        if week < 1 {
+            (self.year - 1, iso_weeks(self.year - 1))
+        } else if week > iso_weeks(self.year) {
+            (self.year + 1, 1)
+        } else {
+            (self.year, week)
+        }
+    }
+
+    fn format(&self, format: &str, out: &mut String) {
+        let mut chars = format.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                out.push(c);
+                continue;
+            }
+            let Some(spec) = chars.next() else {
+                out.push('%');
+                break;
+            };
+            // writing to a String cannot fail
+            let _ = match spec {
+                'a' => write!(out, "{:.3}", name(&WEEKDAYS, self.wday)),
+                'A' => write!(out, "{}", name(&WEEKDAYS, self.wday)),
+                'b' | 'h' => write!(out, "{:.3}", name(&MONTHS, self.month)),
+                'B' => write!(out, "{}", name(&MONTHS, self.month)),
+                'c' => {
+                    self.format("%a %b %e %H:%M:%S %Y", out);
+                    Ok(())
+                }
+                'C' => write!(out, "{:02}", self.year.div_euclid(100)),
+                'd' => write!(out, "{:02}", self.day),
+                'D' | 'x' => {
+                    self.format("%m/%d/%y", out);
+                    Ok(())
+                }
+                'e' => write!(out, "{:2}", self.day),
+                'F' => {
+                    self.format("%Y-%m-%d", out);
+                    Ok(())
+                }
+                'G' => write!(out, "{}", self.iso_week().0),
+                'g' => write!(out, "{:02}", self.iso_week().0.rem_euclid(100)),
+                'H' => write!(out, "{:02}", self.hour),
+                'I' => write!(out, "{:02}", self.hour12()),
+                'j' => write!(out, "{:03}", self.yday + 1),
+                'k' => write!(out, "{:2}", self.hour),
+                'l' => write!(out, "{:2}", self.hour12()),
+                'm' => write!(out, "{:02}", self.month + 1),
+                'M' => write!(out, "{:02}", self.minute),
+                'n' => writeln!(out),
+                'p' => write!(out, "{}", if self.hour < 12 { "AM" } else { "PM" }),
+                'P' => write!(out, "{}", if self.hour < 12 { "am" } else { "pm" }),
+                'r' => {
+                    self.format("%I:%M:%S %p", out);
+                    Ok(())
+                }
+                'R' => {
+                    self.format("%H:%M", out);
+                    Ok(())
+                }
+                's' => write!(out, "{}", self.timegm()),
+                'S' => write!(out, "{:02}", self.second as i64),
+                't' => write!(out, "\t"),
+                'T' | 'X' => {
+                    self.format("%H:%M:%S", out);
+                    Ok(())
+                }
+                'u' => write!(out, "{}", (self.wday + 6).rem_euclid(7) + 1),
+                'U' => write!(out, "{:02}", (self.yday + 7 - self.wday).div_euclid(7)),
+                'V' => write!(out, "{:02}", self.iso_week().1),
+                'w' => write!(out, "{}", self.wday),
+                'W' => write!(
+                    out,
+                    "{:02}",
+                    (self.yday + 7 - (self.wday + 6).rem_euclid(7)).div_euclid(7)
+                ),
+                'y' => write!(out, "{:02}", self.year.rem_euclid(100)),
+                'Y' => write!(out, "{}", self.year),
+                'z' => write!(out, "+0000"),
+                'Z' => write!(out, "UTC"),
+                '%' => write!(out, "%"),
+                // unknown conversions are copied as they are
+                other => write!(out, "%{}", other),
+            };
+        }
+    }
+}
+
+fn name(names: &[&'static str], index: i64) -> &'static str {
+    usize::try_from(index)
+        .ok()
+        .and_then(|index| names.get(index))
+        .copied()
+        .unwrap_or("?")
+}
+
+// Days since 1970-01-01 of a proleptic Gregorian date, with `month` from 1 to
+// 12; days outside the month carry over
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+// The year, month from 1 to 12 and day of a number of days since 1970-01-01
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+// 1970-01-01 was a Thursday
+fn weekday(days: i64) -> i64 {
+    (days + 4).rem_euclid(7)
+}
+
+// The number of ISO 8601 weeks in a year: 53 when it starts on a Thursday,
+// or on a Wednesday in a leap year
+fn iso_weeks(year: i64) -> i64 {
+    let starts = |year: i64| {
+        (year + year.div_euclid(4) - year.div_euclid(100) + year.div_euclid(400)).rem_euclid(7)
+    };
+    if starts(year) == 4 || starts(year - 1) == 3 {
+        53
+    } else {
+        52
+    }
+}
+
+// Fields read by `strptime`, resolved into a broken down time once the
+// whole string matched
+#[derive(Default)]
+struct Parsed {
+    year: Option<i64>,
+    century: Option<i64>,
+    short_year: Option<i64>,
+    month: Option<i64>,
+    day: Option<i64>,
+    yday: Option<i64>,
+    hour: i64,
+    minute: i64,
+    second: i64,
+    hour12: bool,
+    pm: Option<bool>,
+    epoch: Option<i64>,
+}
+
+impl Parsed {
+    // Matches `input` against `format`, returning what is left of the input
+    fn parse<'a>(&mut self, mut input: &'a str, format: &str) -> Option<&'a str> {
+        let mut chars = format.chars();
+        while let Some(c) = chars.next() {
+            if c.is_whitespace() {
+                input = input.trim_start();
+                continue;
+            }
+            if c != '%' {
+                input = input.strip_prefix(c)?;
+                continue;
+            }
+            let Some(spec) = chars.next() else {
+                return input.strip_prefix('%');
+            };
+            input = match spec {
+                'a' | 'A' => parse_name(input, &WEEKDAYS)?.1,
+                'b' | 'B' | 'h' => {
+                    let (month, rest) = parse_name(input, &MONTHS)?;
+                    self.month = Some(month);
+                    rest
+                }
+                'c' => self.parse(input, "%a %b %e %H:%M:%S %Y")?,
+                'C' => {
+                    let (century, rest) = parse_number(input, 2, 0, 99)?;
+                    self.century = Some(century);
+                    rest
+                }
+                'd' | 'e' => {
+                    let (day, rest) = parse_number(input, 2, 1, 31)?;
+                    self.day = Some(day);
+                    rest
+                }
+                'D' | 'x' => self.parse(input, "%m/%d/%y")?,
+                'F' => self.parse(input, "%Y-%m-%d")?,
+                'H' | 'k' => {
+                    let (hour, rest) = parse_number(input, 2, 0, 23)?;
+                    self.hour = hour;
+                    self.hour12 = false;
+                    rest
+                }
+                'I' | 'l' => {
+                    let (hour, rest) = parse_number(input, 2, 1, 12)?;
+                    self.hour = hour;
+                    self.hour12 = true;
+                    rest
+                }
+                'j' => {
+                    let (yday, rest) = parse_number(input, 3, 1, 366)?;
+                    self.yday = Some(yday - 1);
+                    rest
+                }
+                'm' => {
+                    let (month, rest) = parse_number(input, 2, 1, 12)?;
+                    self.month = Some(month - 1);
+                    rest
+                }
+                'M' => {
+                    let (minute, rest) = parse_number(input, 2, 0, 59)?;
+                    self.minute = minute;
+                    rest
+                }
+                'n' | 't' => input.trim_start(),
+                'p' | 'P' => {
+                    let (pm, rest) = parse_name(input, &["AM", "PM"])?;
+                    self.pm = Some(pm == 1);
+                    rest
+                }
+                'r' => self.parse(input, "%I:%M:%S %p")?,
+                'R' => self.parse(input, "%H:%M")?,
+                's' => {
+                    let (negative, rest) = match input.strip_prefix('-') {
+                        Some(rest) => (true, rest),
+                        None => (false, input),
+                    };
+                    let (epoch, rest) = parse_number(rest, 20, 0, i64::MAX)?;
+                    self.epoch = Some(if negative { -epoch } else { epoch });
+                    rest
+                }
+                'S' => {
+                    let (second, rest) = parse_number(input, 2, 0, 61)?;
+                    self.second = second;
+                    rest
+                }
+                'T' | 'X' => self.parse(input, "%H:%M:%S")?,
+                'u' => parse_number(input, 1, 1, 7)?.1,
+                'w' => parse_number(input, 1, 0, 6)?.1,
+                'U' | 'V' | 'W' => parse_number(input, 2, 0, 53)?.1,
+                'G' => parse_number(input, 4, 0, 9999)?.1,
+                'g' => parse_number(input, 2, 0, 99)?.1,
+                'y' => {
+                    let (year, rest) = parse_number(input, 2, 0, 99)?;
+                    self.short_year = Some(year);
+                    rest
+                }
+                'Y' => {
+                    let (year, rest) = parse_number(input, 4, 0, 9999)?;
+                    self.year = Some(year);
+                    rest
+                }
+                // offsets are read but not applied, like jq does
+                'z' => parse_offset(input)?,
+                'Z' => input.trim_start_matches(|c: char| c.is_ascii_alphabetic()),
+                '%' => input.strip_prefix('%')?,
+                _ => return None,
+            };
+        }
+        Some(input)
+    }
+
+    fn resolve(&self) -> Result<Tm, FilterError> {
+        if let Some(epoch) = self.epoch {
+            return Tm::from_epoch(epoch as f64);
+        }
+        let year = match (self.year, self.century, self.short_year) {
+            (Some(year), _, _) => year,
+            (None, Some(century), short_year) => century * 100 + short_year.unwrap_or(0),
+            (None, None, Some(year)) if year < 69 => 2000 + year,
+            (None, None, Some(year)) => 1900 + year,
+            (None, None, None) => 1900,
+        };
+        // a day of the year alone determines the month and day
+        let (month, day) = match (self.month, self.day, self.yday) {
+            (None, None, Some(yday)) => {
+                let (_, month, day) = civil_from_days(days_from_civil(year, 1, 1) + yday);
+                (month - 1, day)
+            }
+            (month, day, _) => (month.unwrap_or(0), day.unwrap_or(0)),
+        };
+        let mut tm = Tm::from_date(year, month, day);
+        tm.hour = match self.pm {
+            Some(pm) if self.hour12 => self.hour % 12 + if pm { 12 } else { 0 },
+            _ => self.hour,
+        };
+        tm.minute = self.minute;
+        tm.second = self.second as f64;
+        Ok(tm)
+    }
+}
+
+// Up to `width` digits after optional whitespace, within `min..=max`
+fn parse_number(input: &str, width: usize, min: i64, max: i64) -> Option<(i64, &str)> {
+    let input = input.trim_start();
+    let digits = input
+        .bytes()
+        .take(width)
+        .take_while(u8::is_ascii_digit)
+        .count();
+    let number = input[..digits].parse::<i64>().ok()?;
+    (min..=max)
+        .contains(&number)
+        .then_some((number, &input[digits..]))
+}
+
+// A full or three-letter name, ignoring case, and its index
+fn parse_name<'a>(input: &'a str, names: &[&str]) -> Option<(i64, &'a str)> {
+    names.iter().enumerate().find_map(|(index, name)| {
+        [name.len(), name.len().min(3)].into_iter().find_map(|len| {
+            let prefix = input.get(..len)?;
+            prefix
+                .eq_ignore_ascii_case(&name[..len])
+                .then(|| (index as i64, &input[len..]))
+        })
+    })
+}
+
+// `Z`, or a sign followed by hours and optionally minutes
+fn parse_offset(input: &str) -> Option<&str> {
+    if let Some(rest) = input.strip_prefix('Z') {
+        return Some(rest);
+    }
+    let rest = input.strip_prefix(['+', '-'])?;
+    let (_, rest) = parse_number(rest, 2, 0, 99)?;
+    let rest = rest.strip_prefix(':').unwrap_or(rest);
+    Some(parse_number(rest, 2, 0, 99).map_or(rest, |(_, rest)| rest))
+}
+
+// `now`: the current time in seconds since the epoch
+pub fn now() -> Value {
+    let elapsed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |elapsed| elapsed.as_secs_f64());
+    float_value(elapsed)
+}
+
+// `gmtime`: the broken down time of seconds since the epoch
+pub fn gmtime(input: &Value) -> Result<Value, FilterError> {
+    match input {
+        Value::Number(secs) => Ok(Tm::from_epoch(secs.as_f64().unwrap_or(0.0))?.to_value()),
+        _ => Err(FilterError::Requires("gmtime()", "numeric inputs")),
+    }
+}
+
+// `mktime`: seconds since the epoch of a broken down time
+pub fn mktime(input: &Value) -> Result<Value, FilterError> {
+    if !input.is_array() {
+        return Err(FilterError::Requires("mktime", "array inputs"));
+    }
+    Ok(float_value(Tm::from_value(input, "mktime")?.timegm()))
+}
+
+// `strftime(format)` of seconds since the epoch or a broken down time
+pub fn strftime(input: &Value, format: &Value) -> Result<Value, FilterError> {
+    let Value::String(format) = format else {
+        return Err(FilterError::Requires("strftime/1", "a string format"));
+    };
+    let tm = match input {
+        Value::Number(secs) => Tm::from_epoch(secs.as_f64().unwrap_or(0.0))?,
+        other => Tm::from_value(other, "strftime/1")?,
+    };
+    let mut out = String::new();
+    tm.format(format, &mut out);
+    Ok(Value::String(out))
+}
+
+// `strptime(format)`: the broken down time a string describes. Trailing
+// whitespace is allowed; anything else left over is a mismatch.
+pub fn strptime(input: &Value, format: &Value) -> Result<Value, FilterError> {
+    let (Value::String(input), Value::String(format)) = (input, format) else {
+        return Err(FilterError::Requires(
+            "strptime/1",
+            "string inputs and arguments",
+        ));
+    };
+    let mut parsed = Parsed::default();
+    match parsed.parse(input, format) {
+        Some(rest) if rest.trim().is_empty() => Ok(parsed.resolve()?.to_value()),
+        _ => Err(FilterError::DateMismatch(input.clone(), format.clone())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_civil_days_round_trip() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 3, 1), 11017);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+        for days in [-719468, -1, 0, 59, 11016, 11017, 2932896] {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+        // days past the end of the month carry over
+        assert_eq!(days_from_civil(2015, 1, 32), days_from_civil(2015, 2, 1));
+    }
+
+    #[test]
+    fn test_gmtime_and_mktime() {
+        let tm = json!([2015, 2, 5, 23, 53, 41, 4, 63]);
+        assert_eq!(gmtime(&json!(1425599621)).unwrap(), tm);
+        assert_eq!(mktime(&tm).unwrap(), json!(1425599621));
+        assert_eq!(
+            gmtime(&json!(1425599621.25)).unwrap(),
+            json!([2015, 2, 5, 23, 53, 41.25, 4, 63])
+        );
+        assert_eq!(
+            gmtime(&json!(-1.5)).unwrap(),
+            json!([1969, 11, 31, 23, 59, 58.5, 3, 364])
+        );
+        // out-of-range fields carry over and the weekday fields are optional
+        assert_eq!(
+            mktime(&json!([2015, 0, 32, 0, 0, 0])).unwrap(),
+            mktime(&json!([2015, 1, 1, 0, 0, 0])).unwrap()
+        );
+        assert!(gmtime(&json!("a")).is_err());
+        assert!(gmtime(&json!(1e20)).is_err());
+        assert!(mktime(&json!([2015, 2, 5])).is_err());
+        assert!(mktime(&json!("2015")).is_err());
+    }
+
+    #[test]
+    fn test_strftime() {
+        let format = |format: &str| {
+            strftime(&json!(1425599621), &json!(format))
+                .unwrap()
+                .as_str()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(format(ISO8601), "2015-03-05T23:53:41Z");
+        assert_eq!(
+            format("%A, %B %d, %Y %j %a %b %e %I %p %y %Z %z %%"),
+            "Thursday, March 05, 2015 064 Thu Mar  5 11 PM 15 UTC +0000 %"
+        );
+        assert_eq!(
+            format("%C %D %F %T %R %s %u %w %U %W %V %G %g"),
+            "20 03/05/15 2015-03-05 23:53:41 23:53 1425599621 4 4 09 09 10 2015 15"
+        );
+        assert_eq!(format("%c|%Q"), "Thu Mar  5 23:53:41 2015|%Q");
+        // the first days of 2016 belong to the last ISO week of 2015
+        assert_eq!(
+            strftime(&json!([2016, 0, 1, 0, 0, 0, 5, 0]), &json!("%V %G %u")).unwrap(),
+            json!("53 2015 5")
+        );
+        assert!(strftime(&json!("x"), &json!("%Y")).is_err());
+        assert!(strftime(&json!(0), &json!(1)).is_err());
+    }
+
+    #[test]
+    fn test_strptime() {
+        let parse = |input: &str, format: &str| strptime(&json!(input), &json!(format));
+        assert_eq!(
+            parse("2015-03-05T23:51:47Z", ISO8601).unwrap(),
+            json!([2015, 2, 5, 23, 51, 47, 4, 63])
+        );
+        assert_eq!(
+            parse("10 march 2015", "%d %B %Y").unwrap(),
+            json!([2015, 2, 10, 0, 0, 0, 2, 68])
+        );
+        assert_eq!(
+            parse("2015 064", "%Y %j").unwrap(),
+            json!([2015, 2, 5, 0, 0, 0, 4, 63])
+        );
+        assert_eq!(parse("1pm", "%I%p").unwrap()[3], json!(13));
+        assert_eq!(parse("15", "%y").unwrap()[0], json!(2015));
+        assert_eq!(
+            parse("1425599621", "%s").unwrap(),
+            json!([2015, 2, 5, 23, 53, 41, 4, 63])
+        );
+        assert_eq!(
+            parse("Thu Mar  5 23:51:47 2015 +01:00 ", "%c %z").unwrap(),
+            json!([2015, 2, 5, 23, 51, 47, 4, 63])
+        );
+        assert_eq!(
+            parse("2015x", "%Y").unwrap_err().to_string(),
+            "date \"2015x\" does not match format \"%Y\""
+        );
+        assert!(parse("123", "%H").is_err());
+        assert!(strptime(&json!(1), &json!("%Y")).is_err());
+    }
+}
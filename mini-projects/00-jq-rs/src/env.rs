@@ -0,0 +1,125 @@
+use crate::filter::{FilterFn, Param};
+use serde_json::Value;
+use std::rc::Rc;
+
+// Variables and functions visible to a filter, innermost first. Binding a
+// name shares the enclosing bindings instead of copying them, so entering a
+// scope for every element of a large input stays cheap.
+#[derive(Clone, Debug, Default)]
+pub struct Env(Option<Rc<Binding>>);
+
+#[derive(Debug)]
+struct Binding {
+    name: String,
+    entry: Entry,
+    parent: Env,
+}
+
+#[derive(Debug)]
+enum Entry {
+    Variable(Value),
+    Function(usize, Rc<Function>),
+}
+
+// A callable filter together with the scope it closes over
+#[derive(Debug)]
+pub enum Function {
+    // a `def`, run in the scope it was defined in
+    Def {
+        params: Vec<Param>,
+        body: Rc<FilterFn>,
+        env: Env,
+    },
+    // a filter passed as an argument, run in the scope of its caller
+    Arg {
+        body: Rc<FilterFn>,
+        env: Env,
+    },
+}
+
+impl Env {
+    pub fn new() -> Env {
+        Env(None)
+    }
+
+    fn push(&self, name: &str, entry: Entry) -> Env {
+        Env(Some(Rc::new(Binding {
+            name: name.to_string(),
+            entry,
+            parent: self.clone(),
+        })))
+    }
+
+    // A new scope where `$name` refers to `value`, shadowing any outer binding
+    pub fn bind(&self, name: &str, value: Value) -> Env {
+        self.push(name, Entry::Variable(value))
+    }
+
+    // A new scope where `name/arity` calls `function`
+    pub fn define(&self, name: &str, arity: usize, function: Rc<Function>) -> Env {
+        self.push(name, Entry::Function(arity, function))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.bindings().find_map(|binding| match &binding.entry {
+            Entry::Variable(value) if binding.name == name => Some(value),
+            _ => None,
+        })
+    }
+
+    pub fn function(&self, name: &str, arity: usize) -> Option<Rc<Function>> {
+        self.bindings().find_map(|binding| match &binding.entry {
+            Entry::Function(n, function) if *n == arity && binding.name == name => {
+                Some(function.clone())
+            }
+            _ => None,
+        })
+    }
+
+    fn bindings(&self) -> impl Iterator<Item = &Binding> {
+        std::iter::successors(self.0.as_deref(), |binding| binding.parent.0.as_deref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_lookup_missing() {
+        let env = Env::new();
+        assert_eq!(env.get("x"), None);
+    }
+
+    #[test]
+    fn test_inner_binding_shadows_outer() {
+        let outer = Env::new().bind("x", json!(1)).bind("y", json!(2));
+        let inner = outer.bind("x", json!(3));
+        assert_eq!(inner.get("x"), Some(&json!(3)));
+        assert_eq!(inner.get("y"), Some(&json!(2)));
+        assert_eq!(outer.get("x"), Some(&json!(1)));
+    }
+
+    #[test]
+    fn test_functions_keyed_by_arity() {
+        let function = |value: Value| {
+            Rc::new(Function::Arg {
+                body: Rc::new(FilterFn::Literal(value)),
+                env: Env::new(),
+            })
+        };
+        let env = Env::new()
+            .define("f", 0, function(json!(0)))
+            .define("f", 1, function(json!(1)))
+            .bind("f", json!("variable"));
+        let found = |arity| match env.function("f", arity).as_deref() {
+            Some(Function::Arg { body, .. }) => Some((**body).clone()),
+            _ => None,
+        };
+        assert_eq!(found(0), Some(FilterFn::Literal(json!(0))));
+        assert_eq!(found(1), Some(FilterFn::Literal(json!(1))));
+        assert_eq!(found(2), None);
+        assert_eq!(env.get("f"), Some(&json!("variable")));
+    }
+}
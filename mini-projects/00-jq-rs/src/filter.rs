@@ -1,131 +1,686 @@
-use serde_json::Value;
+use crate::builtins::{self, Builtin, BuiltinArg, ByOp};
+use crate::env::{Env, Function};
+use crate::input::{InputError, Inputs};
+use crate::matcher::{Found, Matcher, RegexOp};
+use crate::math::MathFn;
+use crate::ops::{self, float_value, is_truthy, BinOp};
+use crate::output;
+use crate::path::{run_assign, AssignOp, MAX_ARRAY_INDEX};
+use crate::stream::{self, FromStream};
+use serde_json::{Map, Value};
+use std::rc::Rc;
 use thiserror::Error;
 
 // Define custom error types using `thiserror`
 #[derive(Error, Debug)]
 pub enum FilterError {
-    #[error("Key '{0}' not found in object")]
-    KeyNotFound(String),
+    #[error("Cannot index {0} with {1}")]
+    CannotIndex(&'static str, String),
 
-    #[error("Index {0} out of bounds")]
-    IndexOutOfBounds(usize),
+    #[error("Cannot iterate over {0}")]
+    CannotIterate(String),
 
-    #[error("Expected an array but found something else")]
-    ExpectedArray,
+    #[error("Object keys must be strings, not {0}")]
+    ObjectKeyNotString(String),
 
-    #[error("Slice out of bounds")]
-    SliceOutOfBounds,
+    #[error("{0} and {1} cannot be {2}")]
+    InvalidOperands(String, String, &'static str),
 
-    #[error("Invalid type encountered")]
-    InvalidType,
+    #[error("{0} and {1} cannot be {2} because the divisor is zero")]
+    DivisionByZero(String, String, &'static str),
+
+    #[error("{0} cannot be negated")]
+    CannotNegate(String),
+
+    #[error("{0} has no keys")]
+    NoKeys(String),
+
+    #[error("{0} has no length")]
+    NoLength(String),
+
+    #[error("Cannot check whether {0} has a {1} key")]
+    CannotCheckKey(&'static str, &'static str),
+
+    #[error("${0} is not defined")]
+    UndefinedVariable(String),
+
+    #[error("{0} is not defined")]
+    UndefinedFunction(String),
+
+    #[error("Range bounds must be numeric")]
+    RangeNotNumeric,
+
+    #[error("Invalid limit: {0}")]
+    InvalidLimit(String),
+
+    // raised by `limit` to stop its generator early; never reaches the user
+    #[error("break")]
+    Break,
+
+    #[error("split input and separator must be strings")]
+    SplitNotStrings,
+
+    #[error("{0}() requires string inputs")]
+    RequiresStrings(&'static str),
+
+    #[error("{0} input must be {1}")]
+    InputMustBe(&'static str, &'static str),
+
+    #[error("Cannot parse '{0}' as a number")]
+    CannotParseNumber(String),
+
+    #[error("{0} cannot be parsed as a number")]
+    NotParsable(String),
+
+    #[error("{0} only strings can be parsed")]
+    OnlyStringsParsed(String),
+
+    #[error("{0} (while parsing '{1}')")]
+    InvalidJson(String, String),
+
+    #[error("{0} is not a valid format")]
+    UnknownFormat(String),
+
+    #[error("{0} cannot be {1}-formatted, only an array can be")]
+    NotFormattable(String, &'static str),
+
+    #[error("{0} is not valid in a {1} row")]
+    InvalidInRow(String, &'static str),
+
+    #[error("{0} can not be escaped for shell")]
+    NotShellEscapable(String),
+
+    #[error("{0} is not valid base64 data")]
+    InvalidBase64(String),
+
+    #[error("{0} requires {1}")]
+    Requires(&'static str, &'static str),
+
+    #[error("date \"{0}\" does not match format \"{1}\"")]
+    DateMismatch(String, String),
+
+    #[error("error converting number of seconds since epoch to datetime")]
+    DateOutOfRange,
+
+    #[error("{0} number required")]
+    NumberRequired(String),
+
+    #[error("{0} cannot be sorted, as it is not an array")]
+    NotSortable(String),
+
+    #[error("{0} and {1} cannot have their containment checked")]
+    NotContainable(String, String),
+
+    #[error("flatten depth must not be negative")]
+    NegativeFlattenDepth,
+
+    #[error("{0} cannot be matched, as it is not a string")]
+    NotMatchable(String),
+
+    #[error("{0} is not a valid regex: {1}")]
+    InvalidRegex(String, String),
+
+    #[error("{0} is not a valid modifier string")]
+    InvalidRegexFlags(String),
+
+    #[error("Invalid path expression with result {0}")]
+    InvalidPath(String),
+
+    #[error("Path must be specified as an array")]
+    PathNotArray,
+
+    #[error("Out of bounds negative array index")]
+    NegativeIndexOutOfBounds,
+
+    #[error("Array index too large")]
+    ArrayIndexTooLarge,
+
+    #[error("{0} is too many copies to be {1}")]
+    TooManyCopies(String, &'static str),
+
+    #[error("Repeat string result too long")]
+    RepeatTooLong,
+
+    #[error("A slice of an array can only be assigned another array")]
+    SliceAssignment,
+
+    #[error("Cannot delete {0} field of {1}")]
+    CannotDelete(&'static str, &'static str),
+
+    #[error("No more inputs")]
+    NoMoreInputs,
+
+    #[error("Invalid stream event {0}")]
+    InvalidStreamEvent(String),
+
+    #[error("{0}")]
+    Input(#[from] InputError),
+
+    #[error("Error writing output: {0}")]
+    Output(#[from] std::io::Error),
+
+    #[error("{}", error_message(.0))]
+    Custom(Value),
+
+    // raised by `halt` and `halt_error` to end the program with an exit
+    // status and an optional message for stderr; `try` does not catch it
+    #[error("halt")]
+    Halt(u8, Option<Value>),
+
+    #[error("Start and end indices of an array slice must be numbers")]
+    SliceIndices,
 }
 
-// Define the FilterFn enum
-#[derive(Clone)]
+impl FilterError {
+    // The value seen by a `catch` handler: the argument of `error(v)` or the message
+    pub fn value(&self) -> Value {
+        match self {
+            FilterError::Custom(value) => value.clone(),
+            other => Value::String(other.to_string()),
+        }
+    }
+}
+
+fn error_message(value: &Value) -> String {
+    match value {
+        Value::String(msg) => msg.clone(),
+        other => format!("{} (not a string)", other),
+    }
+}
+
+// Expression tree produced by the parser
+#[derive(Clone, Debug, PartialEq)]
 pub enum FilterFn {
     Identity,
+    Literal(Value),
     KeyFilter(String),
-    ArrayIndex(usize),
-    ArraySlice { start: usize, end: Option<usize> },
+    ArrayIndex(i64),
+    ArraySlice {
+        start: i64,
+        end: Option<i64>,
+    },
     ArrayIterator,
+    Index {
+        target: Box<FilterFn>,
+        index: Box<FilterFn>,
+    },
+    Slice {
+        target: Box<FilterFn>,
+        start: Option<Box<FilterFn>>,
+        end: Option<Box<FilterFn>>,
+    },
     Add,
     Length,
+    Not,
     Del(Box<FilterFn>),
+    Select(Box<FilterFn>),
+    Map(Box<FilterFn>),
+    MapValues(Box<FilterFn>),
+    Has(Box<FilterFn>),
+    Keys,
+    KeysUnsorted,
+    Values,
+    Scalars,
+    Type,
+    // the inputs whose type is one of these, e.g. `objects`
+    OfTypes(&'static [&'static str]),
+    ToEntries,
+    FromEntries,
+    WithEntries(Box<FilterFn>),
+    Pipe(Box<FilterFn>, Box<FilterFn>),
+    Comma(Box<FilterFn>, Box<FilterFn>),
+    ArrayConstruct(Option<Box<FilterFn>>),
+    ObjectConstruct(Vec<(FilterFn, FilterFn)>),
+    Binary(BinOp, Box<FilterFn>, Box<FilterFn>),
+    And(Box<FilterFn>, Box<FilterFn>),
+    Or(Box<FilterFn>, Box<FilterFn>),
+    Neg(Box<FilterFn>),
+    Try(Box<FilterFn>),
+    TryCatch(Box<FilterFn>, Box<FilterFn>),
+    If {
+        cond: Box<FilterFn>,
+        then_branch: Box<FilterFn>,
+        else_branch: Option<Box<FilterFn>>,
+    },
+    Alternative(Box<FilterFn>, Box<FilterFn>),
+    Error(Option<Box<FilterFn>>),
+    Halt,
+    // `halt_error(code)`, with status 5 when no code is given
+    HaltError(Option<Box<FilterFn>>),
+    Empty,
+    Variable(String),
+    Environment,
+    Input,
+    Inputs,
+    By(ByOp, Box<FilterFn>),
+    // `any` and `all`: whether some or every output of `cond` for the
+    // outputs of `generator` is truthy
+    Quantify {
+        all: bool,
+        generator: Box<FilterFn>,
+        cond: Box<FilterFn>,
+    },
+    AddOf(Box<FilterFn>),
+    Combinations(Option<Box<FilterFn>>),
+    // a libm function of its input, or of one output of each argument
+    Math(MathFn, Vec<FilterFn>),
+    ToStream,
+    FromStream(Box<FilterFn>),
+    TruncateStream(Box<FilterFn>),
+    Bind {
+        source: Box<FilterFn>,
+        pattern: Pattern,
+        body: Box<FilterFn>,
+    },
+    Reduce {
+        source: Box<FilterFn>,
+        pattern: Pattern,
+        init: Box<FilterFn>,
+        update: Box<FilterFn>,
+    },
+    Foreach {
+        source: Box<FilterFn>,
+        pattern: Pattern,
+        init: Box<FilterFn>,
+        update: Box<FilterFn>,
+        extract: Option<Box<FilterFn>>,
+    },
+    Limit(Box<FilterFn>, Box<FilterFn>),
+    First(Box<FilterFn>),
+    Last(Box<FilterFn>),
+    Range {
+        from: Option<Box<FilterFn>>,
+        upto: Box<FilterFn>,
+        by: Option<Box<FilterFn>>,
+    },
+    While(Box<FilterFn>, Box<FilterFn>),
+    Until(Box<FilterFn>, Box<FilterFn>),
+    Recurse {
+        f: Box<FilterFn>,
+        cond: Option<Box<FilterFn>>,
+    },
+    FuncDef {
+        name: String,
+        params: Vec<Param>,
+        body: Rc<FilterFn>,
+        rest: Box<FilterFn>,
+    },
+    Call {
+        name: String,
+        args: Vec<Rc<FilterFn>>,
+    },
+    Path(Box<FilterFn>),
+    // `paths`, or `paths(f)` keeping those whose value satisfies `f`
+    Paths(Option<Box<FilterFn>>),
+    GetPath(Box<FilterFn>),
+    SetPath(Box<FilterFn>, Box<FilterFn>),
+    DelPaths(Box<FilterFn>),
+    Assign {
+        op: AssignOp,
+        lhs: Box<FilterFn>,
+        rhs: Box<FilterFn>,
+    },
+    Builtin(Builtin),
+    BuiltinArg(BuiltinArg, Box<FilterFn>),
+    Format(String),
+    Regex {
+        op: RegexOp,
+        regex: Box<FilterFn>,
+        flags: Option<Box<FilterFn>>,
+    },
+    // `sub`, or `gsub` when `global`; `replacement` runs on the named captures
+    Sub {
+        regex: Box<FilterFn>,
+        replacement: Box<FilterFn>,
+        flags: Option<Box<FilterFn>>,
+        global: bool,
+    },
+    // a string literal with `\(...)` interpolations, each written in `format`
+    Interpolate {
+        format: String,
+        parts: Vec<StringPart>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum StringPart {
+    Literal(String),
+    Expr(FilterFn),
+}
+
+// Parameter of a `def`: `f` takes a filter, `$x` takes each value of one
+#[derive(Clone, Debug, PartialEq)]
+pub enum Param {
+    Filter(String),
+    Value(String),
+}
+
+// Destructuring target of `source as PATTERN | body`
+#[derive(Clone, Debug, PartialEq)]
+pub enum Pattern {
+    Variable(String),
+    Array(Vec<Pattern>),
+    Object(Vec<ObjectPattern>),
 }
 
-pub struct Filter {}
+// One `key: pattern` entry of an object pattern; `{$name}` binds the value
+// under its own key and `{$name: pattern}` binds it and destructures it further
+#[derive(Clone, Debug, PartialEq)]
+pub struct ObjectPattern {
+    pub key: FilterFn,
+    pub variable: Option<String>,
+    pub pattern: Option<Pattern>,
+}
+
+// Receives each output of a filter as soon as it is produced
+pub type Sink<'a> = dyn FnMut(Value) -> Result<(), FilterError> + 'a;
+
+// Evaluation context of a filter: the variables in scope and the inputs not
+// yet read
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    env: Env,
+    inputs: Inputs,
+}
 
 impl Filter {
     pub fn new() -> Filter {
-        Filter {}
+        Filter::default()
+    }
+
+    // a context where `input` and `inputs` read from `inputs`
+    pub fn with_inputs(mut self, inputs: Inputs) -> Filter {
+        self.inputs = inputs;
+        self
+    }
+
+    // a context where `$name` refers to `value`
+    pub fn bind(&self, name: &str, value: Value) -> Filter {
+        self.with_env(self.env.bind(name, value))
+    }
+
+    fn with_env(&self, env: Env) -> Filter {
+        Filter {
+            env,
+            inputs: self.inputs.clone(),
+        }
+    }
+
+    // the next value of the input stream
+    pub fn next_input(&self) -> Result<Option<Value>, FilterError> {
+        Ok(self.inputs.clone().next().transpose()?)
+    }
+
+    // a context where `name/arity` calls `function`
+    pub fn define(&self, name: &str, arity: usize, function: Function) -> Filter {
+        self.with_env(self.env.define(name, arity, Rc::new(function)))
+    }
+
+    // a context where the `def` of `name` is visible, closing over this one
+    pub fn define_def(&self, name: &str, params: &[Param], body: &Rc<FilterFn>) -> Filter {
+        let function = Function::Def {
+            params: params.to_vec(),
+            body: body.clone(),
+            env: self.env.clone(),
+        };
+        self.define(name, params.len(), function)
+    }
+
+    // running the function `name/args.len()` visible in this context
+    pub fn call(
+        &self,
+        name: &str,
+        args: &[Rc<FilterFn>],
+        input: Value,
+        out: &mut Sink,
+    ) -> Result<(), FilterError> {
+        self.with_function(name, args, &input, &mut |scope, body| {
+            body.run(scope, input.clone(), out)
+        })
+    }
+
+    // passing the body of the function `name/args.len()` to `run`, together
+    // with the context it runs in, once for every binding of its value parameters
+    pub fn with_function(
+        &self,
+        name: &str,
+        args: &[Rc<FilterFn>],
+        input: &Value,
+        run: &mut dyn FnMut(&Filter, &FilterFn) -> Result<(), FilterError>,
+    ) -> Result<(), FilterError> {
+        let function = self
+            .env
+            .function(name, args.len())
+            .ok_or_else(|| FilterError::UndefinedFunction(format!("{}/{}", name, args.len())))?;
+        match &*function {
+            Function::Arg { body, env } => run(&self.with_env(env.clone()), body),
+            Function::Def { params, body, env } => {
+                // the definition is visible in its own body, allowing recursion
+                let scope = self.with_env(env.define(name, args.len(), function.clone()));
+                scope.bind_params(params, args, self, input, &mut |scope| run(&scope, body))
+            }
+        }
+    }
+
+    // binding each parameter to its argument; filter arguments keep the
+    // caller's scope and value arguments run once per output
+    fn bind_params(
+        &self,
+        params: &[Param],
+        args: &[Rc<FilterFn>],
+        caller: &Filter,
+        input: &Value,
+        body: &mut dyn FnMut(Filter) -> Result<(), FilterError>,
+    ) -> Result<(), FilterError> {
+        let (Some((param, params)), Some((arg, args))) = (params.split_first(), args.split_first())
+        else {
+            return body(self.clone());
+        };
+        match param {
+            Param::Filter(name) => {
+                let arg = Function::Arg {
+                    body: arg.clone(),
+                    env: caller.env.clone(),
+                };
+                self.define(name, 0, arg)
+                    .bind_params(params, args, caller, input, body)
+            }
+            Param::Value(name) => arg.run(caller, input.clone(), &mut |value| {
+                // `$x` is also callable as the filter `x`
+                let arg = Function::Arg {
+                    body: Rc::new(FilterFn::Literal(value.clone())),
+                    env: Env::new(),
+                };
+                self.bind(name, value)
+                    .define(name, 0, arg)
+                    .bind_params(params, args, caller, input, body)
+            }),
+        }
+    }
+
+    pub fn variable(&self, name: &str) -> Result<Value, FilterError> {
+        self.env
+            .get(name)
+            .cloned()
+            .ok_or_else(|| FilterError::UndefinedVariable(name.to_string()))
     }
 
-    // accessing a key in a JSON object
+    // binding the variables of `pattern` to the parts of `value` and calling `body`
+    // with each resulting context; `input` is the value computed keys see as `.`
+    pub fn destructure(
+        &self,
+        pattern: &Pattern,
+        value: Value,
+        input: &Value,
+        body: &mut dyn FnMut(Filter) -> Result<(), FilterError>,
+    ) -> Result<(), FilterError> {
+        match pattern {
+            Pattern::Variable(name) => body(self.bind(name, value)),
+            Pattern::Array(items) => self.destructure_array(items, 0, &value, input, body),
+            Pattern::Object(entries) => self.destructure_object(entries, &value, input, body),
+        }
+    }
+
+    fn destructure_array(
+        &self,
+        items: &[Pattern],
+        index: i64,
+        value: &Value,
+        input: &Value,
+        body: &mut dyn FnMut(Filter) -> Result<(), FilterError>,
+    ) -> Result<(), FilterError> {
+        let Some((first, rest)) = items.split_first() else {
+            return body(self.clone());
+        };
+        let element = self.array_index(value.clone(), index)?;
+        self.destructure(first, element, input, &mut |scope| {
+            scope.destructure_array(rest, index + 1, value, input, body)
+        })
+    }
+
+    fn destructure_object(
+        &self,
+        entries: &[ObjectPattern],
+        value: &Value,
+        input: &Value,
+        body: &mut dyn FnMut(Filter) -> Result<(), FilterError>,
+    ) -> Result<(), FilterError> {
+        let Some((entry, rest)) = entries.split_first() else {
+            return body(self.clone());
+        };
+        entry.key.run(self, input.clone(), &mut |key| {
+            let field = self.index(value.clone(), &key)?;
+            let scope = match &entry.variable {
+                Some(name) => self.bind(name, field.clone()),
+                None => self.clone(),
+            };
+            match &entry.pattern {
+                Some(pattern) => scope.destructure(pattern, field, input, &mut |scope| {
+                    scope.destructure_object(rest, value, input, body)
+                }),
+                None => scope.destructure_object(rest, value, input, body),
+            }
+        })
+    }
+
+    // accessing a key in a JSON object; missing keys and null inputs give null
     pub fn key_filter(&self, input: Value, key: &str) -> Result<Value, FilterError> {
         match input {
-            Value::Object(map) => match map.get(key) {
-                Some(v) => Ok(v.clone()),
-                None => Err(FilterError::KeyNotFound(key.to_string())),
-            },
-            _ => Err(FilterError::InvalidType),
+            Value::Object(map) => Ok(map.get(key).cloned().unwrap_or(Value::Null)),
+            Value::Null => Ok(Value::Null),
+            other => Err(FilterError::CannotIndex(
+                type_name(&other),
+                format!("string {:?}", key),
+            )),
         }
     }
 
-    // accessing an index in a JSON array
-    pub fn array_index(&self, input: Value, index: usize) -> Result<Value, FilterError> {
+    // accessing an index in a JSON array; negative indices count from the end
+    // and indices past either end give null
+    pub fn array_index(&self, input: Value, index: i64) -> Result<Value, FilterError> {
         match input {
-            Value::Array(arr) => arr
-                .get(index)
+            Value::Array(arr) => Ok(resolve_position(index, arr.len())
+                .and_then(|i| arr.get(i))
                 .cloned()
-                .ok_or(FilterError::IndexOutOfBounds(index)),
-            _ => Err(FilterError::ExpectedArray),
+                .unwrap_or(Value::Null)),
+            Value::Null => Ok(Value::Null),
+            other => Err(FilterError::CannotIndex(
+                type_name(&other),
+                "number".to_string(),
+            )),
         }
     }
 
-    // accessing a slice of a JSON array
+    // accessing a slice of a JSON array or string; negative bounds count from
+    // the end and bounds outside the sequence are clamped to it
     pub fn array_slice(
         &self,
         input: Value,
-        start: usize,
-        end: Option<usize>,
+        start: i64,
+        end: Option<i64>,
     ) -> Result<Value, FilterError> {
+        let range = |len: usize| {
+            let start = clamp_position(start, len);
+            let end = end.map_or(len, |end| clamp_position(end, len));
+            start..end.max(start)
+        };
         match input {
-            Value::Array(arr) => {
-                let slice = match end {
-                    Some(end_idx) => arr.get(start..end_idx),
-                    None => arr.get(start..),
-                };
-                match slice {
-                    Some(s) => Ok(Value::Array(s.to_vec())),
-                    None => Err(FilterError::SliceOutOfBounds),
-                }
+            Value::Array(arr) => Ok(Value::Array(arr[range(arr.len())].to_vec())),
+            Value::String(s) => {
+                let chars: Vec<char> = s.chars().collect();
+                Ok(Value::String(chars[range(chars.len())].iter().collect()))
             }
-            _ => Err(FilterError::ExpectedArray),
+            Value::Null => Ok(Value::Null),
+            other => Err(FilterError::CannotIndex(
+                type_name(&other),
+                "object".to_string(),
+            )),
         }
     }
 
-    pub fn add(&self, input: Value) -> Result<Value, FilterError> {
+    // indexing with a computed key: strings index objects, numbers index arrays
+    pub fn index(&self, input: Value, key: &Value) -> Result<Value, FilterError> {
+        match (&input, key) {
+            (Value::Object(_) | Value::Null, Value::String(key)) => self.key_filter(input, key),
+            (Value::Array(_) | Value::Null, Value::Number(num)) => {
+                let index = num.as_f64().unwrap_or(0.0).floor() as i64;
+                self.array_index(input, index)
+            }
+            (_, Value::String(key)) => Err(FilterError::CannotIndex(
+                type_name(&input),
+                format!("string {:?}", key),
+            )),
+            _ => Err(FilterError::CannotIndex(
+                type_name(&input),
+                type_name(key).to_string(),
+            )),
+        }
+    }
+
+    // iterating over the elements of an array or the values of an object
+    pub fn iterate(&self, input: Value) -> Result<Vec<Value>, FilterError> {
         match input {
-            Value::Array(arr) => {
-                let mut sum_i64 = 0i64;
-                let mut sum_f64 = 0.0f64;
-                let mut concatenated_string = String::new();
-                let mut has_number = false;
-                let mut has_float = false;
-                let mut has_string = false;
+            Value::Array(arr) => Ok(arr),
+            Value::Object(map) => Ok(map.into_iter().map(|(_, v)| v).collect()),
+            other => Err(FilterError::CannotIterate(describe(&other))),
+        }
+    }
 
-                for value in arr {
-                    match value {
-                        Value::Number(num) => {
-                            if let Some(i) = num.as_i64() {
-                                sum_i64 += i;
-                                has_number = true;
-                            } else if let Some(f) = num.as_f64() {
-                                sum_f64 += f;
-                                has_float = true;
-                            }
-                        }
-                        Value::String(s) => {
-                            concatenated_string.push_str(&s);
-                            has_string = true;
-                        }
-                        _ => {
-                            return Err(FilterError::InvalidType);
-                        }
+    // building every object described by `{k: v, ...}`; each key and value
+    // output multiplies the number of objects, earlier entries varying slowest
+    pub fn construct_objects(
+        &self,
+        entries: &[(FilterFn, FilterFn)],
+        input: &Value,
+    ) -> Result<Vec<Map<String, Value>>, FilterError> {
+        let mut objects = vec![Map::new()];
+        for (key_fn, value_fn) in entries {
+            let keys = key_fn.apply(self, vec![input.clone()])?;
+            let values = value_fn.apply(self, vec![input.clone()])?;
+            let mut extended = Vec::new();
+            for object in &objects {
+                for key in &keys {
+                    let key = match key {
+                        Value::String(key) => key,
+                        other => return Err(FilterError::ObjectKeyNotString(describe(other))),
+                    };
+                    for value in &values {
+                        let mut object = object.clone();
+                        object.insert(key.clone(), value.clone());
+                        extended.push(object);
                     }
                 }
-
-                if has_string && !concatenated_string.is_empty() {
-                    Ok(Value::String(concatenated_string))
-                } else if has_float || has_number {
-                    let total = sum_f64 + sum_i64 as f64;
-                    Ok(Value::Number(serde_json::Number::from_f64(total).unwrap()))
-                } else {
-                    Err(FilterError::InvalidType)
-                }
             }
-            _ => Err(FilterError::ExpectedArray),
+            objects = extended;
         }
+        Ok(objects)
+    }
+
+    // summing the elements of an array or the values of an object with `+`
+    pub fn add(&self, input: Value) -> Result<Value, FilterError> {
+        self.iterate(input)?
+            .into_iter()
+            .try_fold(Value::Null, ops::add)
     }
 
     pub fn length(&self, input: Value) -> Result<Value, FilterError> {
@@ -133,93 +688,998 @@ impl Filter {
             Value::Array(arr) => Ok(Value::Number(serde_json::Number::from(arr.len()))),
             Value::Object(map) => Ok(Value::Number(serde_json::Number::from(map.len()))),
             Value::String(s) => Ok(Value::Number(serde_json::Number::from(s.chars().count()))),
-            _ => Err(FilterError::InvalidType),
+            // the length of a number is its absolute value
+            Value::Number(_) => MathFn::Fabs.apply(&[input]),
+            Value::Null => Ok(Value::Number(serde_json::Number::from(0))),
+            Value::Bool(_) => Err(FilterError::NoLength(describe(&input))),
         }
     }
 
+    // deleting every path `target` reaches
     pub fn del(&self, input: Value, target: &FilterFn) -> Result<Value, FilterError> {
-        match target {
-            FilterFn::KeyFilter(key) => {
-                if let Value::Object(mut map) = input {
-                    map.remove(key);
-                    Ok(Value::Object(map))
-                } else {
-                    Err(FilterError::InvalidType)
+        let paths = target.collect_paths(self, &input)?;
+        self.delpaths(input, paths.into_iter().map(Value::Array).collect())
+    }
+
+    // the sorted keys of an object or the indices of an array
+    pub fn keys(&self, input: Value) -> Result<Value, FilterError> {
+        match input {
+            Value::Object(map) => {
+                let mut keys: Vec<String> = map.into_iter().map(|(k, _)| k).collect();
+                keys.sort();
+                Ok(Value::Array(keys.into_iter().map(Value::String).collect()))
+            }
+            other => self.keys_unsorted(other),
+        }
+    }
+
+    // like `keys`, with an object's keys in the order they were inserted
+    pub fn keys_unsorted(&self, input: Value) -> Result<Value, FilterError> {
+        match input {
+            Value::Object(map) => Ok(Value::Array(
+                map.into_iter().map(|(k, _)| Value::String(k)).collect(),
+            )),
+            Value::Array(arr) => Ok(Value::Array((0..arr.len()).map(Value::from).collect())),
+            other => Err(FilterError::NoKeys(describe(&other))),
+        }
+    }
+
+    // whether an object has a string key or an array has a numeric index
+    pub fn has(&self, input: &Value, key: &Value) -> Result<Value, FilterError> {
+        match (input, key) {
+            (Value::Object(map), Value::String(key)) => Ok(Value::Bool(map.contains_key(key))),
+            (Value::Array(arr), Value::Number(num)) => {
+                let index = num.as_f64().unwrap_or(0.0);
+                Ok(Value::Bool(index >= 0.0 && index < arr.len() as f64))
+            }
+            _ => Err(FilterError::CannotCheckKey(
+                type_name(input),
+                type_name(key),
+            )),
+        }
+    }
+
+    // turning `{k: v}` into `[{"key": k, "value": v}]`
+    pub fn to_entries(&self, input: Value) -> Result<Value, FilterError> {
+        let entry = |key: Value, value: Value| {
+            let mut entry = Map::new();
+            entry.insert("key".to_string(), key);
+            entry.insert("value".to_string(), value);
+            Value::Object(entry)
+        };
+        match input {
+            Value::Object(map) => Ok(Value::Array(
+                map.into_iter()
+                    .map(|(key, value)| entry(Value::String(key), value))
+                    .collect(),
+            )),
+            // the keys of an array are its indices
+            Value::Array(values) => Ok(Value::Array(
+                values
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, value)| entry(Value::from(index), value))
+                    .collect(),
+            )),
+            other => Err(FilterError::NoKeys(describe(&other))),
+        }
+    }
+
+    // the inverse of `to_entries`, following jq's definition: the key is
+    // `.key` unless that is null, then the first truthy of `k`, `name`, `Name`
+    // and `K`, else `Key`, written as JSON unless it is a string; the value
+    // is `.value` if the entry has one, else `.v`
+    pub fn collect_entries(&self, input: Value) -> Result<Value, FilterError> {
+        const KEY_FALLBACKS: [&str; 4] = ["k", "name", "Name", "K"];
+        let mut object = Map::new();
+        for entry in self.iterate(input)? {
+            let Value::Object(mut entry) = entry else {
+                return Err(FilterError::CannotIndex(
+                    type_name(&entry),
+                    "string \"key\"".to_string(),
+                ));
+            };
+            let key = match entry.get("key") {
+                Some(key) if !key.is_null() => key,
+                _ => KEY_FALLBACKS
+                    .iter()
+                    .filter_map(|name| entry.get(*name))
+                    .find(|key| is_truthy(key))
+                    .or_else(|| entry.get("Key"))
+                    .unwrap_or(&Value::Null),
+            };
+            let key = match key {
+                Value::String(key) => key.clone(),
+                other => output::to_json(other),
+            };
+            let value = match entry.remove("value") {
+                Some(value) => value,
+                None => entry.remove("v").unwrap_or(Value::Null),
+            };
+            object.insert(key, value);
+        }
+        Ok(Value::Object(object))
+    }
+
+    // replacing every array element or object value with the first output of
+    // `f`, dropping the ones for which `f` produces nothing
+    pub fn map_values(&self, input: Value, f: &FilterFn) -> Result<Value, FilterError> {
+        let first = |value: Value| -> Result<Option<Value>, FilterError> {
+            Ok(f.apply(self, vec![value])?.into_iter().next())
+        };
+        match input {
+            Value::Array(arr) => {
+                let mut mapped = Vec::with_capacity(arr.len());
+                for value in arr {
+                    mapped.extend(first(value)?);
                 }
+                Ok(Value::Array(mapped))
             }
-            FilterFn::ArrayIndex(index) => {
-                if let Value::Array(mut arr) = input {
-                    if *index < arr.len() {
-                        arr.remove(*index);
-                        Ok(Value::Array(arr))
-                    } else {
-                        Err(FilterError::IndexOutOfBounds(*index))
+            Value::Object(map) => {
+                let mut mapped = Map::new();
+                for (key, value) in map {
+                    if let Some(value) = first(value)? {
+                        mapped.insert(key, value);
                     }
-                } else {
-                    Err(FilterError::ExpectedArray)
                 }
+                Ok(Value::Object(mapped))
             }
-            _ => Err(FilterError::InvalidType),
+            other => Err(FilterError::CannotIterate(describe(&other))),
         }
     }
 }
 
+// Whether a path component is the `{"start": s, "end": e}` of a slice
+pub fn is_slice(component: &Value) -> bool {
+    matches!(component, Value::Object(map) if map.contains_key("start") || map.contains_key("end"))
+}
+
+// Resolve a possibly negative position against a sequence of length `len`
+fn resolve_position(pos: i64, len: usize) -> Option<usize> {
+    let resolved = if pos < 0 { len as i64 + pos } else { pos };
+    if (0..=len as i64).contains(&resolved) {
+        Some(resolved as usize)
+    } else {
+        None
+    }
+}
+
+// Like `resolve_position`, but clamps positions outside the sequence to its ends
+pub fn clamp_position(pos: i64, len: usize) -> usize {
+    let resolved = if pos < 0 { len as i64 + pos } else { pos };
+    resolved.clamp(0, len as i64) as usize
+}
+
+// The jq name for the type of a value
+pub fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+// Type and abbreviated value for error messages, e.g. `number (5)`
+pub fn describe(value: &Value) -> String {
+    format!("{} ({})", type_name(value), abbreviate(value))
+}
+
+// The JSON text of a value, cut short if it is long
+pub fn abbreviate(value: &Value) -> String {
+    let mut text = value.to_string();
+    if text.len() > 11 {
+        let mut cut = 10;
+        while !text.is_char_boundary(cut) {
+            cut -= 1;
+        }
+        text.truncate(cut);
+        text.push_str("...");
+    }
+    text
+}
+
+// Convert a computed slice bound to an integer, `null` meaning "open"
+pub fn slice_bound(bound: &Value, round: fn(f64) -> f64) -> Result<Option<i64>, FilterError> {
+    match bound {
+        Value::Null => Ok(None),
+        Value::Number(num) => Ok(Some(round(num.as_f64().unwrap_or(0.0)) as i64)),
+        _ => Err(FilterError::SliceIndices),
+    }
+}
+
 impl FilterFn {
+    // Run the filter on a single value and keep only its last output
+    pub fn run_last(&self, filter: &Filter, input: Value) -> Result<Option<Value>, FilterError> {
+        let mut last = None;
+        self.run(filter, input, &mut |value| {
+            last = Some(value);
+            Ok(())
+        })?;
+        Ok(last)
+    }
+
+    // Run the filter on a single value and keep only its first output, without
+    // running it any further
+    pub fn run_first(&self, filter: &Filter, input: Value) -> Result<Option<Value>, FilterError> {
+        let mut first = None;
+        run_limited(self, filter, input, 1, &mut |value| {
+            first = Some(value);
+            Ok(())
+        })?;
+        Ok(first)
+    }
+
+    // Run the filter on every value of the stream and collect all outputs
     pub fn apply(&self, filter: &Filter, values: Vec<Value>) -> Result<Vec<Value>, FilterError> {
         let mut results = Vec::new();
+        for value in values {
+            self.run(filter, value, &mut |result| {
+                results.push(result);
+                Ok(())
+            })?;
+        }
+        Ok(results)
+    }
+
+    // Evaluate the filter against a single input, passing every output to `out`
+    pub fn run(&self, filter: &Filter, input: Value, out: &mut Sink) -> Result<(), FilterError> {
         match self {
-            FilterFn::Identity => {
-                results.extend(values);
+            FilterFn::Identity => out(input),
+            FilterFn::Literal(value) => out(value.clone()),
+            FilterFn::KeyFilter(key) => out(filter.key_filter(input, key)?),
+            FilterFn::ArrayIndex(index) => out(filter.array_index(input, *index)?),
+            FilterFn::ArraySlice { start, end } => out(filter.array_slice(input, *start, *end)?),
+            FilterFn::ArrayIterator => {
+                for value in filter.iterate(input)? {
+                    out(value)?;
+                }
+                Ok(())
+            }
+            FilterFn::Index { target, index } => index.run(filter, input.clone(), &mut |key| {
+                target.run(filter, input.clone(), &mut |value| {
+                    out(filter.index(value, &key)?)
+                })
+            }),
+            FilterFn::Slice { target, start, end } => {
+                run_slice(target, start.as_deref(), end.as_deref(), filter, input, out)
             }
-            FilterFn::KeyFilter(key) => {
-                for value in values {
-                    let result = filter.key_filter(value, key)?;
-                    results.push(result);
+            FilterFn::Add => out(filter.add(input)?),
+            FilterFn::Length => out(filter.length(input)?),
+            FilterFn::Not => out(Value::Bool(!is_truthy(&input))),
+            FilterFn::Del(target) => out(filter.del(input, target)?),
+            FilterFn::Select(cond) => cond.run(filter, input.clone(), &mut |value| {
+                if is_truthy(&value) {
+                    out(input.clone())?;
+                }
+                Ok(())
+            }),
+            FilterFn::Map(f) => {
+                let mut mapped = Vec::new();
+                for value in filter.iterate(input)? {
+                    f.run(filter, value, &mut |result| {
+                        mapped.push(result);
+                        Ok(())
+                    })?;
                 }
+                out(Value::Array(mapped))
             }
-            FilterFn::ArrayIndex(index) => {
-                for value in values {
-                    let result = filter.array_index(value, *index)?;
-                    results.push(result);
+            FilterFn::MapValues(f) => out(filter.map_values(input, f)?),
+            FilterFn::Has(key) => key.run(filter, input.clone(), &mut |key| {
+                out(filter.has(&input, &key)?)
+            }),
+            FilterFn::Keys => out(filter.keys(input)?),
+            FilterFn::KeysUnsorted => out(filter.keys_unsorted(input)?),
+            FilterFn::Values => match input {
+                Value::Null => Ok(()),
+                input => out(input),
+            },
+            FilterFn::Scalars => match input {
+                Value::Array(_) | Value::Object(_) => Ok(()),
+                input => out(input),
+            },
+            FilterFn::Type => out(Value::String(type_name(&input).to_string())),
+            FilterFn::OfTypes(types) if types.contains(&type_name(&input)) => out(input),
+            FilterFn::OfTypes(_) => Ok(()),
+            FilterFn::ToEntries => out(filter.to_entries(input)?),
+            FilterFn::FromEntries => out(filter.collect_entries(input)?),
+            FilterFn::WithEntries(f) => {
+                let entries = filter.to_entries(input)?;
+                let mapped = FilterFn::Map(f.clone()).apply(filter, vec![entries])?;
+                for entries in mapped {
+                    out(filter.collect_entries(entries)?)?;
                 }
+                Ok(())
+            }
+            FilterFn::Pipe(lhs, rhs) => {
+                lhs.run(filter, input, &mut |value| rhs.run(filter, value, out))
+            }
+            FilterFn::Comma(lhs, rhs) => {
+                lhs.run(filter, input.clone(), out)?;
+                rhs.run(filter, input, out)
+            }
+            FilterFn::ArrayConstruct(None) => out(Value::Array(Vec::new())),
+            FilterFn::ArrayConstruct(Some(inner)) => {
+                out(Value::Array(inner.apply(filter, vec![input])?))
             }
-            FilterFn::ArraySlice { start, end } => {
-                for value in values {
-                    let result = filter.array_slice(value, *start, *end)?;
-                    results.push(result);
+            FilterFn::ObjectConstruct(entries) => {
+                for object in filter.construct_objects(entries, &input)? {
+                    out(Value::Object(object))?;
                 }
+                Ok(())
             }
-            FilterFn::ArrayIterator => {
-                for value in values {
-                    match value {
-                        Value::Array(arr) => {
-                            results.extend(arr);
+            FilterFn::Binary(op, lhs, rhs) => {
+                // jq varies the left operand fastest: `(1,2) + (10,20)` is 11, 12, 21, 22.
+                // The right operand is collected first so that recursive calls on
+                // both sides, as in `(. - 1 | fib) + (. - 2 | fib)`, do not nest
+                for r in rhs.apply(filter, vec![input.clone()])? {
+                    lhs.run(filter, input.clone(), &mut |l| out(op.apply(l, r.clone())?))?;
+                }
+                Ok(())
+            }
+            FilterFn::And(lhs, rhs) => lhs.run(filter, input.clone(), &mut |l| {
+                if !is_truthy(&l) {
+                    return out(Value::Bool(false));
+                }
+                rhs.run(filter, input.clone(), &mut |r| {
+                    out(Value::Bool(is_truthy(&r)))
+                })
+            }),
+            FilterFn::Or(lhs, rhs) => lhs.run(filter, input.clone(), &mut |l| {
+                if is_truthy(&l) {
+                    return out(Value::Bool(true));
+                }
+                rhs.run(filter, input.clone(), &mut |r| {
+                    out(Value::Bool(is_truthy(&r)))
+                })
+            }),
+            FilterFn::Neg(inner) => inner.run(filter, input, &mut |value| out(ops::negate(value)?)),
+            FilterFn::Try(body) => run_try(body, filter, input, out).map(|_| ()),
+            FilterFn::TryCatch(body, handler) => match run_try(body, filter, input, out)? {
+                Some(err) => handler.run(filter, err.value(), out),
+                None => Ok(()),
+            },
+            FilterFn::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => cond.run(filter, input.clone(), &mut |value| match else_branch {
+                _ if is_truthy(&value) => then_branch.run(filter, input.clone(), out),
+                Some(else_branch) => else_branch.run(filter, input.clone(), out),
+                None => out(input.clone()),
+            }),
+            FilterFn::Alternative(lhs, rhs) => run_alternative(lhs, rhs, filter, input, out),
+            FilterFn::Error(None) => Err(FilterError::Custom(input)),
+            FilterFn::Error(Some(msg)) => {
+                msg.run(filter, input, &mut |msg| Err(FilterError::Custom(msg)))
+            }
+            FilterFn::Halt => Err(FilterError::Halt(0, None)),
+            FilterFn::HaltError(None) => Err(FilterError::Halt(5, Some(input))),
+            FilterFn::HaltError(Some(code)) => code.run(filter, input.clone(), &mut |code| {
+                let code = code
+                    .as_f64()
+                    .ok_or(FilterError::Requires("halt_error/1", "a number"))?;
+                // exit statuses wrap around like they do in C
+                let status = (code as i64).rem_euclid(256) as u8;
+                Err(FilterError::Halt(status, Some(input.clone())))
+            }),
+            FilterFn::Empty => Ok(()),
+            FilterFn::Variable(name) => out(filter.variable(name)?),
+            // variables that are not valid unicode are kept, lossily, rather
+            // than making `env` panic
+            FilterFn::Environment => out(Value::Object(
+                std::env::vars_os()
+                    .map(|(k, v)| {
+                        let value = Value::String(v.to_string_lossy().into_owned());
+                        (k.to_string_lossy().into_owned(), value)
+                    })
+                    .collect(),
+            )),
+            FilterFn::Input => out(filter.next_input()?.ok_or(FilterError::NoMoreInputs)?),
+            FilterFn::Inputs => {
+                while let Some(value) = filter.next_input()? {
+                    out(value)?;
+                }
+                Ok(())
+            }
+            // elements are ordered by the array of their key's outputs
+            FilterFn::By(op, key) => {
+                let keys = builtins::sortable(&input)?
+                    .iter()
+                    .map(|item| key.apply(filter, vec![item.clone()]).map(Value::Array))
+                    .collect::<Result<Vec<_>, _>>()?;
+                out(op.apply(input, keys))
+            }
+            FilterFn::Quantify {
+                all,
+                generator,
+                cond,
+            } => {
+                // stop at the first output that settles the answer
+                let mut settled = false;
+                let result = generator.run(filter, input, &mut |value| {
+                    cond.run(filter, value, &mut |test| {
+                        if is_truthy(&test) != *all {
+                            settled = true;
+                            return Err(FilterError::Break);
                         }
-                        _ => return Err(FilterError::ExpectedArray),
+                        Ok(())
+                    })
+                });
+                match result {
+                    Err(FilterError::Break) if settled => {}
+                    other => other?,
+                }
+                out(Value::Bool(settled != *all))
+            }
+            FilterFn::AddOf(f) => {
+                let mut sum = Value::Null;
+                f.run(filter, input, &mut |value| {
+                    sum = ops::add(std::mem::take(&mut sum), value)?;
+                    Ok(())
+                })?;
+                out(sum)
+            }
+            FilterFn::Combinations(None) => {
+                let arrays = filter
+                    .iterate(input)?
+                    .into_iter()
+                    .map(|array| match array {
+                        Value::Array(items) => Ok(items),
+                        other => Err(FilterError::CannotIterate(describe(&other))),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                builtins::combinations(arrays.len(), &|i| &arrays[i], out)
+            }
+            // `combinations(n)` combines `n` copies of the input, as many as an
+            // array may hold
+            FilterFn::Combinations(Some(n)) => n.run(filter, input.clone(), &mut |n| {
+                let Value::Array(items) = &input else {
+                    return Err(FilterError::CannotIterate(describe(&input)));
+                };
+                let copies = match n.as_f64() {
+                    Some(n) if n > MAX_ARRAY_INDEX as f64 && !items.is_empty() => {
+                        return Err(FilterError::TooManyCopies(
+                            describe(&float_value(n)),
+                            "combined",
+                        ))
+                    }
+                    Some(n) if n > 0.0 => n.ceil() as usize,
+                    _ => 0,
+                };
+                builtins::combinations(copies, &|_| items, out)
+            }),
+            FilterFn::Math(op, args) if args.is_empty() => out(op.apply(&[input])?),
+            FilterFn::Math(op, args) => {
+                math_args(filter, args, &input, &mut Vec::new(), &mut |numbers| {
+                    out(op.apply(numbers)?)
+                })
+            }
+            FilterFn::ToStream => stream::to_stream(&input, out),
+            FilterFn::FromStream(events) => {
+                let mut from = FromStream::default();
+                events.run(
+                    filter,
+                    input,
+                    &mut |event| match from.push(filter, event)? {
+                        Some(value) => out(value),
+                        None => Ok(()),
+                    },
+                )
+            }
+            // the input is the depth to remove; the events are run on null
+            FilterFn::TruncateStream(events) => {
+                let depth = match input.as_f64() {
+                    Some(depth) if depth >= 0.0 => depth as usize,
+                    _ => {
+                        return Err(FilterError::InputMustBe(
+                            "truncate_stream",
+                            "a non-negative number",
+                        ))
                     }
+                };
+                events.run(
+                    filter,
+                    Value::Null,
+                    &mut |event| match stream::truncate(event, depth)? {
+                        Some(event) => out(event),
+                        None => Ok(()),
+                    },
+                )
+            }
+            FilterFn::Bind {
+                source,
+                pattern,
+                body,
+            } => source.run(filter, input.clone(), &mut |value| {
+                filter.destructure(pattern, value, &input, &mut |scope| {
+                    body.run(&scope, input.clone(), out)
+                })
+            }),
+            FilterFn::FuncDef {
+                name,
+                params,
+                body,
+                rest,
+            } => rest.run(&filter.define_def(name, params, body), input, out),
+            FilterFn::Call { name, args } => filter.call(name, args, input, out),
+            FilterFn::Reduce {
+                source,
+                pattern,
+                init,
+                update,
+            } => run_reduce(source, pattern, init, update, filter, input, out),
+            FilterFn::Foreach {
+                source,
+                pattern,
+                init,
+                update,
+                extract,
+            } => {
+                let extract = extract.as_deref();
+                run_foreach(source, pattern, init, update, extract, filter, input, out)
+            }
+            FilterFn::Limit(n, f) => n.run(filter, input.clone(), &mut |n| {
+                let n = match n {
+                    Value::Number(n) => n.as_f64().unwrap_or(0.0),
+                    other => return Err(FilterError::InvalidLimit(describe(&other))),
+                };
+                if n < 0.0 {
+                    return f.run(filter, input.clone(), out);
                 }
+                run_limited(f, filter, input.clone(), n.ceil() as usize, out)
+            }),
+            FilterFn::First(f) => run_limited(f, filter, input, 1, out),
+            FilterFn::Last(f) => match f.run_last(filter, input)? {
+                Some(last) => out(last),
+                None => Ok(()),
+            },
+            FilterFn::Range { from, upto, by } => {
+                run_range(from.as_deref(), upto, by.as_deref(), filter, input, out)
             }
-            FilterFn::Add => {
-                if values.len() != 1 {
-                    return Err(FilterError::InvalidType);
+            FilterFn::While(cond, update) => run_loop(cond, update, false, filter, input, out),
+            FilterFn::Until(cond, update) => run_loop(cond, update, true, filter, input, out),
+            FilterFn::Recurse { f, cond } => run_recurse(f, cond.as_deref(), filter, input, out),
+            FilterFn::Path(f) => f.run_paths(filter, Vec::new(), input, &mut |path, _| {
+                out(Value::Array(path))
+            }),
+            FilterFn::Paths(f) => run_paths_filtered(f.as_deref(), filter, input, out),
+            FilterFn::GetPath(path) => path.run(filter, input.clone(), &mut |path| match path {
+                Value::Array(path) => out(filter.getpath(&input, &path)?),
+                _ => Err(FilterError::PathNotArray),
+            }),
+            FilterFn::SetPath(path, value) => {
+                for value in value.apply(filter, vec![input.clone()])? {
+                    path.run(filter, input.clone(), &mut |path| match path {
+                        Value::Array(path) => {
+                            out(filter.setpath(input.clone(), &path, value.clone())?)
+                        }
+                        _ => Err(FilterError::PathNotArray),
+                    })?;
                 }
-                let result = filter.add(values.into_iter().next().unwrap())?;
-                results.push(result);
+                Ok(())
+            }
+            FilterFn::DelPaths(paths) => {
+                paths.run(filter, input.clone(), &mut |paths| match paths {
+                    Value::Array(paths) => out(filter.delpaths(input.clone(), paths)?),
+                    _ => Err(FilterError::PathNotArray),
+                })
+            }
+            FilterFn::Assign { op, lhs, rhs } => run_assign(op, lhs, rhs, filter, input, out),
+            FilterFn::Builtin(builtin) => out(builtin.apply(input)?),
+            FilterFn::BuiltinArg(builtin, arg) => arg.run(filter, input.clone(), &mut |arg| {
+                out(builtin.apply(&input, arg)?)
+            }),
+            FilterFn::Format(name) => out(Value::String(builtins::format(name, &input)?)),
+            FilterFn::Regex { op, regex, flags } => {
+                regex.run(filter, input.clone(), &mut |regex| {
+                    run_flags(flags.as_deref(), filter, &input, &mut |flags| {
+                        for value in op.apply(&input, &regex, flags.as_ref())? {
+                            out(value)?;
+                        }
+                        Ok(())
+                    })
+                })
+            }
+            FilterFn::Sub {
+                regex,
+                replacement,
+                flags,
+                global,
+            } => regex.run(filter, input.clone(), &mut |regex| {
+                run_flags(flags.as_deref(), filter, &input, &mut |flags| {
+                    let mut matcher = Matcher::new(&regex, flags.as_ref())?;
+                    if *global {
+                        matcher.set_global(true);
+                    }
+                    let found = matcher.find(&input)?;
+                    let text = input.as_str().unwrap_or_default();
+                    run_sub(&found, replacement, text, 0, String::new(), filter, out)
+                })
+            }),
+            FilterFn::Interpolate { format, parts } => {
+                run_interpolate(parts, format, String::new(), filter, &input, out)
+            }
+        }
+    }
+}
+
+// `target[start:end]` with computed bounds, every combination of bounds in turn
+fn run_slice(
+    target: &FilterFn,
+    start: Option<&FilterFn>,
+    end: Option<&FilterFn>,
+    filter: &Filter,
+    input: Value,
+    out: &mut Sink,
+) -> Result<(), FilterError> {
+    let starts = match start {
+        Some(start) => start.apply(filter, vec![input.clone()])?,
+        None => vec![Value::Null],
+    };
+    let ends = match end {
+        Some(end) => end.apply(filter, vec![input.clone()])?,
+        None => vec![Value::Null],
+    };
+    for end in &ends {
+        for start in &starts {
+            let start = slice_bound(start, f64::floor)?.unwrap_or(0);
+            let end = slice_bound(end, f64::ceil)?;
+            target.run(filter, input.clone(), &mut |value| {
+                out(filter.array_slice(value, start, end)?)
+            })?;
+        }
+    }
+    Ok(())
+}
+
+// Every combination of the outputs of the interpolated parts, the first part
+// varying fastest; `suffix` is the text already built from the parts after them
+fn run_interpolate(
+    parts: &[StringPart],
+    format: &str,
+    suffix: String,
+    filter: &Filter,
+    input: &Value,
+    out: &mut Sink,
+) -> Result<(), FilterError> {
+    let Some((last, rest)) = parts.split_last() else {
+        return out(Value::String(suffix));
+    };
+    match last {
+        StringPart::Literal(text) => {
+            run_interpolate(rest, format, text.clone() + &suffix, filter, input, out)
+        }
+        StringPart::Expr(expr) => expr.run(filter, input.clone(), &mut |value| {
+            let text = builtins::format(format, &value)? + &suffix;
+            run_interpolate(rest, format, text, filter, input, out)
+        }),
+    }
+}
+
+// The paths below the input, all of them or those whose value makes `f` true
+fn run_paths_filtered(
+    f: Option<&FilterFn>,
+    filter: &Filter,
+    input: Value,
+    out: &mut Sink,
+) -> Result<(), FilterError> {
+    let all = FilterFn::Recurse {
+        f: Box::new(FilterFn::Try(Box::new(FilterFn::ArrayIterator))),
+        cond: None,
+    };
+    all.run_paths(filter, Vec::new(), input, &mut |path, value| {
+        if path.is_empty() {
+            return Ok(());
+        }
+        let Some(f) = f else {
+            return out(Value::Array(path));
+        };
+        f.run(filter, value, &mut |keep| {
+            if is_truthy(&keep) {
+                out(Value::Array(path.clone()))?;
             }
-            FilterFn::Length => {
-                for value in values {
-                    let result = filter.length(value)?;
-                    results.push(result);
+            Ok(())
+        })
+    })
+}
+
+// Each output of the optional flags argument of a regex built-in
+fn run_flags(
+    flags: Option<&FilterFn>,
+    filter: &Filter,
+    input: &Value,
+    f: &mut dyn FnMut(Option<Value>) -> Result<(), FilterError>,
+) -> Result<(), FilterError> {
+    match flags {
+        Some(flags) => flags.run(filter, input.clone(), &mut |flags| f(Some(flags))),
+        None => f(None),
+    }
+}
+
+// Replace the matches from `pos` on, once for every combination of outputs of
+// `replacement`; `done` is the text built from the earlier matches
+fn run_sub(
+    found: &[Found],
+    replacement: &FilterFn,
+    text: &str,
+    pos: usize,
+    done: String,
+    filter: &Filter,
+    out: &mut Sink,
+) -> Result<(), FilterError> {
+    let Some((first, rest)) = found.split_first() else {
+        return out(Value::String(done + &text[pos..]));
+    };
+    let before = Value::String(done + &text[pos..first.start]);
+    replacement.run(filter, first.captures(), &mut |value| {
+        let Value::String(done) = ops::add(before.clone(), value)? else {
+            unreachable!("adding to a string gives a string");
+        };
+        run_sub(rest, replacement, text, first.end, done, filter, out)
+    })
+}
+
+// `lhs // rhs`: the truthy outputs of `lhs`, or those of `rhs` if there are
+// none; errors on the left count as producing nothing
+fn run_alternative(
+    lhs: &FilterFn,
+    rhs: &FilterFn,
+    filter: &Filter,
+    input: Value,
+    out: &mut Sink,
+) -> Result<(), FilterError> {
+    let mut found = false;
+    let mut downstream_failed = false;
+    let result = lhs.run(filter, input.clone(), &mut |value| {
+        if !is_truthy(&value) {
+            return Ok(());
+        }
+        found = true;
+        out(value).inspect_err(|_| downstream_failed = true)
+    });
+    match result {
+        Err(err) if downstream_failed => Err(err),
+        Err(err @ FilterError::Halt(..)) => Err(err),
+        _ if found => Ok(()),
+        _ => rhs.run(filter, input, out),
+    }
+}
+
+// `reduce source as pattern (init; update)`
+fn run_reduce(
+    source: &FilterFn,
+    pattern: &Pattern,
+    init: &FilterFn,
+    update: &FilterFn,
+    filter: &Filter,
+    input: Value,
+    out: &mut Sink,
+) -> Result<(), FilterError> {
+    init.run(filter, input.clone(), &mut |init| {
+        let mut acc = init;
+        source.run(filter, input.clone(), &mut |value| {
+            filter.destructure(pattern, value, &input, &mut |scope| {
+                // the last output of the update becomes the new state
+                let state = std::mem::take(&mut acc);
+                acc = update.run_last(&scope, state)?.unwrap_or(Value::Null);
+                Ok(())
+            })
+        })?;
+        out(acc)
+    })
+}
+
+// `foreach source as pattern (init; update; extract)`
+#[allow(clippy::too_many_arguments)]
+fn run_foreach(
+    source: &FilterFn,
+    pattern: &Pattern,
+    init: &FilterFn,
+    update: &FilterFn,
+    extract: Option<&FilterFn>,
+    filter: &Filter,
+    input: Value,
+    out: &mut Sink,
+) -> Result<(), FilterError> {
+    init.run(filter, input.clone(), &mut |init| {
+        let mut acc = init;
+        source.run(filter, input.clone(), &mut |value| {
+            filter.destructure(pattern, value, &input, &mut |scope| {
+                let state = std::mem::take(&mut acc);
+                for state in update.apply(&scope, vec![state])? {
+                    acc = state.clone();
+                    match extract {
+                        Some(extract) => extract.run(&scope, state, out)?,
+                        None => out(state)?,
+                    }
                 }
+                Ok(())
+            })
+        })
+    })
+}
+
+// `range(from; upto; by)` for every combination of bounds, `from` varying slowest
+fn run_range(
+    from: Option<&FilterFn>,
+    upto: &FilterFn,
+    by: Option<&FilterFn>,
+    filter: &Filter,
+    input: Value,
+    out: &mut Sink,
+) -> Result<(), FilterError> {
+    let from = match from {
+        Some(from) => from.apply(filter, vec![input.clone()])?,
+        None => vec![Value::from(0)],
+    };
+    let upto = upto.apply(filter, vec![input.clone()])?;
+    let by = match by {
+        Some(by) => by.apply(filter, vec![input])?,
+        None => vec![Value::from(1)],
+    };
+    for from in &from {
+        for upto in &upto {
+            for by in &by {
+                range(from, upto, by, out)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Calls `out` with one output of each argument of a libm function, for
+// every combination; like jq, the last argument varies slowest
+fn math_args(
+    filter: &Filter,
+    args: &[FilterFn],
+    input: &Value,
+    chosen: &mut Vec<Value>,
+    out: &mut dyn FnMut(&[Value]) -> Result<(), FilterError>,
+) -> Result<(), FilterError> {
+    let Some((last, rest)) = args.split_last() else {
+        let numbers: Vec<Value> = chosen.iter().rev().cloned().collect();
+        return out(&numbers);
+    };
+    last.run(filter, input.clone(), &mut |value| {
+        chosen.push(value);
+        let result = math_args(filter, rest, input, chosen, out);
+        chosen.pop();
+        result
+    })
+}
+
+// `while(cond; update)`, emitting every value while `cond` holds, or with
+// `until` set, `until(cond; update)`, emitting only the value where it first holds
+fn run_loop(
+    cond: &FilterFn,
+    update: &FilterFn,
+    until: bool,
+    filter: &Filter,
+    input: Value,
+    out: &mut Sink,
+) -> Result<(), FilterError> {
+    let mut stack = vec![input];
+    while let Some(value) = stack.pop() {
+        let mut next = Vec::new();
+        for c in cond.apply(filter, vec![value.clone()])? {
+            if is_truthy(&c) {
+                out(value.clone())?;
+            }
+            if is_truthy(&c) != until {
+                next.extend(update.apply(filter, vec![value.clone()])?);
             }
-            FilterFn::Del(target) => {
-                for value in values {
-                    let result = filter.del(value, target)?;
-                    results.push(result);
+        }
+        stack.extend(next.into_iter().rev());
+    }
+    Ok(())
+}
+
+// `recurse(f; cond)`, depth first with an explicit stack so that deep documents
+// and long chains like `recurse(. + 1)` do not exhaust the call stack
+fn run_recurse(
+    f: &FilterFn,
+    cond: Option<&FilterFn>,
+    filter: &Filter,
+    input: Value,
+    out: &mut Sink,
+) -> Result<(), FilterError> {
+    let mut stack = vec![input];
+    while let Some(value) = stack.pop() {
+        out(value.clone())?;
+        let mut children = Vec::new();
+        for child in f.apply(filter, vec![value])? {
+            match cond {
+                Some(cond) => {
+                    for c in cond.apply(filter, vec![child.clone()])? {
+                        if is_truthy(&c) {
+                            children.push(child.clone());
+                        }
+                    }
                 }
+                None => children.push(child),
             }
         }
-        Ok(results)
+        stack.extend(children.into_iter().rev());
+    }
+    Ok(())
+}
+
+// Pass on the first `n` outputs of `f`, then stop it without running it further
+fn run_limited(
+    f: &FilterFn,
+    filter: &Filter,
+    input: Value,
+    n: usize,
+    out: &mut Sink,
+) -> Result<(), FilterError> {
+    if n == 0 {
+        return Ok(());
+    }
+    let mut count = 0;
+    let mut stopped = false;
+    let result = f.run(filter, input, &mut |value| {
+        count += 1;
+        out(value)?;
+        if count == n {
+            stopped = true;
+            return Err(FilterError::Break);
+        }
+        Ok(())
+    });
+    match result {
+        // a `Break` raised further down belongs to an enclosing `limit`
+        Err(FilterError::Break) if stopped => Ok(()),
+        other => other,
+    }
+}
+
+// Emit `from`, `from + by`, ... up to but excluding `upto`, one at a time
+fn range(from: &Value, upto: &Value, by: &Value, out: &mut Sink) -> Result<(), FilterError> {
+    let (Value::Number(from), Value::Number(upto), Value::Number(by)) = (from, upto, by) else {
+        return Err(FilterError::RangeNotNumeric);
+    };
+    let upto = upto.as_f64().unwrap_or(0.0);
+    if let (Some(from), Some(by)) = (from.as_i64(), by.as_i64()) {
+        let mut x = from;
+        while (by > 0 && (x as f64) < upto) || (by < 0 && (x as f64) > upto) {
+            out(Value::from(x))?;
+            x = match x.checked_add(by) {
+                Some(x) => x,
+                None => break,
+            };
+        }
+        return Ok(());
+    }
+    let by = by.as_f64().unwrap_or(0.0);
+    let mut x = from.as_f64().unwrap_or(0.0);
+    while (by > 0.0 && x < upto) || (by < 0.0 && x > upto) {
+        out(float_value(x))?;
+        x += by;
+    }
+    Ok(())
+}
+
+// Run `body`, returning the error it raised instead of failing; only errors
+// raised by the body are caught, errors from later stages of the pipeline
+// (including a `limit` stopping early) pass through untouched
+fn run_try(
+    body: &FilterFn,
+    filter: &Filter,
+    input: Value,
+    out: &mut Sink,
+) -> Result<Option<FilterError>, FilterError> {
+    let mut downstream_failed = false;
+    let result = body.run(filter, input, &mut |value| {
+        out(value).inspect_err(|_| downstream_failed = true)
+    });
+    match result {
+        Err(err) if downstream_failed => Err(err),
+        Err(err @ FilterError::Halt(..)) => Err(err),
+        Err(err) => Ok(Some(err)),
+        Ok(()) => Ok(None),
     }
 }
 
@@ -240,8 +1700,25 @@ mod tests {
     fn test_key_filter_nonexistent_key() {
         let filter = Filter::new();
         let input = json!({"key": "value"});
-        let result = filter.key_filter(input, "nonexistent");
-        assert!(matches!(result, Err(FilterError::KeyNotFound(_))));
+        let result = filter.key_filter(input, "nonexistent").unwrap();
+        assert_eq!(result, json!(null));
+    }
+
+    #[test]
+    fn test_key_filter_null_input() {
+        let filter = Filter::new();
+        let result = filter.key_filter(json!(null), "key").unwrap();
+        assert_eq!(result, json!(null));
+    }
+
+    #[test]
+    fn test_key_filter_invalid_type() {
+        let filter = Filter::new();
+        let result = filter.key_filter(json!(5), "key");
+        match result {
+            Err(err) => assert_eq!(err.to_string(), r#"Cannot index number with string "key""#),
+            Ok(_) => panic!("Expected CannotIndex error"),
+        }
     }
 
     #[test]
@@ -255,33 +1732,279 @@ mod tests {
     #[test]
     fn test_array_index_out_of_bounds() {
         let filter = Filter::new();
-        let input = json!(["zero", "one", "two"]);
-        let result = filter.array_index(input, 5);
-        assert!(matches!(result, Err(FilterError::IndexOutOfBounds(_))));
+        let input = json!(["zero", "one", "two"]);
+        let result = filter.array_index(input, 5).unwrap();
+        assert_eq!(result, json!(null));
+    }
+
+    #[test]
+    fn test_array_index_invalid_type() {
+        let filter = Filter::new();
+        let result = filter.array_index(json!({"a": 1}), 0);
+        assert!(matches!(result, Err(FilterError::CannotIndex("object", _))));
+    }
+
+    #[test]
+    fn test_array_slice() {
+        let filter = Filter::new();
+        let input = json!(["zero", "one", "two", "three"]);
+        let result = filter.array_slice(input.clone(), 1, Some(3)).unwrap();
+        assert_eq!(result, json!(["one", "two"]));
+    }
+
+    #[test]
+    fn test_array_slice_no_end() {
+        let filter = Filter::new();
+        let input = json!(["zero", "one", "two", "three"]);
+        let result = filter.array_slice(input.clone(), 2, None).unwrap();
+        assert_eq!(result, json!(["two", "three"]));
+    }
+
+    #[test]
+    fn test_array_slice_out_of_bounds() {
+        let filter = Filter::new();
+        let input = json!(["zero", "one", "two", "three"]);
+        let result = filter.array_slice(input, 3, Some(5)).unwrap();
+        assert_eq!(result, json!(["three"]));
+    }
+
+    #[test]
+    fn test_array_slice_reversed_bounds() {
+        let filter = Filter::new();
+        let input = json!(["zero", "one", "two", "three"]);
+        let result = filter.array_slice(input, 3, Some(1)).unwrap();
+        assert_eq!(result, json!([]));
+    }
+
+    #[test]
+    fn test_array_index_negative() {
+        let filter = Filter::new();
+        let input = json!(["zero", "one", "two"]);
+        let result = filter.array_index(input, -1).unwrap();
+        assert_eq!(result, json!("two"));
+    }
+
+    #[test]
+    fn test_array_slice_negative_start() {
+        let filter = Filter::new();
+        let input = json!(["zero", "one", "two", "three"]);
+        let result = filter.array_slice(input, -2, None).unwrap();
+        assert_eq!(result, json!(["two", "three"]));
+    }
+
+    #[test]
+    fn test_array_slice_string() {
+        let filter = Filter::new();
+        let result = filter.array_slice(json!("héllo"), 1, Some(3)).unwrap();
+        assert_eq!(result, json!("él"));
+    }
+
+    #[test]
+    fn test_slice_bound_not_a_number() {
+        assert_eq!(slice_bound(&json!(null), f64::floor).unwrap(), None);
+        let err = slice_bound(&json!("1"), f64::floor).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Start and end indices of an array slice must be numbers"
+        );
+    }
+
+    #[test]
+    fn test_iterate_object_values() {
+        let filter = Filter::new();
+        let result = filter.iterate(json!({"a": 1, "b": [2]})).unwrap();
+        assert_eq!(result, vec![json!(1), json!([2])]);
+    }
+
+    #[test]
+    fn test_chained_path_access() {
+        let filter = Filter::new();
+        let input = json!({"spec": {"containers": [{"image": "nginx"}, {"image": "redis"}]}});
+        let path = FilterFn::Pipe(
+            Box::new(FilterFn::KeyFilter("spec".to_string())),
+            Box::new(FilterFn::Pipe(
+                Box::new(FilterFn::KeyFilter("containers".to_string())),
+                Box::new(FilterFn::Pipe(
+                    Box::new(FilterFn::ArrayIterator),
+                    Box::new(FilterFn::KeyFilter("image".to_string())),
+                )),
+            )),
+        );
+        let result = path.apply(&filter, vec![input]).unwrap();
+        assert_eq!(result, vec![json!("nginx"), json!("redis")]);
+    }
+
+    #[test]
+    fn test_computed_index_uses_original_input() {
+        let filter = Filter::new();
+        let input = json!({"items": ["a", "b", "c"], "i": 2});
+        let path = FilterFn::Index {
+            target: Box::new(FilterFn::KeyFilter("items".to_string())),
+            index: Box::new(FilterFn::KeyFilter("i".to_string())),
+        };
+        let result = path.apply(&filter, vec![input]).unwrap();
+        assert_eq!(result, vec![json!("c")]);
+    }
+
+    #[test]
+    fn test_iterate_invalid_type() {
+        let filter = Filter::new();
+        let result = filter.iterate(json!(5));
+        match result {
+            Err(err) => assert_eq!(err.to_string(), "Cannot iterate over number (5)"),
+            Ok(_) => panic!("Expected CannotIterate error"),
+        }
+    }
+
+    #[test]
+    fn test_try_suppresses_errors() {
+        let filter = Filter::new();
+        let input = json!([{"a": 1}, 5, {"a": 2}]);
+        let optional = FilterFn::Pipe(
+            Box::new(FilterFn::ArrayIterator),
+            Box::new(FilterFn::Try(Box::new(FilterFn::KeyFilter(
+                "a".to_string(),
+            )))),
+        );
+        let result = optional.apply(&filter, vec![input]).unwrap();
+        assert_eq!(result, vec![json!(1), json!(2)]);
+    }
+
+    #[test]
+    fn test_try_keeps_outputs_before_error() {
+        let filter = Filter::new();
+        let input = json!([[1], 5, [2]]);
+        let optional = FilterFn::Try(Box::new(FilterFn::Pipe(
+            Box::new(FilterFn::ArrayIterator),
+            Box::new(FilterFn::ArrayIterator),
+        )));
+        let result = optional.apply(&filter, vec![input]).unwrap();
+        assert_eq!(result, vec![json!(1)]);
+    }
+
+    #[test]
+    fn test_try_does_not_suppress_later_errors() {
+        let filter = Filter::new();
+        let input = json!({"a": 5});
+        let pipeline = FilterFn::Pipe(
+            Box::new(FilterFn::Try(Box::new(FilterFn::KeyFilter(
+                "a".to_string(),
+            )))),
+            Box::new(FilterFn::ArrayIterator),
+        );
+        let result = pipeline.apply(&filter, vec![input]);
+        assert!(matches!(result, Err(FilterError::CannotIterate(_))));
+    }
+
+    #[test]
+    fn test_comma_forks_stream_in_order() {
+        let filter = Filter::new();
+        let input = json!([{"name": "a", "age": 1}, {"name": "b", "age": 2}]);
+        let fields = FilterFn::Pipe(
+            Box::new(FilterFn::ArrayIterator),
+            Box::new(FilterFn::Comma(
+                Box::new(FilterFn::KeyFilter("name".to_string())),
+                Box::new(FilterFn::KeyFilter("age".to_string())),
+            )),
+        );
+        let result = fields.apply(&filter, vec![input]).unwrap();
+        assert_eq!(result, vec![json!("a"), json!(1), json!("b"), json!(2)]);
+    }
+
+    #[test]
+    fn test_comma_outputs_feed_pipe() {
+        let filter = Filter::new();
+        let input = json!({"a": [1, 2], "b": "xyz"});
+        let lengths = FilterFn::Pipe(
+            Box::new(FilterFn::Comma(
+                Box::new(FilterFn::KeyFilter("a".to_string())),
+                Box::new(FilterFn::KeyFilter("b".to_string())),
+            )),
+            Box::new(FilterFn::Length),
+        );
+        let result = lengths.apply(&filter, vec![input]).unwrap();
+        assert_eq!(result, vec![json!(2), json!(3)]);
+    }
+
+    #[test]
+    fn test_array_construction_collects_outputs() {
+        let filter = Filter::new();
+        let input = json!([{"x": 1}, {"x": 2}]);
+        let collect = FilterFn::ArrayConstruct(Some(Box::new(FilterFn::Pipe(
+            Box::new(FilterFn::ArrayIterator),
+            Box::new(FilterFn::KeyFilter("x".to_string())),
+        ))));
+        let result = collect.apply(&filter, vec![input]).unwrap();
+        assert_eq!(result, vec![json!([1, 2])]);
     }
 
     #[test]
-    fn test_array_slice() {
+    fn test_object_construction() {
         let filter = Filter::new();
-        let input = json!(["zero", "one", "two", "three"]);
-        let result = filter.array_slice(input.clone(), 1, Some(3)).unwrap();
-        assert_eq!(result, json!(["one", "two"]));
+        let input = json!({"name": "a", "meta": {"id": 7}, "k": "key", "v": true});
+        let entries = vec![
+            (
+                FilterFn::Literal(json!("name")),
+                FilterFn::KeyFilter("name".to_string()),
+            ),
+            (
+                FilterFn::Literal(json!("id")),
+                FilterFn::Pipe(
+                    Box::new(FilterFn::KeyFilter("meta".to_string())),
+                    Box::new(FilterFn::KeyFilter("id".to_string())),
+                ),
+            ),
+            (
+                FilterFn::KeyFilter("k".to_string()),
+                FilterFn::KeyFilter("v".to_string()),
+            ),
+        ];
+        let result = FilterFn::ObjectConstruct(entries)
+            .apply(&filter, vec![input])
+            .unwrap();
+        assert_eq!(result, vec![json!({"name": "a", "id": 7, "key": true})]);
     }
 
     #[test]
-    fn test_array_slice_no_end() {
+    fn test_object_construction_cartesian_product() {
         let filter = Filter::new();
-        let input = json!(["zero", "one", "two", "three"]);
-        let result = filter.array_slice(input.clone(), 2, None).unwrap();
-        assert_eq!(result, json!(["two", "three"]));
+        let input = json!({"a": [1, 2], "b": [3, 4]});
+        let entries = vec![
+            (
+                FilterFn::Literal(json!("a")),
+                FilterFn::Pipe(
+                    Box::new(FilterFn::KeyFilter("a".to_string())),
+                    Box::new(FilterFn::ArrayIterator),
+                ),
+            ),
+            (
+                FilterFn::Literal(json!("b")),
+                FilterFn::Pipe(
+                    Box::new(FilterFn::KeyFilter("b".to_string())),
+                    Box::new(FilterFn::ArrayIterator),
+                ),
+            ),
+        ];
+        let result = FilterFn::ObjectConstruct(entries)
+            .apply(&filter, vec![input])
+            .unwrap();
+        assert_eq!(
+            result,
+            vec![
+                json!({"a": 1, "b": 3}),
+                json!({"a": 1, "b": 4}),
+                json!({"a": 2, "b": 3}),
+                json!({"a": 2, "b": 4}),
+            ]
+        );
     }
 
     #[test]
-    fn test_array_slice_out_of_bounds() {
+    fn test_object_construction_rejects_non_string_keys() {
         let filter = Filter::new();
-        let input = json!(["zero", "one", "two", "three"]);
-        let result = filter.array_slice(input, 3, Some(5));
-        assert!(matches!(result, Err(FilterError::SliceOutOfBounds)));
+        let entries = vec![(FilterFn::Literal(json!(1)), FilterFn::Identity)];
+        let result = FilterFn::ObjectConstruct(entries).apply(&filter, vec![json!(null)]);
+        assert!(matches!(result, Err(FilterError::ObjectKeyNotString(_))));
     }
 
     #[test]
@@ -289,7 +2012,7 @@ mod tests {
         let filter = Filter::new();
         let input = json!([1, 2, 3]);
         let result = filter.add(input).unwrap();
-        assert_eq!(result, json!(6.0));
+        assert_eq!(result, json!(6));
     }
 
     #[test]
@@ -297,7 +2020,10 @@ mod tests {
         let filter = Filter::new();
         let input = json!([1.5, 2.5, 3.0]);
         let result = filter.add(input).unwrap();
-        assert_eq!(result, json!(7.0));
+        // an integral sum becomes an integer, so it prints as 7 like jq
+        assert_eq!(result, json!(7));
+        let result = filter.add(json!([1.5, 0.25])).unwrap();
+        assert_eq!(result, json!(1.75));
     }
 
     #[test]
@@ -313,7 +2039,59 @@ mod tests {
         let filter = Filter::new();
         let input = json!([true, false]);
         let result = filter.add(input);
-        assert!(matches!(result, Err(FilterError::InvalidType)));
+        assert!(matches!(
+            result,
+            Err(FilterError::InvalidOperands(_, _, "added"))
+        ));
+    }
+
+    #[test]
+    fn test_add_mixed_containers() {
+        let filter = Filter::new();
+        let input = json!([[1], null, [2, 3]]);
+        let result = filter.add(input).unwrap();
+        assert_eq!(result, json!([1, 2, 3]));
+    }
+
+    #[test]
+    fn test_add_empty_array() {
+        let filter = Filter::new();
+        let result = filter.add(json!([])).unwrap();
+        assert_eq!(result, json!(null));
+    }
+
+    #[test]
+    fn test_binary_operator_output_order() {
+        let filter = Filter::new();
+        let sum = FilterFn::Binary(
+            BinOp::Add,
+            Box::new(FilterFn::Comma(
+                Box::new(FilterFn::Literal(json!(1))),
+                Box::new(FilterFn::Literal(json!(2))),
+            )),
+            Box::new(FilterFn::Comma(
+                Box::new(FilterFn::Literal(json!(10))),
+                Box::new(FilterFn::Literal(json!(20))),
+            )),
+        );
+        let result = sum.apply(&filter, vec![json!(null)]).unwrap();
+        assert_eq!(result, vec![json!(11), json!(12), json!(21), json!(22)]);
+    }
+
+    #[test]
+    fn test_and_or_short_circuit() {
+        let filter = Filter::new();
+        let both = FilterFn::Comma(
+            Box::new(FilterFn::Literal(json!(true))),
+            Box::new(FilterFn::Literal(json!(false))),
+        );
+        let and = FilterFn::And(Box::new(both.clone()), Box::new(both.clone()));
+        let result = and.apply(&filter, vec![json!(null)]).unwrap();
+        assert_eq!(result, vec![json!(true), json!(false), json!(false)]);
+
+        let or = FilterFn::Or(Box::new(both.clone()), Box::new(both));
+        let result = or.apply(&filter, vec![json!(null)]).unwrap();
+        assert_eq!(result, vec![json!(true), json!(true), json!(false)]);
     }
 
     #[test]
@@ -343,9 +2121,22 @@ mod tests {
     #[test]
     fn test_length_invalid_type() {
         let filter = Filter::new();
-        let input = json!(null);
+        let input = json!(true);
         let result = filter.length(input);
-        assert!(matches!(result, Err(FilterError::InvalidType)));
+        assert!(matches!(result, Err(FilterError::NoLength(_))));
+    }
+
+    #[test]
+    fn test_length_number() {
+        let filter = Filter::new();
+        assert_eq!(filter.length(json!(-5)).unwrap(), json!(5));
+        assert_eq!(filter.length(json!(-1.5)).unwrap(), json!(1.5));
+    }
+
+    #[test]
+    fn test_length_null() {
+        let filter = Filter::new();
+        assert_eq!(filter.length(json!(null)).unwrap(), json!(0));
     }
 
     #[test]
@@ -381,16 +2172,864 @@ mod tests {
         let filter = Filter::new();
         let input = json!(["zero", "one", "two"]);
         let target = FilterFn::ArrayIndex(5);
-        let result = filter.del(input, &target);
-        assert!(matches!(result, Err(FilterError::IndexOutOfBounds(_))));
+        let result = filter.del(input.clone(), &target).unwrap();
+        // like a missing key, a missing index leaves the array unchanged
+        assert_eq!(result, input);
     }
 
     #[test]
     fn test_del_invalid_target() {
         let filter = Filter::new();
         let input = json!({"key": "value"});
-        let target = FilterFn::Add; // Invalid target for del
+        let target = FilterFn::Literal(json!(1)); // Invalid target for del
         let result = filter.del(input, &target);
-        assert!(matches!(result, Err(FilterError::InvalidType)));
+        assert!(matches!(result, Err(FilterError::InvalidPath(_))));
+    }
+
+    #[test]
+    fn test_del_nested_and_selected_paths() {
+        let filter = Filter::new();
+        let input = json!({"a": {"b": [1, 2, 3]}, "c": [{"x": true}, {"x": false}]});
+        let nested = FilterFn::Pipe(
+            Box::new(FilterFn::KeyFilter("a".to_string())),
+            Box::new(FilterFn::Pipe(
+                Box::new(FilterFn::KeyFilter("b".to_string())),
+                Box::new(FilterFn::ArrayIndex(0)),
+            )),
+        );
+        let selected = FilterFn::Pipe(
+            Box::new(FilterFn::KeyFilter("c".to_string())),
+            Box::new(FilterFn::Pipe(
+                Box::new(FilterFn::ArrayIterator),
+                Box::new(FilterFn::Select(Box::new(FilterFn::KeyFilter(
+                    "x".to_string(),
+                )))),
+            )),
+        );
+        let target = FilterFn::Comma(Box::new(nested), Box::new(selected));
+        let result = filter.del(input, &target).unwrap();
+        assert_eq!(result, json!({"a": {"b": [2, 3]}, "c": [{"x": false}]}));
+    }
+
+    #[test]
+    fn test_select_filters_stream() {
+        let filter = Filter::new();
+        let select = FilterFn::Pipe(
+            Box::new(FilterFn::ArrayIterator),
+            Box::new(FilterFn::Select(Box::new(FilterFn::KeyFilter(
+                "ok".to_string(),
+            )))),
+        );
+        let input = json!([{"ok": true}, {"ok": false}, {"ok": 1}, {}]);
+        let result = select.apply(&filter, vec![input]).unwrap();
+        assert_eq!(result, vec![json!({"ok": true}), json!({"ok": 1})]);
+    }
+
+    #[test]
+    fn test_map_collects_all_outputs() {
+        let filter = Filter::new();
+        let map = FilterFn::Map(Box::new(FilterFn::Comma(
+            Box::new(FilterFn::Identity),
+            Box::new(FilterFn::Identity),
+        )));
+        let result = map.apply(&filter, vec![json!({"a": 1, "b": 2})]).unwrap();
+        assert_eq!(result, vec![json!([1, 1, 2, 2])]);
+    }
+
+    #[test]
+    fn test_map_values_keeps_first_output() {
+        let filter = Filter::new();
+        let f = FilterFn::Comma(Box::new(FilterFn::Length), Box::new(FilterFn::Identity));
+        let result = filter
+            .map_values(json!({"a": "xyz", "b": [1]}), &f)
+            .unwrap();
+        assert_eq!(result, json!({"a": 3, "b": 1}));
+    }
+
+    #[test]
+    fn test_keys_sorted() {
+        let filter = Filter::new();
+        let result = filter.keys(json!({"b": 1, "a": 2, "c": 3})).unwrap();
+        assert_eq!(result, json!(["a", "b", "c"]));
+        let result = filter.keys(json!(["x", "y"])).unwrap();
+        assert_eq!(result, json!([0, 1]));
+    }
+
+    #[test]
+    fn test_keys_unsorted() {
+        let filter = Filter::new();
+        let result = filter
+            .keys_unsorted(json!({"b": 1, "a": 2, "c": 3}))
+            .unwrap();
+        assert_eq!(result, json!(["b", "a", "c"]));
+        assert!(filter.keys_unsorted(json!(5)).is_err());
+    }
+
+    #[test]
+    fn test_type_and_type_selectors() {
+        let filter = Filter::new();
+        let inputs = vec![
+            json!(null),
+            json!(true),
+            json!(1),
+            json!("s"),
+            json!([]),
+            json!({}),
+        ];
+        let types = FilterFn::Type.apply(&filter, inputs.clone()).unwrap();
+        assert_eq!(
+            types,
+            vec![
+                json!("null"),
+                json!("boolean"),
+                json!("number"),
+                json!("string"),
+                json!("array"),
+                json!("object")
+            ]
+        );
+        let select = |types| {
+            FilterFn::OfTypes(types)
+                .apply(&filter, inputs.clone())
+                .unwrap()
+        };
+        assert_eq!(select(&["number"]), vec![json!(1)]);
+        assert_eq!(select(&["array", "object"]), vec![json!([]), json!({})]);
+    }
+
+    #[test]
+    fn test_keys_invalid_type() {
+        let filter = Filter::new();
+        let result = filter.keys(json!(5));
+        match result {
+            Err(err) => assert_eq!(err.to_string(), "number (5) has no keys"),
+            Ok(_) => panic!("Expected NoKeys error"),
+        }
+    }
+
+    #[test]
+    fn test_has() {
+        let filter = Filter::new();
+        let object = json!({"a": null});
+        assert_eq!(filter.has(&object, &json!("a")).unwrap(), json!(true));
+        assert_eq!(filter.has(&object, &json!("b")).unwrap(), json!(false));
+        let array = json!([1, 2]);
+        assert_eq!(filter.has(&array, &json!(1)).unwrap(), json!(true));
+        assert_eq!(filter.has(&array, &json!(2)).unwrap(), json!(false));
+        assert!(matches!(
+            filter.has(&array, &json!("a")),
+            Err(FilterError::CannotCheckKey("array", "string"))
+        ));
+    }
+
+    #[test]
+    fn test_entries_round_trip() {
+        let filter = Filter::new();
+        let input = json!({"a": 1, "b": [2]});
+        let entries = filter.to_entries(input.clone()).unwrap();
+        assert_eq!(
+            entries,
+            json!([{"key": "a", "value": 1}, {"key": "b", "value": [2]}])
+        );
+        assert_eq!(filter.collect_entries(entries).unwrap(), input);
+    }
+
+    #[test]
+    fn test_to_entries_array() {
+        let filter = Filter::new();
+        let entries = filter.to_entries(json!(["a", [1]])).unwrap();
+        assert_eq!(
+            entries,
+            json!([{"key": 0, "value": "a"}, {"key": 1, "value": [1]}])
+        );
+        assert!(filter.to_entries(json!(1)).is_err());
+    }
+
+    #[test]
+    fn test_from_entries_alternate_names() {
+        let filter = Filter::new();
+        let input = json!([{"k": "a", "v": 1}, {"name": 2, "value": true}, {"key": null}]);
+        let result = filter.collect_entries(input).unwrap();
+        assert_eq!(result, json!({"a": 1, "2": true, "null": null}));
+    }
+
+    #[test]
+    fn test_from_entries_false_key() {
+        let filter = Filter::new();
+        let input = json!([{"key": false, "value": 1}, {"key": null, "k": "b", "value": 2}]);
+        let result = filter.collect_entries(input).unwrap();
+        assert_eq!(result, json!({"false": 1, "b": 2}));
+        // the other spellings of the key fall back on truthiness
+        let input = json!([{"k": false, "name": "n", "value": 1}, {"k": false, "Key": false}]);
+        let result = filter.collect_entries(input).unwrap();
+        assert_eq!(result, json!({"n": 1, "false": null}));
+    }
+
+    #[test]
+    fn test_from_entries_value_names() {
+        let filter = Filter::new();
+        let input = json!([{"key": "a", "Value": 3}, {"key": "b", "value": null, "v": 4}]);
+        let result = filter.collect_entries(input).unwrap();
+        assert_eq!(result, json!({"a": null, "b": null}));
+        let result = filter
+            .collect_entries(json!([{"key": [1], "v": 2}]))
+            .unwrap();
+        assert_eq!(result, json!({"[1]": 2}));
+    }
+
+    #[test]
+    fn test_with_entries() {
+        let filter = Filter::new();
+        let with_entries = FilterFn::WithEntries(Box::new(FilterFn::ObjectConstruct(vec![
+            (
+                FilterFn::Literal(json!("key")),
+                FilterFn::Pipe(
+                    Box::new(FilterFn::KeyFilter("key".to_string())),
+                    Box::new(FilterFn::Length),
+                ),
+            ),
+            (
+                FilterFn::Literal(json!("value")),
+                FilterFn::KeyFilter("key".to_string()),
+            ),
+        ])));
+        let result = with_entries
+            .apply(&filter, vec![json!({"abc": 1})])
+            .unwrap();
+        // lengths are not strings, so they become keys through their JSON text
+        assert_eq!(result, vec![json!({"3": "abc"})]);
+    }
+
+    fn literals(values: &[Value]) -> FilterFn {
+        values
+            .iter()
+            .map(|value| FilterFn::Literal(value.clone()))
+            .reduce(|lhs, rhs| FilterFn::Comma(Box::new(lhs), Box::new(rhs)))
+            .unwrap_or(FilterFn::Empty)
+    }
+
+    #[test]
+    fn test_if_without_else_passes_input() {
+        let filter = Filter::new();
+        let cond = FilterFn::If {
+            cond: Box::new(FilterFn::KeyFilter("ok".to_string())),
+            then_branch: Box::new(FilterFn::Literal(json!("yes"))),
+            else_branch: None,
+        };
+        let result = cond
+            .apply(&filter, vec![json!({"ok": true}), json!({"ok": null})])
+            .unwrap();
+        assert_eq!(result, vec![json!("yes"), json!({"ok": null})]);
+    }
+
+    #[test]
+    fn test_if_runs_once_per_condition_output() {
+        let filter = Filter::new();
+        let cond = FilterFn::If {
+            cond: Box::new(literals(&[json!(true), json!(false), json!(1)])),
+            then_branch: Box::new(FilterFn::Literal(json!("then"))),
+            else_branch: Some(Box::new(FilterFn::Literal(json!("else")))),
+        };
+        let result = cond.apply(&filter, vec![json!(null)]).unwrap();
+        assert_eq!(result, vec![json!("then"), json!("else"), json!("then")]);
+    }
+
+    #[test]
+    fn test_alternative() {
+        let filter = Filter::new();
+        let fallback = Box::new(FilterFn::Literal(json!("default")));
+        let truthy = FilterFn::Alternative(
+            Box::new(literals(&[json!(null), json!(1), json!(false), json!(2)])),
+            fallback.clone(),
+        );
+        let result = truthy.apply(&filter, vec![json!(null)]).unwrap();
+        assert_eq!(result, vec![json!(1), json!(2)]);
+
+        let falsy = FilterFn::Alternative(Box::new(literals(&[json!(false)])), fallback.clone());
+        let result = falsy.apply(&filter, vec![json!(null)]).unwrap();
+        assert_eq!(result, vec![json!("default")]);
+
+        let failing = FilterFn::Alternative(Box::new(FilterFn::ArrayIterator), fallback);
+        let result = failing.apply(&filter, vec![json!(5)]).unwrap();
+        assert_eq!(result, vec![json!("default")]);
+    }
+
+    #[test]
+    fn test_try_catch_receives_error_message() {
+        let filter = Filter::new();
+        let catch = FilterFn::TryCatch(
+            Box::new(FilterFn::ArrayIterator),
+            Box::new(FilterFn::Identity),
+        );
+        let result = catch.apply(&filter, vec![json!(5)]).unwrap();
+        assert_eq!(result, vec![json!("Cannot iterate over number (5)")]);
+    }
+
+    #[test]
+    fn test_try_catch_receives_error_value() {
+        let filter = Filter::new();
+        let catch = FilterFn::TryCatch(
+            Box::new(FilterFn::Error(Some(Box::new(FilterFn::Literal(
+                json!({"code": 42}),
+            ))))),
+            Box::new(FilterFn::KeyFilter("code".to_string())),
+        );
+        let result = catch.apply(&filter, vec![json!(null)]).unwrap();
+        assert_eq!(result, vec![json!(42)]);
+    }
+
+    #[test]
+    fn test_error_message() {
+        let filter = Filter::new();
+        let result = FilterFn::Error(None).apply(&filter, vec![json!("boom")]);
+        match result {
+            Err(err) => assert_eq!(err.to_string(), "boom"),
+            Ok(_) => panic!("Expected Custom error"),
+        }
+        let result = FilterFn::Error(None).apply(&filter, vec![json!({"a": 1})]);
+        match result {
+            Err(err) => assert_eq!(err.to_string(), r#"{"a":1} (not a string)"#),
+            Ok(_) => panic!("Expected Custom error"),
+        }
+    }
+
+    #[test]
+    fn test_halt_is_not_caught() {
+        let filter = Filter::new();
+        let halt = FilterFn::TryCatch(
+            Box::new(FilterFn::HaltError(Some(Box::new(FilterFn::Literal(
+                json!(300),
+            ))))),
+            Box::new(FilterFn::Literal(json!("caught"))),
+        );
+        match halt.apply(&filter, vec![json!("bye")]) {
+            Err(FilterError::Halt(status, message)) => {
+                assert_eq!(status, 44);
+                assert_eq!(message, Some(json!("bye")));
+            }
+            other => panic!("Expected Halt, got {:?}", other),
+        }
+        let alternative = FilterFn::Alternative(
+            Box::new(FilterFn::Halt),
+            Box::new(FilterFn::Literal(json!(1))),
+        );
+        assert!(matches!(
+            alternative.apply(&filter, vec![json!(null)]),
+            Err(FilterError::Halt(0, None))
+        ));
+        let code = FilterFn::HaltError(Some(Box::new(FilterFn::Literal(json!("a")))));
+        assert!(matches!(
+            code.apply(&filter, vec![json!(null)]),
+            Err(FilterError::Requires(..))
+        ));
+    }
+
+    #[test]
+    fn test_bind_keeps_input() {
+        let filter = Filter::new();
+        let bind = FilterFn::Bind {
+            source: Box::new(FilterFn::KeyFilter("name".to_string())),
+            pattern: Pattern::Variable("n".to_string()),
+            body: Box::new(FilterFn::ArrayConstruct(Some(Box::new(FilterFn::Comma(
+                Box::new(FilterFn::Variable("n".to_string())),
+                Box::new(FilterFn::KeyFilter("id".to_string())),
+            ))))),
+        };
+        let result = bind
+            .apply(&filter, vec![json!({"name": "a", "id": 7})])
+            .unwrap();
+        assert_eq!(result, vec![json!(["a", 7])]);
+    }
+
+    #[test]
+    fn test_destructure_nested_patterns() {
+        let filter = Filter::new();
+        let pattern = Pattern::Object(vec![ObjectPattern {
+            key: FilterFn::Literal(json!("b")),
+            variable: Some("b".to_string()),
+            pattern: Some(Pattern::Array(vec![
+                Pattern::Variable("x".to_string()),
+                Pattern::Variable("y".to_string()),
+            ])),
+        }]);
+        let input = json!({"b": [1]});
+        let mut bindings = Vec::new();
+        filter
+            .destructure(&pattern, input.clone(), &input, &mut |scope| {
+                bindings.push([
+                    scope.variable("b")?,
+                    scope.variable("x")?,
+                    scope.variable("y")?,
+                ]);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(bindings, vec![[json!([1]), json!(1), json!(null)]]);
+    }
+
+    #[test]
+    fn test_destructure_type_mismatch() {
+        let filter = Filter::new();
+        let pattern = Pattern::Array(vec![Pattern::Variable("x".to_string())]);
+        let input = json!({"a": 1});
+        let result = filter.destructure(&pattern, input.clone(), &input, &mut |_| Ok(()));
+        assert!(matches!(result, Err(FilterError::CannotIndex("object", _))));
+    }
+
+    #[test]
+    fn test_undefined_variable() {
+        let filter = Filter::new();
+        let result = FilterFn::Variable("x".to_string()).apply(&filter, vec![json!(null)]);
+        match result {
+            Err(err) => assert_eq!(err.to_string(), "$x is not defined"),
+            Ok(_) => panic!("Expected UndefinedVariable error"),
+        }
+    }
+
+    fn range_to(n: i64) -> FilterFn {
+        FilterFn::Range {
+            from: None,
+            upto: Box::new(FilterFn::Literal(json!(n))),
+            by: None,
+        }
+    }
+
+    // `recurse(. + 1)`, an endless stream of increasing numbers
+    fn count_up() -> FilterFn {
+        FilterFn::Recurse {
+            f: Box::new(FilterFn::Binary(
+                BinOp::Add,
+                Box::new(FilterFn::Identity),
+                Box::new(FilterFn::Literal(json!(1))),
+            )),
+            cond: None,
+        }
+    }
+
+    #[test]
+    fn test_reduce_sums() {
+        let filter = Filter::new();
+        let reduce = FilterFn::Reduce {
+            source: Box::new(FilterFn::ArrayIterator),
+            pattern: Pattern::Variable("x".to_string()),
+            init: Box::new(FilterFn::Literal(json!(0))),
+            update: Box::new(FilterFn::Binary(
+                BinOp::Add,
+                Box::new(FilterFn::Identity),
+                Box::new(FilterFn::Variable("x".to_string())),
+            )),
+        };
+        let result = reduce.apply(&filter, vec![json!([1, 2, 3])]).unwrap();
+        assert_eq!(result, vec![json!(6)]);
+    }
+
+    #[test]
+    fn test_foreach_emits_each_state() {
+        let filter = Filter::new();
+        let foreach = FilterFn::Foreach {
+            source: Box::new(range_to(4)),
+            pattern: Pattern::Variable("x".to_string()),
+            init: Box::new(FilterFn::Literal(json!(0))),
+            update: Box::new(FilterFn::Binary(
+                BinOp::Add,
+                Box::new(FilterFn::Identity),
+                Box::new(FilterFn::Variable("x".to_string())),
+            )),
+            extract: Some(Box::new(FilterFn::ArrayConstruct(Some(Box::new(
+                FilterFn::Comma(
+                    Box::new(FilterFn::Variable("x".to_string())),
+                    Box::new(FilterFn::Identity),
+                ),
+            ))))),
+        };
+        let result = foreach.apply(&filter, vec![json!(null)]).unwrap();
+        assert_eq!(
+            result,
+            vec![json!([0, 0]), json!([1, 1]), json!([2, 3]), json!([3, 6])]
+        );
+    }
+
+    #[test]
+    fn test_limit_stops_endless_generator() {
+        let filter = Filter::new();
+        let limit = FilterFn::Limit(Box::new(FilterFn::Literal(json!(3))), Box::new(count_up()));
+        let result = limit.apply(&filter, vec![json!(1)]).unwrap();
+        assert_eq!(result, vec![json!(1), json!(2), json!(3)]);
+    }
+
+    #[test]
+    fn test_nested_limits() {
+        let filter = Filter::new();
+        let inner = FilterFn::Limit(Box::new(FilterFn::Literal(json!(5))), Box::new(count_up()));
+        let outer = FilterFn::Limit(Box::new(FilterFn::Literal(json!(2))), Box::new(inner));
+        let result = outer.apply(&filter, vec![json!(0)]).unwrap();
+        assert_eq!(result, vec![json!(0), json!(1)]);
+    }
+
+    #[test]
+    fn test_first_of_huge_range_is_lazy() {
+        let filter = Filter::new();
+        let first = FilterFn::First(Box::new(FilterFn::Range {
+            from: None,
+            upto: Box::new(FilterFn::Literal(json!(1e9))),
+            by: None,
+        }));
+        let result = first.apply(&filter, vec![json!(null)]).unwrap();
+        assert_eq!(result, vec![json!(0)]);
+    }
+
+    #[test]
+    fn test_last() {
+        let filter = Filter::new();
+        let last = FilterFn::Last(Box::new(range_to(5)));
+        assert_eq!(
+            last.apply(&filter, vec![json!(null)]).unwrap(),
+            vec![json!(4)]
+        );
+        let last = FilterFn::Last(Box::new(FilterFn::Empty));
+        assert!(last.apply(&filter, vec![json!(null)]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_range_with_step() {
+        let filter = Filter::new();
+        let range = FilterFn::Range {
+            from: Some(Box::new(FilterFn::Literal(json!(10)))),
+            upto: Box::new(FilterFn::Literal(json!(0))),
+            by: Some(Box::new(FilterFn::Literal(json!(-3)))),
+        };
+        let result = range.apply(&filter, vec![json!(null)]).unwrap();
+        assert_eq!(result, vec![json!(10), json!(7), json!(4), json!(1)]);
+    }
+
+    #[test]
+    fn test_while_and_until() {
+        let filter = Filter::new();
+        let below_50 = Box::new(FilterFn::Binary(
+            BinOp::Lt,
+            Box::new(FilterFn::Identity),
+            Box::new(FilterFn::Literal(json!(50))),
+        ));
+        let double = Box::new(FilterFn::Binary(
+            BinOp::Mul,
+            Box::new(FilterFn::Identity),
+            Box::new(FilterFn::Literal(json!(2))),
+        ));
+        let while_ = FilterFn::While(below_50.clone(), double.clone());
+        let result = while_.apply(&filter, vec![json!(1)]).unwrap();
+        assert_eq!(
+            result,
+            vec![json!(1), json!(2), json!(4), json!(8), json!(16), json!(32)]
+        );
+        let at_least_50 = Box::new(FilterFn::Pipe(below_50, Box::new(FilterFn::Not)));
+        let until = FilterFn::Until(at_least_50, double);
+        let result = until.apply(&filter, vec![json!(1)]).unwrap();
+        assert_eq!(result, vec![json!(64)]);
+    }
+
+    #[test]
+    fn test_recurse_walks_depth_first() {
+        let filter = Filter::new();
+        let recurse = FilterFn::Recurse {
+            f: Box::new(FilterFn::Try(Box::new(FilterFn::ArrayIterator))),
+            cond: None,
+        };
+        let result = recurse
+            .apply(&filter, vec![json!({"a": [1], "b": 2})])
+            .unwrap();
+        assert_eq!(
+            result,
+            vec![json!({"a": [1], "b": 2}), json!([1]), json!(1), json!(2)]
+        );
+    }
+
+    #[test]
+    fn test_break_is_not_caught_by_try() {
+        let filter = Filter::new();
+        let limit = FilterFn::Limit(
+            Box::new(FilterFn::Literal(json!(1))),
+            Box::new(FilterFn::Try(Box::new(count_up()))),
+        );
+        let result = limit.apply(&filter, vec![json!(0)]).unwrap();
+        assert_eq!(result, vec![json!(0)]);
+    }
+
+    #[test]
+    fn test_interpolation_combines_outputs() {
+        let filter = Filter::new();
+        let interpolate = FilterFn::Interpolate {
+            format: "text".to_string(),
+            parts: vec![
+                StringPart::Expr(literals(&[json!(1), json!(2)])),
+                StringPart::Literal(" ".to_string()),
+                StringPart::Expr(literals(&[json!("a"), json!(null)])),
+            ],
+        };
+        let result = interpolate.apply(&filter, vec![json!(null)]).unwrap();
+        assert_eq!(
+            result,
+            vec![json!("1 a"), json!("2 a"), json!("1 null"), json!("2 null")]
+        );
+    }
+
+    #[test]
+    fn test_builtin_arg_runs_per_argument_output() {
+        let filter = Filter::new();
+        let starts = FilterFn::BuiltinArg(
+            BuiltinArg::Startswith,
+            Box::new(literals(&[json!("ab"), json!("b")])),
+        );
+        let result = starts.apply(&filter, vec![json!("abc")]).unwrap();
+        assert_eq!(result, vec![json!(true), json!(false)]);
+    }
+
+    #[test]
+    fn test_sub_replaces_with_captures() {
+        let filter = Filter::new();
+        let sub = |global| FilterFn::Sub {
+            regex: Box::new(FilterFn::Literal(json!("(?<c>[a-z])\\d"))),
+            replacement: Box::new(FilterFn::Pipe(
+                Box::new(FilterFn::KeyFilter("c".to_string())),
+                Box::new(FilterFn::Builtin(Builtin::AsciiUpcase)),
+            )),
+            flags: None,
+            global,
+        };
+        let result = sub(true).apply(&filter, vec![json!("a1-b2-c")]).unwrap();
+        assert_eq!(result, vec![json!("A-B-c")]);
+        let result = sub(false).apply(&filter, vec![json!("a1-b2-c")]).unwrap();
+        assert_eq!(result, vec![json!("A-b2-c")]);
+    }
+
+    #[test]
+    fn test_sub_once_per_replacement_output() {
+        let filter = Filter::new();
+        let sub = FilterFn::Sub {
+            regex: Box::new(FilterFn::Literal(json!("a"))),
+            replacement: Box::new(literals(&[json!("x"), json!("y")])),
+            flags: Some(Box::new(FilterFn::Literal(json!("g")))),
+            global: false,
+        };
+        let result = sub.apply(&filter, vec![json!("aba")]).unwrap();
+        assert_eq!(
+            result,
+            vec![json!("xbx"), json!("xby"), json!("ybx"), json!("yby")]
+        );
+    }
+
+    fn call(name: &str, args: Vec<FilterFn>) -> FilterFn {
+        FilterFn::Call {
+            name: name.to_string(),
+            args: args.into_iter().map(Rc::new).collect(),
+        }
+    }
+
+    #[test]
+    fn test_recursive_function() {
+        // def count: if . > 0 then ., (. - 1 | count) else empty end; count
+        let filter = Filter::new();
+        let body = FilterFn::If {
+            cond: Box::new(FilterFn::Binary(
+                BinOp::Gt,
+                Box::new(FilterFn::Identity),
+                Box::new(FilterFn::Literal(json!(0))),
+            )),
+            then_branch: Box::new(FilterFn::Comma(
+                Box::new(FilterFn::Identity),
+                Box::new(FilterFn::Pipe(
+                    Box::new(FilterFn::Binary(
+                        BinOp::Sub,
+                        Box::new(FilterFn::Identity),
+                        Box::new(FilterFn::Literal(json!(1))),
+                    )),
+                    Box::new(call("count", vec![])),
+                )),
+            )),
+            else_branch: Some(Box::new(FilterFn::Empty)),
+        };
+        let def = FilterFn::FuncDef {
+            name: "count".to_string(),
+            params: vec![],
+            body: Rc::new(body),
+            rest: Box::new(call("count", vec![])),
+        };
+        let result = def.apply(&filter, vec![json!(3)]).unwrap();
+        assert_eq!(result, vec![json!(3), json!(2), json!(1)]);
+    }
+
+    #[test]
+    fn test_function_closes_over_definition_scope() {
+        // 1 as $x | def f: $x; 2 as $x | f
+        let filter = Filter::new();
+        let program = FilterFn::Bind {
+            source: Box::new(FilterFn::Literal(json!(1))),
+            pattern: Pattern::Variable("x".to_string()),
+            body: Box::new(FilterFn::FuncDef {
+                name: "f".to_string(),
+                params: vec![],
+                body: Rc::new(FilterFn::Variable("x".to_string())),
+                rest: Box::new(FilterFn::Bind {
+                    source: Box::new(FilterFn::Literal(json!(2))),
+                    pattern: Pattern::Variable("x".to_string()),
+                    body: Box::new(call("f", vec![])),
+                }),
+            }),
+        };
+        let result = program.apply(&filter, vec![json!(null)]).unwrap();
+        assert_eq!(result, vec![json!(1)]);
+    }
+
+    #[test]
+    fn test_filter_and_value_params() {
+        // def f(g; $n): [g, $n, n]; f(.a; .b, 10)
+        let filter = Filter::new();
+        let def = FilterFn::FuncDef {
+            name: "f".to_string(),
+            params: vec![
+                Param::Filter("g".to_string()),
+                Param::Value("n".to_string()),
+            ],
+            body: Rc::new(FilterFn::ArrayConstruct(Some(Box::new(FilterFn::Comma(
+                Box::new(call("g", vec![])),
+                Box::new(FilterFn::Comma(
+                    Box::new(FilterFn::Variable("n".to_string())),
+                    Box::new(call("n", vec![])),
+                )),
+            ))))),
+            rest: Box::new(call(
+                "f",
+                vec![
+                    FilterFn::KeyFilter("a".to_string()),
+                    FilterFn::Comma(
+                        Box::new(FilterFn::KeyFilter("b".to_string())),
+                        Box::new(FilterFn::Literal(json!(10))),
+                    ),
+                ],
+            )),
+        };
+        let result = def.apply(&filter, vec![json!({"a": 1, "b": 2})]).unwrap();
+        assert_eq!(result, vec![json!([1, 2, 2]), json!([1, 10, 10])]);
+    }
+
+    #[test]
+    fn test_undefined_function() {
+        let filter = Filter::new();
+        let result = call("f", vec![FilterFn::Identity]).apply(&filter, vec![json!(null)]);
+        match result {
+            Err(err) => assert_eq!(err.to_string(), "f/1 is not defined"),
+            Ok(_) => panic!("Expected UndefinedFunction error"),
+        }
+    }
+
+    #[test]
+    fn test_sort_by_uses_all_key_outputs() {
+        let filter = Filter::new();
+        let key = |name: &str| FilterFn::KeyFilter(name.to_string());
+        let sort_by = FilterFn::By(
+            ByOp::Sort,
+            Box::new(FilterFn::Comma(Box::new(key("a")), Box::new(key("b")))),
+        );
+        let input = json!([{"a": 2, "b": 1}, {"a": 1, "b": 2}, {"a": 2, "b": 0}]);
+        let result = sort_by.apply(&filter, vec![input]).unwrap();
+        assert_eq!(
+            result,
+            vec![json!([{"a": 1, "b": 2}, {"a": 2, "b": 0}, {"a": 2, "b": 1}])]
+        );
+        let err = sort_by.apply(&filter, vec![json!({})]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "object ({}) cannot be sorted, as it is not an array"
+        );
+    }
+
+    #[test]
+    fn test_any_stops_at_first_truthy_output() {
+        let filter = Filter::new();
+        let any = FilterFn::Quantify {
+            all: false,
+            generator: Box::new(count_up()),
+            cond: Box::new(FilterFn::Binary(
+                BinOp::Eq,
+                Box::new(FilterFn::Identity),
+                Box::new(FilterFn::Literal(json!(3))),
+            )),
+        };
+        assert_eq!(
+            any.apply(&filter, vec![json!(0)]).unwrap(),
+            vec![json!(true)]
+        );
+        let all = FilterFn::Quantify {
+            all: true,
+            generator: Box::new(FilterFn::ArrayIterator),
+            cond: Box::new(FilterFn::Identity),
+        };
+        let result = all.apply(&filter, vec![json!([1, true]), json!([1, null]), json!([])]);
+        assert_eq!(
+            result.unwrap(),
+            vec![json!(true), json!(false), json!(true)]
+        );
+    }
+
+    #[test]
+    fn test_add_of_and_combinations() {
+        let filter = Filter::new();
+        let add = FilterFn::AddOf(Box::new(literals(&[json!(1), json!(2), json!(3)])));
+        assert_eq!(
+            add.apply(&filter, vec![json!(null)]).unwrap(),
+            vec![json!(6)]
+        );
+        let combinations = FilterFn::Combinations(None);
+        let result = combinations
+            .apply(&filter, vec![json!([[1, 2], [3, 4]])])
+            .unwrap();
+        assert_eq!(
+            result,
+            vec![json!([1, 3]), json!([1, 4]), json!([2, 3]), json!([2, 4])]
+        );
+        let squared = FilterFn::Combinations(Some(Box::new(FilterFn::Literal(json!(2)))));
+        let result = squared.apply(&filter, vec![json!([0, 1])]).unwrap();
+        assert_eq!(result.len(), 4);
+        let huge = FilterFn::Combinations(Some(Box::new(FilterFn::Literal(json!(1e10)))));
+        assert!(huge.apply(&filter, vec![json!([0, 1])]).is_err());
+        assert!(huge.apply(&filter, vec![json!([])]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_math_arguments_vary_first_fastest() {
+        let filter = Filter::new();
+        let pow = FilterFn::Math(
+            MathFn::Pow,
+            vec![
+                literals(&[json!(2), json!(3)]),
+                literals(&[json!(1), json!(2)]),
+            ],
+        );
+        assert_eq!(
+            pow.apply(&filter, vec![json!(null)]).unwrap(),
+            vec![json!(2), json!(3), json!(4), json!(9)]
+        );
+        let sqrt = FilterFn::Math(MathFn::Sqrt, Vec::new());
+        assert_eq!(
+            sqrt.apply(&filter, vec![json!(2.25)]).unwrap(),
+            vec![json!(1.5)]
+        );
+        assert!(matches!(
+            sqrt.apply(&filter, vec![json!([])]),
+            Err(FilterError::NumberRequired(_))
+        ));
+    }
+
+    #[test]
+    fn test_input_and_inputs_share_the_stream() {
+        let values = vec![Ok(json!(1)), Ok(json!(2)), Ok(json!(3))];
+        let filter = Filter::new().with_inputs(Inputs::new(Box::new(values.into_iter())));
+        let first = FilterFn::Input.apply(&filter, vec![json!(null)]).unwrap();
+        assert_eq!(first, vec![json!(1)]);
+        let rest = FilterFn::Inputs.apply(&filter, vec![json!(null)]).unwrap();
+        assert_eq!(rest, vec![json!(2), json!(3)]);
+        let err = FilterFn::Input
+            .apply(&filter, vec![json!(null)])
+            .unwrap_err();
+        assert_eq!(err.to_string(), "No more inputs");
     }
 }
@@ -1,8 +1,12 @@
 // src/input.rs
+use crate::stream;
 use serde_json::Value;
+use std::cell::RefCell;
+use std::fmt;
 use std::fs::File;
-use std::io::{self, BufReader};
+use std::io::{self, BufRead, BufReader, Read};
 use std::path::Path;
+use std::rc::Rc;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -12,14 +16,93 @@ pub enum InputError {
 
     #[error("JSON deserialization error: {0}")]
     Json(#[from] serde_json::Error),
+
+    #[error("JSON syntax error: {0} at line {1}, column {2}")]
+    Syntax(&'static str, usize, usize),
 }
 
+// The values read from an input, one at a time
+pub type Values = Box<dyn Iterator<Item = Result<Value, InputError>>>;
+
 pub trait InputReader {
-    fn json(self: Box<Self>) -> Result<Value, InputError>;
+    // An input holds any number of JSON values separated by whitespace, so
+    // both concatenated documents and newline-delimited JSON are accepted
+    fn values(self: Box<Self>) -> Values;
+}
+
+// How the content of an input is turned into values
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Json,
+    // `[path, leaf]` events, as `--stream` reads them
+    Stream,
+    // each line as a string, as `--raw-input` reads them
+    Lines,
+    // the whole content as one string, as `--raw-input --slurp` reads it
+    Text,
+}
+
+// The values of `reader` read in the given mode
+pub fn read<R: BufRead + 'static>(reader: R, mode: Mode) -> Values {
+    match mode {
+        Mode::Json => json_values(reader),
+        Mode::Stream => stream::events(reader),
+        Mode::Lines => lines(reader),
+        Mode::Text => text(reader),
+    }
+}
+
+// Parse the JSON values of `reader` lazily, stopping after the first error
+pub fn json_values<R: Read + 'static>(reader: R) -> Values {
+    let stream = serde_json::Deserializer::from_reader(reader).into_iter::<Value>();
+    let mut failed = false;
+    Box::new(stream.map_while(move |value| {
+        if failed {
+            return None;
+        }
+        failed = value.is_err();
+        Some(value.map_err(InputError::from))
+    }))
+}
+
+// Every line of `reader` without its line ending; invalid UTF-8 is replaced
+fn lines<R: BufRead + 'static>(mut reader: R) -> Values {
+    let mut failed = false;
+    Box::new(std::iter::from_fn(move || {
+        if failed {
+            return None;
+        }
+        let mut line = Vec::new();
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) => None,
+            Ok(_) => {
+                if line.last() == Some(&b'\n') {
+                    line.pop();
+                }
+                Some(Ok(Value::String(
+                    String::from_utf8_lossy(&line).into_owned(),
+                )))
+            }
+            Err(err) => {
+                failed = true;
+                Some(Err(InputError::from(err)))
+            }
+        }
+    }))
+}
+
+fn text<R: BufRead + 'static>(mut reader: R) -> Values {
+    let mut content = Vec::new();
+    let text = reader
+        .read_to_end(&mut content)
+        .map(|_| Value::String(String::from_utf8_lossy(&content).into_owned()))
+        .map_err(InputError::from);
+    Box::new(std::iter::once(text))
 }
 
 pub struct FileReader {
     reader: BufReader<File>,
+    mode: Mode,
 }
 
 impl FileReader {
@@ -27,33 +110,94 @@ impl FileReader {
         let file = File::open(path)?;
         Ok(FileReader {
             reader: BufReader::new(file),
+            mode: Mode::Json,
         })
     }
+
+    pub fn with_mode(mut self, mode: Mode) -> FileReader {
+        self.mode = mode;
+        self
+    }
 }
 
 impl InputReader for FileReader {
-    fn json(self: Box<Self>) -> Result<Value, InputError> {
-        let value = serde_json::from_reader(self.reader)?;
-        Ok(value)
+    fn values(self: Box<Self>) -> Values {
+        read(self.reader, self.mode)
     }
 }
 
 pub struct StdinReader {
     reader: BufReader<io::Stdin>,
+    mode: Mode,
 }
 
 impl StdinReader {
     pub fn new() -> StdinReader {
         StdinReader {
             reader: BufReader::new(io::stdin()),
+            mode: Mode::Json,
         }
     }
+
+    pub fn with_mode(mut self, mode: Mode) -> StdinReader {
+        self.mode = mode;
+        self
+    }
 }
 
 impl InputReader for StdinReader {
-    fn json(self: Box<Self>) -> Result<Value, InputError> {
-        let value = serde_json::from_reader(self.reader)?;
-        Ok(value)
+    fn values(self: Box<Self>) -> Values {
+        read(self.reader, self.mode)
+    }
+}
+
+// The values of several inputs one after another
+pub fn chain(readers: Vec<Box<dyn InputReader>>) -> Values {
+    Box::new(readers.into_iter().flat_map(|reader| reader.values()))
+}
+
+// All values collected into a single array, as `--slurp` reads them
+pub fn slurp(values: Values) -> Values {
+    let all = values.collect::<Result<Vec<Value>, InputError>>();
+    Box::new(std::iter::once(all.map(Value::Array)))
+}
+
+// The texts of all inputs joined into one string, as `--raw-input --slurp` reads them
+pub fn concat(values: Values) -> Values {
+    let all = values
+        .map(|value| value.map(|value| value.as_str().unwrap_or_default().to_string()))
+        .collect::<Result<String, InputError>>();
+    Box::new(std::iter::once(all.map(Value::String)))
+}
+
+// The input stream shared by the main loop and the `input` and `inputs`
+// built-ins, which take values the main loop then no longer sees
+#[derive(Clone)]
+pub struct Inputs(Rc<RefCell<Values>>);
+
+impl Inputs {
+    pub fn new(values: Values) -> Inputs {
+        Inputs(Rc::new(RefCell::new(values)))
+    }
+}
+
+impl Default for Inputs {
+    fn default() -> Inputs {
+        Inputs::new(Box::new(std::iter::empty()))
+    }
+}
+
+impl fmt::Debug for Inputs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Inputs")
+    }
+}
+
+impl Iterator for Inputs {
+    type Item = Result<Value, InputError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.borrow_mut().next()
     }
 }
 
@@ -63,20 +207,27 @@ impl InputReader for StdinReader {
 mod tests {
     use super::*;
     use serde_json::json;
-    use std::io::{BufReader, Cursor, Read};
+    use std::io::{BufReader, Cursor};
 
     // Implement a TestFileReader that uses an in-memory buffer
     struct TestFileReader<R: Read> {
         reader: BufReader<R>,
     }
 
-    impl<R: Read> InputReader for TestFileReader<R> {
-        fn json(self: Box<Self>) -> Result<Value, InputError> {
-            let value = serde_json::from_reader(self.reader)?;
-            Ok(value)
+    impl<R: Read + 'static> InputReader for TestFileReader<R> {
+        fn values(self: Box<Self>) -> Values {
+            json_values(self.reader)
         }
     }
 
+    // the only value of an input holding exactly one
+    fn single(reader: Box<dyn InputReader>) -> Result<Value, InputError> {
+        let mut values = reader.values();
+        let value = values.next().expect("input holds a value")?;
+        assert!(values.next().is_none());
+        Ok(value)
+    }
+
     #[test]
     fn test_file_reader_with_valid_json() {
         // Simulate file content using Cursor
@@ -88,7 +239,7 @@ mod tests {
             reader: BufReader::new(cursor),
         };
 
-        let value = Box::new(test_reader).json().expect("Failed to read JSON");
+        let value = single(Box::new(test_reader)).expect("Failed to read JSON");
 
         // Verify the JSON content
         let expected = json!({"key": "value", "number": 42});
@@ -105,7 +256,7 @@ mod tests {
             reader: BufReader::new(cursor),
         };
 
-        let result = Box::new(test_reader).json();
+        let result = single(Box::new(test_reader));
 
         // Verify that an error is returned
         assert!(result.is_err());
@@ -126,7 +277,7 @@ mod tests {
             reader: BufReader::new(cursor),
         };
 
-        let result = Box::new(test_reader).json();
+        let result = single(Box::new(test_reader));
 
         // Verify that an error is returned
         assert!(result.is_err());
@@ -148,9 +299,8 @@ mod tests {
         }
 
         impl<R: Read + 'static> InputReader for TestStdinReader<R> {
-            fn json(self: Box<Self>) -> Result<Value, InputError> {
-                let value = serde_json::from_reader(self.reader)?;
-                Ok(value)
+            fn values(self: Box<Self>) -> Values {
+                json_values(self.reader)
             }
         }
 
@@ -158,7 +308,7 @@ mod tests {
             reader: BufReader::new(cursor),
         };
 
-        let value = Box::new(test_reader).json().expect("Failed to read JSON");
+        let value = single(Box::new(test_reader)).expect("Failed to read JSON");
 
         // Verify the JSON content
         let expected = json!({"key": "value", "number": 42});
@@ -176,9 +326,8 @@ mod tests {
         }
 
         impl<R: Read + 'static> InputReader for TestStdinReader<R> {
-            fn json(self: Box<Self>) -> Result<Value, InputError> {
-                let value = serde_json::from_reader(self.reader)?;
-                Ok(value)
+            fn values(self: Box<Self>) -> Values {
+                json_values(self.reader)
             }
         }
 
@@ -186,7 +335,7 @@ mod tests {
             reader: BufReader::new(cursor),
         };
 
-        let result = Box::new(test_reader).json();
+        let result = single(Box::new(test_reader));
 
         // Verify that an error is returned
         assert!(result.is_err());
@@ -196,4 +345,72 @@ mod tests {
             panic!("Expected Json error");
         }
     }
+
+    #[test]
+    fn test_concatenated_and_newline_delimited_values() {
+        let content = "{\"a\": 1}{\"a\": 2}\n3\n\n\"x\" [4]";
+        let values: Vec<Value> = json_values(Cursor::new(content))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            values,
+            vec![
+                json!({"a": 1}),
+                json!({"a": 2}),
+                json!(3),
+                json!("x"),
+                json!([4])
+            ]
+        );
+        assert_eq!(json_values(Cursor::new(" \n")).count(), 0);
+    }
+
+    #[test]
+    fn test_values_before_an_error_are_kept() {
+        let mut values = json_values(Cursor::new("1 2 {"));
+        assert_eq!(values.next().unwrap().unwrap(), json!(1));
+        assert_eq!(values.next().unwrap().unwrap(), json!(2));
+        assert!(matches!(values.next(), Some(Err(InputError::Json(_)))));
+        assert!(values.next().is_none());
+    }
+
+    #[test]
+    fn test_slurp_and_chain() {
+        let readers: Vec<Box<dyn InputReader>> = vec![
+            Box::new(TestFileReader {
+                reader: BufReader::new(Cursor::new("1 2")),
+            }),
+            Box::new(TestFileReader {
+                reader: BufReader::new(Cursor::new("3")),
+            }),
+        ];
+        let mut slurped = slurp(chain(readers));
+        assert_eq!(slurped.next().unwrap().unwrap(), json!([1, 2, 3]));
+        assert!(slurped.next().is_none());
+    }
+
+    #[test]
+    fn test_inputs_are_shared() {
+        let mut inputs = Inputs::new(json_values(Cursor::new("1 2 3")));
+        let mut other = inputs.clone();
+        assert_eq!(inputs.next().unwrap().unwrap(), json!(1));
+        assert_eq!(other.next().unwrap().unwrap(), json!(2));
+        assert_eq!(inputs.next().unwrap().unwrap(), json!(3));
+        assert!(other.next().is_none());
+    }
+
+    #[test]
+    fn test_raw_lines_and_text() {
+        let lines: Vec<Value> = read(Cursor::new("a\nb\r\n\nc"), Mode::Lines)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(lines, vec![json!("a"), json!("b\r"), json!(""), json!("c")]);
+        assert_eq!(read(Cursor::new(""), Mode::Lines).count(), 0);
+
+        let texts = vec![Ok(json!("a\n")), Ok(json!("b"))];
+        let mut joined = concat(Box::new(texts.into_iter()));
+        assert_eq!(joined.next().unwrap().unwrap(), json!("a\nb"));
+        let mut text = read(Cursor::new(""), Mode::Text);
+        assert_eq!(text.next().unwrap().unwrap(), json!(""));
+    }
 }
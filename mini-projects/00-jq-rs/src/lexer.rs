@@ -0,0 +1,649 @@
+use crate::parse::ParseError;
+use serde_json::Number;
+use std::fmt;
+
+// A single lexical unit of a filter string
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Dot,
+    DotDot,
+    Field(String),
+    Ident(String),
+    Variable(String),
+    Format(String),
+    Number(Number),
+    Str(String),
+    Template(Vec<TemplatePart>),
+    Pipe,
+    Comma,
+    Colon,
+    Semicolon,
+    Question,
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    LBrace,
+    RBrace,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Percent,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Assign,
+    UpdateAssign,
+    PlusAssign,
+    MinusAssign,
+    StarAssign,
+    SlashAssign,
+    PercentAssign,
+    Alternative,
+    AlternativeAssign,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            Token::Dot => ".",
+            Token::DotDot => "..",
+            Token::Field(name) => return write!(f, ".{}", name),
+            Token::Ident(name) => name,
+            Token::Variable(name) => return write!(f, "${}", name),
+            Token::Format(name) => return write!(f, "@{}", name),
+            Token::Number(num) => return write!(f, "{}", num),
+            Token::Str(s) => return write!(f, "{:?}", s),
+            Token::Template(parts) => {
+                write!(f, "\"")?;
+                for part in parts {
+                    match part {
+                        TemplatePart::Literal(s) => write!(f, "{}", s.escape_debug())?,
+                        TemplatePart::Expr(_) => write!(f, "\\(...)")?,
+                    }
+                }
+                return write!(f, "\"");
+            }
+            Token::Pipe => "|",
+            Token::Comma => ",",
+            Token::Colon => ":",
+            Token::Semicolon => ";",
+            Token::Question => "?",
+            Token::LParen => "(",
+            Token::RParen => ")",
+            Token::LBracket => "[",
+            Token::RBracket => "]",
+            Token::LBrace => "{",
+            Token::RBrace => "}",
+            Token::Plus => "+",
+            Token::Minus => "-",
+            Token::Star => "*",
+            Token::Slash => "/",
+            Token::Percent => "%",
+            Token::Eq => "==",
+            Token::Ne => "!=",
+            Token::Lt => "<",
+            Token::Le => "<=",
+            Token::Gt => ">",
+            Token::Ge => ">=",
+            Token::Assign => "=",
+            Token::UpdateAssign => "|=",
+            Token::PlusAssign => "+=",
+            Token::MinusAssign => "-=",
+            Token::StarAssign => "*=",
+            Token::SlashAssign => "/=",
+            Token::PercentAssign => "%=",
+            Token::Alternative => "//",
+            Token::AlternativeAssign => "//=",
+        };
+        f.write_str(text)
+    }
+}
+
+// A piece of a string literal containing `\(...)` interpolations
+#[derive(Debug, Clone, PartialEq)]
+pub enum TemplatePart {
+    Literal(String),
+    Expr(Vec<Spanned>),
+}
+
+// A token together with the byte offset where it starts in the filter string
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spanned {
+    pub token: Token,
+    pub offset: usize,
+}
+
+pub struct Lexer<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(input: &'a str) -> Lexer<'a> {
+        Lexer { input, pos: 0 }
+    }
+
+    // Split the whole input into tokens
+    pub fn tokenize(mut self) -> Result<Vec<Spanned>, ParseError> {
+        self.lex_tokens(false)
+    }
+
+    // Lex tokens up to the end of input or, inside an interpolation, up to and
+    // including the `)` that closes it
+    fn lex_tokens(&mut self, interpolation: bool) -> Result<Vec<Spanned>, ParseError> {
+        let mut tokens = Vec::new();
+        let mut depth = 0;
+        loop {
+            self.skip_whitespace_and_comments();
+            let offset = self.pos;
+            let c = match self.peek() {
+                Some(c) => c,
+                None => return Ok(tokens),
+            };
+            match c {
+                '(' => depth += 1,
+                ')' if interpolation && depth == 0 => {
+                    self.bump();
+                    return Ok(tokens);
+                }
+                ')' => depth -= 1,
+                _ => {}
+            }
+            let token = match c {
+                '.' => self.lex_dot(),
+                '"' => self.lex_string()?,
+                '$' => {
+                    self.bump();
+                    match self.lex_ident() {
+                        Some(name) => Token::Variable(name),
+                        None => {
+                            return Err(ParseError::InvalidCharacter {
+                                offset,
+                                character: '$',
+                            })
+                        }
+                    }
+                }
+                '@' => {
+                    self.bump();
+                    match self.lex_ident() {
+                        Some(name) => Token::Format(name),
+                        None => {
+                            return Err(ParseError::InvalidCharacter {
+                                offset,
+                                character: '@',
+                            })
+                        }
+                    }
+                }
+                c if c.is_ascii_digit() => self.lex_number(),
+                c if c.is_ascii_alphabetic() || c == '_' => {
+                    Token::Ident(self.lex_ident().unwrap_or_default())
+                }
+                _ => self.lex_punct(offset)?,
+            };
+            tokens.push(Spanned { token, offset });
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.input[self.pos..].chars().next()
+    }
+
+    fn peek_second(&self) -> Option<char> {
+        let mut chars = self.input[self.pos..].chars();
+        chars.next();
+        chars.next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    // Consume the next character only if it matches `expected`
+    fn eat(&mut self, expected: char) -> bool {
+        if self.peek() == Some(expected) {
+            self.bump();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn skip_whitespace_and_comments(&mut self) {
+        while let Some(c) = self.peek() {
+            if c.is_whitespace() {
+                self.bump();
+            } else if c == '#' {
+                while let Some(c) = self.bump() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            } else {
+                break;
+            }
+        }
+    }
+
+    // Identifiers may contain `::` to refer to module members, e.g. `lib::f`
+    fn lex_ident(&mut self) -> Option<String> {
+        let start = self.pos;
+        match self.peek() {
+            Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+            _ => return None,
+        }
+        while let Some(c) = self.peek() {
+            if c.is_ascii_alphanumeric() || c == '_' {
+                self.bump();
+            } else if c == ':'
+                && self.peek_second() == Some(':')
+                && self.input[self.pos + 2..]
+                    .chars()
+                    .next()
+                    .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            {
+                self.pos += 2;
+            } else {
+                break;
+            }
+        }
+        Some(self.input[start..self.pos].to_string())
+    }
+
+    fn lex_dot(&mut self) -> Token {
+        self.bump();
+        match self.peek() {
+            Some('.') => {
+                self.bump();
+                Token::DotDot
+            }
+            Some(c) if c.is_ascii_digit() => {
+                // A leading dot followed by digits is a number such as `.5`
+                self.pos -= 1;
+                self.lex_number()
+            }
+            Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+                let start = self.pos;
+                while let Some(c) = self.peek() {
+                    if c.is_ascii_alphanumeric() || c == '_' {
+                        self.bump();
+                    } else {
+                        break;
+                    }
+                }
+                Token::Field(self.input[start..self.pos].to_string())
+            }
+            _ => Token::Dot,
+        }
+    }
+
+    fn lex_number(&mut self) -> Token {
+        let start = self.pos;
+        let mut is_float = false;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.bump();
+        }
+        if self.peek() == Some('.') {
+            is_float = true;
+            self.bump();
+            while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+                self.bump();
+            }
+        }
+        if matches!(self.peek(), Some('e') | Some('E')) {
+            let mark = self.pos;
+            self.bump();
+            if matches!(self.peek(), Some('+') | Some('-')) {
+                self.bump();
+            }
+            if self.peek().is_some_and(|c| c.is_ascii_digit()) {
+                is_float = true;
+                while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+                    self.bump();
+                }
+            } else {
+                // Not an exponent after all, leave the `e` for the next token
+                self.pos = mark;
+            }
+        }
+        let text = &self.input[start..self.pos];
+        if !is_float {
+            if let Ok(i) = text.parse::<i64>() {
+                return Token::Number(Number::from(i));
+            }
+        }
+        // Literals too large for a double saturate like they do in jq
+        let f = text.parse::<f64>().unwrap_or(f64::MAX).min(f64::MAX);
+        Token::Number(Number::from_f64(f).unwrap_or_else(|| Number::from(0)))
+    }
+
+    // A string literal with JSON escapes; `\(expr)` interpolations make it a template
+    fn lex_string(&mut self) -> Result<Token, ParseError> {
+        let start = self.pos;
+        self.bump();
+        let mut parts = Vec::new();
+        let mut s = String::new();
+        loop {
+            match self.bump() {
+                None => return Err(ParseError::UnterminatedString { offset: start }),
+                Some('"') => break,
+                Some('\\') => {
+                    let escape_offset = self.pos - 1;
+                    match self.bump() {
+                        Some('"') => s.push('"'),
+                        Some('\\') => s.push('\\'),
+                        Some('/') => s.push('/'),
+                        Some('n') => s.push('\n'),
+                        Some('t') => s.push('\t'),
+                        Some('r') => s.push('\r'),
+                        Some('b') => s.push('\u{8}'),
+                        Some('f') => s.push('\u{c}'),
+                        Some('u') => s.push(self.lex_unicode_escape(escape_offset)?),
+                        Some('(') => {
+                            let tokens = self.lex_tokens(true)?;
+                            if self.input[..self.pos].ends_with(')') {
+                                parts.push(TemplatePart::Literal(std::mem::take(&mut s)));
+                                parts.push(TemplatePart::Expr(tokens));
+                            } else {
+                                return Err(ParseError::UnterminatedString { offset: start });
+                            }
+                        }
+                        Some(c) => {
+                            return Err(ParseError::InvalidEscape {
+                                offset: escape_offset,
+                                character: c,
+                            })
+                        }
+                        None => return Err(ParseError::UnterminatedString { offset: start }),
+                    }
+                }
+                Some(c) => s.push(c),
+            }
+        }
+        if parts.is_empty() {
+            return Ok(Token::Str(s));
+        }
+        parts.push(TemplatePart::Literal(s));
+        parts.retain(|part| part != &TemplatePart::Literal(String::new()));
+        Ok(Token::Template(parts))
+    }
+
+    // The character of a `\uXXXX` escape after the `u`; a surrogate pair takes
+    // two escapes and unpaired surrogates become U+FFFD
+    fn lex_unicode_escape(&mut self, escape_offset: usize) -> Result<char, ParseError> {
+        let high = self.lex_hex4(escape_offset)?;
+        if !(0xD800..0xDC00).contains(&high) {
+            return Ok(char::from_u32(high).unwrap_or(char::REPLACEMENT_CHARACTER));
+        }
+        if self.input[self.pos..].starts_with("\\u") {
+            let rest = self.pos;
+            self.pos += 2;
+            let low = self.lex_hex4(rest)?;
+            if (0xDC00..0xE000).contains(&low) {
+                let c = 0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00);
+                return Ok(char::from_u32(c).unwrap_or(char::REPLACEMENT_CHARACTER));
+            }
+            // not a low surrogate: leave the second escape to be read on its own
+            self.pos = rest;
+        }
+        Ok(char::REPLACEMENT_CHARACTER)
+    }
+
+    fn lex_hex4(&mut self, escape_offset: usize) -> Result<u32, ParseError> {
+        let digits = self.input[self.pos..].get(..4).unwrap_or("");
+        match u32::from_str_radix(digits, 16) {
+            Ok(code) if digits.len() == 4 && digits.chars().all(|c| c.is_ascii_hexdigit()) => {
+                self.pos += 4;
+                Ok(code)
+            }
+            _ => Err(ParseError::InvalidEscape {
+                offset: escape_offset,
+                character: 'u',
+            }),
+        }
+    }
+
+    fn lex_punct(&mut self, offset: usize) -> Result<Token, ParseError> {
+        let c = self.bump().unwrap_or_default();
+        let token = match c {
+            '|' if self.eat('=') => Token::UpdateAssign,
+            '|' => Token::Pipe,
+            ',' => Token::Comma,
+            ':' => Token::Colon,
+            ';' => Token::Semicolon,
+            '?' => Token::Question,
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            '[' => Token::LBracket,
+            ']' => Token::RBracket,
+            '{' => Token::LBrace,
+            '}' => Token::RBrace,
+            '+' if self.eat('=') => Token::PlusAssign,
+            '+' => Token::Plus,
+            '-' if self.eat('=') => Token::MinusAssign,
+            '-' => Token::Minus,
+            '*' if self.eat('=') => Token::StarAssign,
+            '*' => Token::Star,
+            '/' if self.eat('/') => {
+                if self.eat('=') {
+                    Token::AlternativeAssign
+                } else {
+                    Token::Alternative
+                }
+            }
+            '/' if self.eat('=') => Token::SlashAssign,
+            '/' => Token::Slash,
+            '%' if self.eat('=') => Token::PercentAssign,
+            '%' => Token::Percent,
+            '=' if self.eat('=') => Token::Eq,
+            '=' => Token::Assign,
+            '!' if self.eat('=') => Token::Ne,
+            '<' if self.eat('=') => Token::Le,
+            '<' => Token::Lt,
+            '>' if self.eat('=') => Token::Ge,
+            '>' => Token::Gt,
+            _ => {
+                return Err(ParseError::InvalidCharacter {
+                    offset,
+                    character: c,
+                })
+            }
+        };
+        Ok(token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(input: &str) -> Vec<Token> {
+        Lexer::new(input)
+            .tokenize()
+            .unwrap()
+            .into_iter()
+            .map(|spanned| spanned.token)
+            .collect()
+    }
+
+    #[test]
+    fn test_lex_identity_and_fields() {
+        assert_eq!(
+            tokens(". .foo .bar_2"),
+            vec![
+                Token::Dot,
+                Token::Field("foo".to_string()),
+                Token::Field("bar_2".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lex_offsets() {
+        let spanned = Lexer::new(".a | .[0]").tokenize().unwrap();
+        let offsets: Vec<usize> = spanned.iter().map(|s| s.offset).collect();
+        assert_eq!(offsets, vec![0, 3, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn test_lex_numbers() {
+        assert_eq!(
+            tokens("12 1.5 .5 2e3"),
+            vec![
+                Token::Number(Number::from(12)),
+                Token::Number(Number::from_f64(1.5).unwrap()),
+                Token::Number(Number::from_f64(0.5).unwrap()),
+                Token::Number(Number::from_f64(2000.0).unwrap()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lex_string_keeps_pipes() {
+        assert_eq!(
+            tokens(r#""a|b" | ."#),
+            vec![Token::Str("a|b".to_string()), Token::Pipe, Token::Dot]
+        );
+    }
+
+    #[test]
+    fn test_lex_string_escapes() {
+        assert_eq!(
+            tokens(r#""say \"hi\"\n""#),
+            vec![Token::Str("say \"hi\"\n".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_lex_unicode_escapes() {
+        assert_eq!(
+            tokens(r#""\u00e9\ud83d\ude00\b\f\/""#),
+            vec![Token::Str("é😀\u{8}\u{c}/".to_string())]
+        );
+        // an unpaired surrogate cannot be represented and becomes U+FFFD
+        assert_eq!(
+            tokens(r#""\ud800x""#),
+            vec![Token::Str("\u{fffd}x".to_string())]
+        );
+        let result = Lexer::new(r#""\u12g4""#).tokenize();
+        assert!(matches!(
+            result,
+            Err(ParseError::InvalidEscape {
+                offset: 1,
+                character: 'u'
+            })
+        ));
+    }
+
+    #[test]
+    fn test_lex_interpolation() {
+        let lexed = Lexer::new(r#""a\(.x | f(1))b\("\(1)")""#)
+            .tokenize()
+            .unwrap();
+        let Token::Template(parts) = &lexed[0].token else {
+            panic!("Expected a template");
+        };
+        let part_tokens = |tokens: &[Spanned]| -> Vec<Token> {
+            tokens.iter().map(|spanned| spanned.token.clone()).collect()
+        };
+        assert_eq!(parts.len(), 4);
+        assert_eq!(parts[0], TemplatePart::Literal("a".to_string()));
+        match &parts[1] {
+            TemplatePart::Expr(tokens) => {
+                assert_eq!(
+                    part_tokens(tokens),
+                    vec![
+                        Token::Field("x".to_string()),
+                        Token::Pipe,
+                        Token::Ident("f".to_string()),
+                        Token::LParen,
+                        Token::Number(Number::from(1)),
+                        Token::RParen,
+                    ]
+                );
+                // offsets stay relative to the whole filter
+                assert_eq!(tokens[0].offset, 4);
+            }
+            other => panic!("Expected an interpolation, got {:?}", other),
+        }
+        assert_eq!(parts[2], TemplatePart::Literal("b".to_string()));
+        assert!(matches!(&parts[3], TemplatePart::Expr(tokens)
+            if matches!(tokens[0].token, Token::Template(_))));
+        assert_eq!(lexed.len(), 1);
+    }
+
+    #[test]
+    fn test_lex_unterminated_interpolation() {
+        let result = Lexer::new(r#""a\(1 + (2)"#).tokenize();
+        assert!(matches!(
+            result,
+            Err(ParseError::UnterminatedString { offset: 0 })
+        ));
+    }
+
+    #[test]
+    fn test_lex_unterminated_string() {
+        let result = Lexer::new(r#". | "abc"#).tokenize();
+        assert!(matches!(
+            result,
+            Err(ParseError::UnterminatedString { offset: 4 })
+        ));
+    }
+
+    #[test]
+    fn test_lex_operators() {
+        assert_eq!(
+            tokens("// //= |= == != <= >= .."),
+            vec![
+                Token::Alternative,
+                Token::AlternativeAssign,
+                Token::UpdateAssign,
+                Token::Eq,
+                Token::Ne,
+                Token::Le,
+                Token::Ge,
+                Token::DotDot,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lex_variables_formats_and_modules() {
+        assert_eq!(
+            tokens("$x @base64 lib::f"),
+            vec![
+                Token::Variable("x".to_string()),
+                Token::Format("base64".to_string()),
+                Token::Ident("lib::f".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lex_comments() {
+        assert_eq!(
+            tokens(". # the rest is ignored | .a\n| length"),
+            vec![Token::Dot, Token::Pipe, Token::Ident("length".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_lex_invalid_character() {
+        let result = Lexer::new(".a & .b").tokenize();
+        assert!(matches!(
+            result,
+            Err(ParseError::InvalidCharacter {
+                offset: 3,
+                character: '&'
+            })
+        ));
+    }
+}
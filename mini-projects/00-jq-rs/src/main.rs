@@ -1,65 +1,223 @@
-use anyhow::Result;
-use clap::Parser;
-use std::io::{self, Write};
-use std::path::PathBuf;
+use anyhow::{anyhow, Context, Result};
+use clap::{ArgMatches, CommandFactory, FromArgMatches};
+use serde_json::{Map, Value};
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::{iter, panic, thread};
 
+mod builtins;
+mod date;
+mod env;
 mod filter;
 mod input;
+mod lexer;
+mod matcher;
+mod math;
+mod ops;
 mod output;
 mod parse;
+mod path;
+mod stream;
 
-use filter::Filter;
-use input::InputReader;
-use output::{write_output, OutputOptions};
+use filter::{Filter, FilterError, FilterFn};
+use input::{InputError, InputReader, Inputs, Mode};
+use output::{write_result, OutputOptions};
 use parse::Parse;
 
-#[derive(Parser, Debug)]
+#[derive(clap::Parser, Debug)]
 struct Args {
-    #[clap(short = 'c', long)]
+    #[clap(short = 'c', long, conflicts_with = "indent")]
     compact_output: bool,
 
     #[clap(short = 'S', long)]
     sort_keys: bool,
 
-    #[clap(short = 'C', long)]
+    #[clap(short = 'C', long, conflicts_with = "monochrome_output")]
     color_output: bool,
 
     #[clap(short = 'M', long)]
     monochrome_output: bool,
 
-    #[clap(long)]
+    // spaces per level, from 0 to 7
+    #[clap(long, value_parser = clap::value_parser!(u8).range(0..=7))]
     indent: Option<u8>,
 
+    // indent with tabs instead of spaces
+    #[clap(long)]
+    tab: bool,
+
+    // write strings without quotes
+    #[clap(short = 'r', long)]
+    raw_output: bool,
+
+    // like `-r`, without a newline after each result
+    #[clap(short = 'j', long)]
+    join_output: bool,
+
+    // like `-r`, with a NUL byte after each result
+    #[clap(long = "raw-output0")]
+    raw_output0: bool,
+
+    // escape non-ASCII characters
+    #[clap(short = 'a', long)]
+    ascii_output: bool,
+
+    // read each line of the inputs as a string instead of parsing JSON
+    #[clap(short = 'R', long)]
+    raw_input: bool,
+
+    // read all inputs into one array and run the filter on it once
+    #[clap(short = 's', long)]
+    slurp: bool,
+
+    // run the filter once on `null`; it can still read the inputs with `input`
+    #[clap(short = 'n', long)]
+    null_input: bool,
+
+    // exit with 1 if the last output is false or null, and 4 if there is none
+    #[clap(short = 'e', long)]
+    exit_status: bool,
+
+    // read the inputs as `[path, leaf]` events instead of whole values
+    #[clap(long)]
+    stream: bool,
+
+    // `$NAME` is the string VALUE
+    #[clap(long = "arg", num_args = 2, value_names = ["NAME", "VALUE"])]
+    arg: Vec<String>,
+
+    // `$NAME` is the JSON value TEXT
+    #[clap(long = "argjson", num_args = 2, value_names = ["NAME", "TEXT"])]
+    argjson: Vec<String>,
+
+    // `$NAME` is an array of the JSON values in FILE
+    #[clap(long = "slurpfile", num_args = 2, value_names = ["NAME", "FILE"])]
+    slurpfile: Vec<String>,
+
+    // `$NAME` is the content of FILE as a string
+    #[clap(long = "rawfile", num_args = 2, value_names = ["NAME", "FILE"])]
+    rawfile: Vec<String>,
+
+    // the arguments after this flag are strings in `$ARGS.positional`, not files
+    #[clap(long = "args")]
+    string_args: bool,
+
+    // the arguments after this flag are JSON values in `$ARGS.positional`
+    #[clap(long = "jsonargs")]
+    json_args: bool,
+
+    // directories searched for modules loaded with `import` and `include`
+    #[clap(short = 'L', long = "library-path")]
+    library_path: Vec<PathBuf>,
+
+    // filters such as `-1` or `-.a` start with a hyphen but are not flags
+    #[clap(allow_hyphen_values = true)]
     filter: String,
 
-    file: Option<PathBuf>,
+    files: Vec<PathBuf>,
 }
 
-fn main() -> Result<()> {
-    let args = Args::parse();
+// Recursive filters nest deeply; give them more room than the main thread has
+const STACK_SIZE: usize = 256 * 1024 * 1024;
 
-    // Create a boxed input reader depending on whether a file is provided
-    let reader: Box<dyn InputReader> = if let Some(file) = &args.file {
-        Box::new(input::FileReader::new(file)?)
-    } else {
-        Box::new(input::StdinReader::new())
-    };
+// Exit statuses, as jq documents them
+const EXIT_FALSY: u8 = 1;
+const EXIT_USAGE: u8 = 2;
+const EXIT_COMPILE: u8 = 3;
+const EXIT_NO_OUTPUT: u8 = 4;
+const EXIT_RUNTIME: u8 = 5;
+
+fn main() -> ExitCode {
+    let result = thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(run)
+        .map_err(anyhow::Error::from)
+        .and_then(|worker| {
+            worker
+                .join()
+                .unwrap_or_else(|payload| panic::resume_unwind(payload))
+        });
+    // anything `run` does not handle itself is a usage or system error
+    result.unwrap_or_else(|err| {
+        eprintln!("Error: {:#}", err);
+        ExitCode::from(EXIT_USAGE)
+    })
+}
 
-    // Read the JSON input
-    let json = reader.json()?;
+fn run() -> Result<ExitCode> {
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+
+    // Variables given on the command line, also collected in `$ARGS`, which
+    // is also bound as `$__prog_args` with its `named` part as `$named`
+    let named = named_arguments(&args)?;
+    let positional = positional_arguments(&matches, &mut args.files)?;
+    let mut variables: Vec<String> = named.keys().cloned().collect();
+    variables.extend(["ARGS", "__prog_args", "named"].map(String::from));
 
     // Create a Parse instance
-    let parser = Parse::new();
+    let parser = Parse::new()
+        .with_library_paths(args.library_path.clone())
+        .with_variables(variables);
+
+    // Parse the filter string into an expression tree
+    let filter_fn = match parser.parse(&args.filter) {
+        Ok(filter_fn) => filter_fn,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            return Ok(ExitCode::from(EXIT_COMPILE));
+        }
+    };
 
-    // Parse the filter string into filters
-    let filters = parser.parse(&args.filter)?;
+    // Raw input takes precedence over `--stream`, as in jq
+    let mode = match (args.raw_input, args.slurp, args.stream) {
+        (true, true, _) => Mode::Text,
+        (true, false, _) => Mode::Lines,
+        (false, _, true) => Mode::Stream,
+        (false, _, false) => Mode::Json,
+    };
 
-    // Apply the filters to the JSON
-    let filter_instance = Filter::new();
-    let mut current_values = vec![json];
-    for filter_fn in &filters {
-        current_values = filter_fn.apply(&filter_instance, current_values)?;
+    // Read the files one after another, or stdin if none are given. Files
+    // that cannot be opened are skipped, failing the run once the rest is done.
+    let mut unreadable = false;
+    let readers: Vec<Box<dyn InputReader>> = if args.files.is_empty() {
+        vec![Box::new(input::StdinReader::new().with_mode(mode))]
+    } else {
+        let mut readers: Vec<Box<dyn InputReader>> = Vec::new();
+        for file in &args.files {
+            match input::FileReader::new(file) {
+                Ok(reader) => readers.push(Box::new(reader.with_mode(mode))),
+                Err(err) => {
+                    eprintln!("Error: Could not open {}: {}", file.display(), err);
+                    unreadable = true;
+                }
+            }
+        }
+        readers
+    };
+    let mut values = input::chain(readers);
+    if args.slurp {
+        values = match mode {
+            Mode::Text => input::concat(values),
+            _ => input::slurp(values),
+        };
+    }
+    let inputs = Inputs::new(values);
+    let mut program_args = Map::new();
+    program_args.insert("positional".to_string(), Value::Array(positional));
+    program_args.insert("named".to_string(), Value::Object(named.clone()));
+    let program_args = Value::Object(program_args);
+    // a variable given on the command line shadows the aliases of the same name
+    let mut filter_instance = Filter::new()
+        .with_inputs(inputs.clone())
+        .bind("__prog_args", program_args.clone())
+        .bind("named", Value::Object(named.clone()));
+    for (name, value) in &named {
+        filter_instance = filter_instance.bind(name, value.clone());
     }
+    let filter_instance = filter_instance.bind("ARGS", program_args);
 
     // Set up output options
     let output_options = OutputOptions {
@@ -68,18 +226,144 @@ fn main() -> Result<()> {
         monochrome_output: args.monochrome_output,
         sort_keys: args.sort_keys,
         indent: args.indent.unwrap_or(2) as usize,
+        tab: args.tab,
+        raw_output: args.raw_output,
+        join_output: args.join_output,
+        raw_output0: args.raw_output0,
+        ascii_output: args.ascii_output,
     };
 
-    println!("{}", args.sort_keys);
+    // Apply the filter to each input, writing results as they are produced.
+    // An error stops the current input only; `halt` stops everything.
+    let mut stdout_writer = BufWriter::new(io::stdout().lock());
+    let mut failed = false;
+    let mut last_truthy = None;
+    let values: Box<dyn Iterator<Item = Result<Value, InputError>>> = if args.null_input {
+        Box::new(iter::once(Ok(Value::Null)))
+    } else {
+        Box::new(inputs)
+    };
+    for value in values {
+        let value = match value {
+            Ok(value) => value,
+            Err(err) => {
+                stdout_writer.flush()?;
+                eprintln!("Error: {}", err);
+                return Ok(ExitCode::from(EXIT_USAGE));
+            }
+        };
+        let result = process(
+            &filter_fn,
+            &filter_instance,
+            value,
+            &output_options,
+            &mut stdout_writer,
+            &mut last_truthy,
+        );
+        match result {
+            Ok(()) => {}
+            Err(FilterError::Halt(status, message)) => {
+                stdout_writer.flush()?;
+                // strings are written as they are, anything else as JSON
+                match message {
+                    Some(Value::String(text)) => eprint!("{}", text),
+                    Some(value) => eprintln!("{}", output::to_json(&value)),
+                    None => {}
+                }
+                return Ok(ExitCode::from(status));
+            }
+            Err(err) => {
+                stdout_writer.flush()?;
+                eprintln!("Error: {}", err);
+                failed = true;
+            }
+        }
+    }
+    stdout_writer.flush()?;
+    let status = match last_truthy {
+        _ if unreadable => EXIT_USAGE,
+        _ if failed => EXIT_RUNTIME,
+        _ if !args.exit_status => 0,
+        None => EXIT_NO_OUTPUT,
+        Some(false) => EXIT_FALSY,
+        Some(true) => 0,
+    };
+    Ok(ExitCode::from(status))
+}
 
-    // Output the results
-    let mut stdout_writer = io::stdout();
+// The values of `--arg`, `--argjson`, `--slurpfile` and `--rawfile` by name
+fn named_arguments(args: &Args) -> Result<Map<String, Value>> {
+    let mut named = Map::new();
+    for pair in args.arg.chunks(2) {
+        named.insert(pair[0].clone(), Value::String(pair[1].clone()));
+    }
+    for pair in args.argjson.chunks(2) {
+        let value = serde_json::from_str(&pair[1])
+            .map_err(|_| anyhow!("invalid JSON text passed to --argjson"))?;
+        named.insert(pair[0].clone(), value);
+    }
+    for pair in args.slurpfile.chunks(2) {
+        let bad = |err| anyhow!("Bad JSON in --slurpfile {} {}: {}", pair[0], pair[1], err);
+        let reader = input::FileReader::new(Path::new(&pair[1])).map_err(bad)?;
+        let values = Box::new(reader)
+            .values()
+            .collect::<Result<Vec<Value>, _>>()
+            .map_err(bad)?;
+        named.insert(pair[0].clone(), Value::Array(values));
+    }
+    for pair in args.rawfile.chunks(2) {
+        let text = fs::read_to_string(&pair[1])
+            .with_context(|| format!("Bad file in --rawfile {} {}", pair[0], pair[1]))?;
+        named.insert(pair[0].clone(), Value::String(text));
+    }
+    Ok(named)
+}
 
-    for value in current_values {
-        write_output(&mut stdout_writer, &value, &output_options)?;
-        if !output_options.compact {
-            writeln!(stdout_writer)?;
+// Takes the arguments that come after `--args` or `--jsonargs` out of `files`.
+// Each one is a string or a JSON value depending on which of the two flags
+// came last before it; the arguments before both flags stay input files.
+fn positional_arguments(matches: &ArgMatches, files: &mut Vec<PathBuf>) -> Result<Vec<Value>> {
+    let flag_indices = |id: &str| -> Vec<usize> {
+        matches
+            .indices_of(id)
+            .map(|indices| indices.collect())
+            .unwrap_or_default()
+    };
+    let string_flags = flag_indices("string_args");
+    let json_flags = flag_indices("json_args");
+    let file_indices = flag_indices("files");
+    let mut positional = Vec::new();
+    let mut remaining = Vec::new();
+    for (file, index) in std::mem::take(files).into_iter().zip(file_indices) {
+        let last_before = |flags: &[usize]| flags.iter().copied().filter(|&f| f < index).max();
+        match (last_before(&string_flags), last_before(&json_flags)) {
+            (None, None) => remaining.push(file),
+            (string, json) if string > json => {
+                positional.push(Value::String(file.to_string_lossy().into_owned()))
+            }
+            _ => positional.push(
+                serde_json::from_str(&file.to_string_lossy())
+                    .map_err(|_| anyhow!("Invalid JSON text passed to --jsonargs"))?,
+            ),
         }
     }
-    Ok(())
+    *files = remaining;
+    Ok(positional)
+}
+
+// Run the filter on one input and write each output as soon as it is
+// produced, noting whether the last one is truthy for `--exit-status`
+fn process<W: Write>(
+    filter_fn: &FilterFn,
+    filter: &Filter,
+    input: Value,
+    options: &OutputOptions,
+    writer: &mut W,
+    last_truthy: &mut Option<bool>,
+) -> Result<(), FilterError> {
+    filter_fn.run(filter, input, &mut |value| {
+        write_result(writer, &value, options)?;
+        *last_truthy = Some(ops::is_truthy(&value));
+        Ok(())
+    })
 }
@@ -0,0 +1,373 @@
+use crate::filter::{describe, FilterError};
+use regex::{Regex, RegexBuilder};
+use serde_json::{json, Map, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+// compiled regexes are reused across inputs; filters rarely build more than a
+// handful, so the cache is only cleared if a program generates patterns freely
+const REGEX_CACHE_LIMIT: usize = 256;
+
+thread_local! {
+    static REGEX_CACHE: RefCell<HashMap<(String, String), Regex>> = RefCell::new(HashMap::new());
+}
+
+// The regex built-ins other than `sub` and `gsub`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RegexOp {
+    Test,
+    Match,
+    Capture,
+    Scan,
+    Split,
+    Splits,
+}
+
+// A regex compiled with jq's modifier flags
+pub struct Matcher {
+    regex: Regex,
+    global: bool,
+    skip_empty: bool,
+}
+
+// One match: its byte range in the input and the jq match object describing it
+pub struct Found {
+    pub start: usize,
+    pub end: usize,
+    pub object: Value,
+}
+
+impl RegexOp {
+    pub fn from_name(name: &str) -> Option<RegexOp> {
+        let op = match name {
+            "test" => RegexOp::Test,
+            "match" => RegexOp::Match,
+            "capture" => RegexOp::Capture,
+            "scan" => RegexOp::Scan,
+            "split" => RegexOp::Split,
+            "splits" => RegexOp::Splits,
+            _ => return None,
+        };
+        Some(op)
+    }
+
+    // The outputs of the built-in on `input` for one regex and flags value
+    pub fn apply(
+        self,
+        input: &Value,
+        regex: &Value,
+        flags: Option<&Value>,
+    ) -> Result<Vec<Value>, FilterError> {
+        let mut matcher = Matcher::new(regex, flags)?;
+        if matches!(self, RegexOp::Scan | RegexOp::Split | RegexOp::Splits) {
+            matcher.set_global(true);
+        }
+        let found = matcher.find(input)?;
+        let outputs = match self {
+            RegexOp::Test => vec![Value::Bool(!found.is_empty())],
+            RegexOp::Match => found.into_iter().map(|found| found.object).collect(),
+            RegexOp::Capture => found.iter().map(|found| found.captures()).collect(),
+            RegexOp::Scan => found.iter().map(|found| found.scanned()).collect(),
+            RegexOp::Split => vec![Value::Array(split(input, &found))],
+            RegexOp::Splits => split(input, &found),
+        };
+        Ok(outputs)
+    }
+}
+
+impl Matcher {
+    // `regex` is either a pattern or, without `flags`, a `[pattern, flags]` array
+    pub fn new(regex: &Value, flags: Option<&Value>) -> Result<Matcher, FilterError> {
+        let (pattern, flags) = match (regex, flags) {
+            (Value::Array(items), None) if !items.is_empty() => (&items[0], items.get(1)),
+            _ => (regex, flags),
+        };
+        let Value::String(pattern) = pattern else {
+            return Err(FilterError::InvalidRegex(
+                describe(pattern),
+                "not a string".to_string(),
+            ));
+        };
+        let flags = match flags {
+            None | Some(Value::Null) => "",
+            Some(Value::String(flags)) => flags.as_str(),
+            Some(other) => return Err(FilterError::InvalidRegexFlags(other.to_string())),
+        };
+        let mut matcher = Matcher {
+            regex: compile(pattern, flags)?,
+            global: false,
+            skip_empty: false,
+        };
+        for flag in flags.chars() {
+            match flag {
+                'g' => matcher.global = true,
+                'n' => matcher.skip_empty = true,
+                _ => {}
+            }
+        }
+        Ok(matcher)
+    }
+
+    pub fn set_global(&mut self, global: bool) {
+        self.global = global;
+    }
+
+    // Every match in `input`, or only the first one unless the regex is global
+    pub fn find(&self, input: &Value) -> Result<Vec<Found>, FilterError> {
+        let Value::String(text) = input else {
+            return Err(FilterError::NotMatchable(describe(input)));
+        };
+        let names: Vec<Option<&str>> = self.regex.capture_names().skip(1).collect();
+        let mut found = Vec::new();
+        for captures in self.regex.captures_iter(text) {
+            let whole = captures.get(0).expect("group 0 is the whole match");
+            if self.skip_empty && whole.is_empty() {
+                continue;
+            }
+            let groups = names
+                .iter()
+                .enumerate()
+                .map(|(i, name)| {
+                    let mut group = match captures.get(i + 1) {
+                        Some(group) => span_object(text, group.start(), group.as_str()),
+                        None => json!({"offset": -1, "length": 0, "string": null}),
+                    };
+                    group["name"] = json!(name);
+                    group
+                })
+                .collect();
+            let mut object = span_object(text, whole.start(), whole.as_str());
+            object["captures"] = Value::Array(groups);
+            found.push(Found {
+                start: whole.start(),
+                end: whole.end(),
+                object,
+            });
+            if !self.global {
+                break;
+            }
+        }
+        Ok(found)
+    }
+}
+
+impl Found {
+    // The named groups as an object, unmatched groups being null
+    pub fn captures(&self) -> Value {
+        let mut map = Map::new();
+        for group in self.groups() {
+            if let Value::String(name) = &group["name"] {
+                map.insert(name.clone(), group["string"].clone());
+            }
+        }
+        Value::Object(map)
+    }
+
+    // What `scan` emits: the matched text, or the text of each group if there are any
+    pub fn scanned(&self) -> Value {
+        let groups = self.groups();
+        if groups.is_empty() {
+            return self.object["string"].clone();
+        }
+        Value::Array(groups.iter().map(|group| group["string"].clone()).collect())
+    }
+
+    fn groups(&self) -> &[Value] {
+        match &self.object["captures"] {
+            Value::Array(groups) => groups,
+            _ => &[],
+        }
+    }
+}
+
+// Compile a pattern with the flags that change how it matches
+fn compile(pattern: &str, flags: &str) -> Result<Regex, FilterError> {
+    let key = (pattern.to_string(), flags.to_string());
+    if let Some(regex) = REGEX_CACHE.with(|cache| cache.borrow().get(&key).cloned()) {
+        return Ok(regex);
+    }
+    let mut builder = RegexBuilder::new(pattern);
+    for flag in flags.chars() {
+        match flag {
+            'i' => builder.case_insensitive(true),
+            'x' => builder.ignore_whitespace(true),
+            // single line mode: `^` and `$` anchor to the whole text, which
+            // they already do unless the pattern turns on `(?m)`
+            's' => builder.multi_line(false),
+            // single line mode, with `.` also matching newlines
+            'p' => builder.multi_line(false).dot_matches_new_line(true),
+            'g' | 'n' => &mut builder,
+            // `l` asks for the longest match, which the leftmost-first regex
+            // engine cannot give, so it is refused rather than ignored
+            _ => return Err(FilterError::InvalidRegexFlags(flags.to_string())),
+        };
+    }
+    let regex = builder.build().map_err(|err| {
+        let message = err.to_string();
+        let reason = message.lines().last().unwrap_or_default();
+        FilterError::InvalidRegex(
+            pattern.to_string(),
+            reason.trim_start_matches("error: ").to_string(),
+        )
+    })?;
+    REGEX_CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        if cache.len() >= REGEX_CACHE_LIMIT {
+            cache.clear();
+        }
+        cache.insert(key, regex.clone());
+    });
+    Ok(regex)
+}
+
+// Offset and length of a span counted in codepoints, as jq reports them
+fn span_object(text: &str, start: usize, matched: &str) -> Value {
+    json!({
+        "offset": text[..start].chars().count(),
+        "length": matched.chars().count(),
+        "string": matched,
+    })
+}
+
+// The pieces of `input` between the matches
+fn split(input: &Value, found: &[Found]) -> Vec<Value> {
+    let text = input.as_str().unwrap_or_default();
+    let mut pieces = Vec::new();
+    let mut pos = 0;
+    for found in found {
+        pieces.push(Value::String(text[pos..found.start].to_string()));
+        pos = found.end;
+    }
+    pieces.push(Value::String(text[pos..].to_string()));
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(op: RegexOp, input: &str, regex: &str, flags: Option<&str>) -> Vec<Value> {
+        let flags = flags.map(|flags| json!(flags));
+        op.apply(&json!(input), &json!(regex), flags.as_ref())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_test_with_flags() {
+        assert_eq!(run(RegexOp::Test, "abc", "B", None), vec![json!(false)]);
+        assert_eq!(run(RegexOp::Test, "abc", "B", Some("i")), vec![json!(true)]);
+        assert_eq!(
+            run(RegexOp::Test, "a b", "a b", Some("x")),
+            vec![json!(false)]
+        );
+        assert_eq!(run(RegexOp::Test, "a\nb", "a.b", None), vec![json!(false)]);
+        assert_eq!(
+            run(RegexOp::Test, "a\nb", "a.b", Some("s")),
+            vec![json!(false)]
+        );
+        assert_eq!(
+            run(RegexOp::Test, "a\nb", "a.b", Some("p")),
+            vec![json!(true)]
+        );
+        assert_eq!(
+            run(RegexOp::Test, "a\nb", "^b", Some("s")),
+            vec![json!(false)]
+        );
+    }
+
+    #[test]
+    fn test_regex_and_flags_array() {
+        let result = RegexOp::Test.apply(&json!("abc"), &json!(["B", "i"]), None);
+        assert_eq!(result.unwrap(), vec![json!(true)]);
+    }
+
+    #[test]
+    fn test_match_objects() {
+        assert_eq!(
+            run(RegexOp::Match, "foo bar foo", "foo", Some("g")),
+            vec![
+                json!({"offset": 0, "length": 3, "string": "foo", "captures": []}),
+                json!({"offset": 8, "length": 3, "string": "foo", "captures": []}),
+            ]
+        );
+        assert_eq!(
+            run(RegexOp::Match, "éoo", "(?<x>o+)|(z)", None),
+            vec![json!({
+                "offset": 1,
+                "length": 2,
+                "string": "oo",
+                "captures": [
+                    {"offset": 1, "length": 2, "string": "oo", "name": "x"},
+                    {"offset": -1, "length": 0, "string": null, "name": null},
+                ],
+            })]
+        );
+    }
+
+    #[test]
+    fn test_skip_empty_matches() {
+        assert_eq!(run(RegexOp::Match, "abc", "", Some("g")).len(), 4);
+        assert_eq!(run(RegexOp::Match, "abc", "b*", Some("gn")).len(), 1);
+    }
+
+    #[test]
+    fn test_capture() {
+        assert_eq!(
+            run(
+                RegexOp::Capture,
+                "xyz-123",
+                r"(?<a>[a-z]+)-(?<n>\d+)(?<rest>!)?",
+                None
+            ),
+            vec![json!({"a": "xyz", "n": "123", "rest": null})]
+        );
+    }
+
+    #[test]
+    fn test_scan() {
+        assert_eq!(
+            run(RegexOp::Scan, "a1b22", r"\d+", None),
+            vec![json!("1"), json!("22")]
+        );
+        assert_eq!(
+            run(RegexOp::Scan, "a1b22", r"([a-z])(\d)", None),
+            vec![json!(["a", "1"]), json!(["b", "2"])]
+        );
+    }
+
+    #[test]
+    fn test_split_and_splits() {
+        assert_eq!(
+            run(RegexOp::Split, "a, b,c", ", *", None),
+            vec![json!(["a", "b", "c"])]
+        );
+        assert_eq!(
+            run(RegexOp::Splits, "aXbxc", "x", Some("i")),
+            vec![json!("a"), json!("b"), json!("c")]
+        );
+    }
+
+    #[test]
+    fn test_regex_errors() {
+        let err = RegexOp::Test
+            .apply(&json!(1), &json!("a"), None)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "number (1) cannot be matched, as it is not a string"
+        );
+        let err = RegexOp::Test
+            .apply(&json!("a"), &json!("a"), Some(&json!("q")))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "q is not a valid modifier string");
+        let err = RegexOp::Match
+            .apply(&json!("abcd"), &json!("a|ab"), Some(&json!("l")))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "l is not a valid modifier string");
+        let err = RegexOp::Test
+            .apply(&json!("a"), &json!("("), None)
+            .unwrap_err();
+        assert!(err.to_string().starts_with("( is not a valid regex"));
+        assert!(RegexOp::Test.apply(&json!("a"), &json!(1), None).is_err());
+    }
+}
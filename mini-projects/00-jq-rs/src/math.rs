@@ -0,0 +1,469 @@
+use crate::filter::{describe, FilterError};
+use crate::ops::float_value;
+use serde_json::{Number, Value};
+use std::f64::consts::PI;
+
+// jq's libm built-ins. One-argument functions apply to their input, e.g.
+// `2|sqrt`; the others take their numbers as arguments, e.g. `pow(2; 10)`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MathFn {
+    // rounding, kept exact on integers
+    Floor,
+    Ceil,
+    Round,
+    Trunc,
+    Rint,
+    Fabs,
+    // one argument
+    Sqrt,
+    Cbrt,
+    Exp,
+    Exp2,
+    Exp10,
+    Expm1,
+    Log,
+    Log2,
+    Log10,
+    Log1p,
+    Logb,
+    Significand,
+    Sin,
+    Cos,
+    Tan,
+    Asin,
+    Acos,
+    Atan,
+    Sinh,
+    Cosh,
+    Tanh,
+    Asinh,
+    Acosh,
+    Atanh,
+    Lgamma,
+    Tgamma,
+    LgammaR,
+    Frexp,
+    Modf,
+    IsInfinite,
+    IsNan,
+    IsNormal,
+    // two arguments
+    Pow,
+    Atan2,
+    Fmod,
+    Hypot,
+    Fmin,
+    Fmax,
+    Fdim,
+    Copysign,
+    Ldexp,
+    Nextafter,
+    Drem,
+    // three arguments
+    Fma,
+}
+
+impl MathFn {
+    pub fn from_name(name: &str, arity: usize) -> Option<MathFn> {
+        let op = match (name, arity) {
+            ("floor", 0) => MathFn::Floor,
+            ("ceil", 0) => MathFn::Ceil,
+            ("round", 0) => MathFn::Round,
+            ("trunc", 0) => MathFn::Trunc,
+            ("rint" | "nearbyint", 0) => MathFn::Rint,
+            ("fabs", 0) => MathFn::Fabs,
+            ("sqrt", 0) => MathFn::Sqrt,
+            ("cbrt", 0) => MathFn::Cbrt,
+            ("exp", 0) => MathFn::Exp,
+            ("exp2", 0) => MathFn::Exp2,
+            ("exp10" | "pow10", 0) => MathFn::Exp10,
+            ("expm1", 0) => MathFn::Expm1,
+            ("log", 0) => MathFn::Log,
+            ("log2", 0) => MathFn::Log2,
+            ("log10", 0) => MathFn::Log10,
+            ("log1p", 0) => MathFn::Log1p,
+            ("logb", 0) => MathFn::Logb,
+            ("significand", 0) => MathFn::Significand,
+            ("sin", 0) => MathFn::Sin,
+            ("cos", 0) => MathFn::Cos,
+            ("tan", 0) => MathFn::Tan,
+            ("asin", 0) => MathFn::Asin,
+            ("acos", 0) => MathFn::Acos,
+            ("atan", 0) => MathFn::Atan,
+            ("sinh", 0) => MathFn::Sinh,
+            ("cosh", 0) => MathFn::Cosh,
+            ("tanh", 0) => MathFn::Tanh,
+            ("asinh", 0) => MathFn::Asinh,
+            ("acosh", 0) => MathFn::Acosh,
+            ("atanh", 0) => MathFn::Atanh,
+            ("gamma" | "lgamma", 0) => MathFn::Lgamma,
+            ("tgamma", 0) => MathFn::Tgamma,
+            ("lgamma_r", 0) => MathFn::LgammaR,
+            ("frexp", 0) => MathFn::Frexp,
+            ("modf", 0) => MathFn::Modf,
+            ("isinfinite", 0) => MathFn::IsInfinite,
+            ("isnan", 0) => MathFn::IsNan,
+            ("isnormal", 0) => MathFn::IsNormal,
+            ("pow", 2) => MathFn::Pow,
+            ("atan2", 2) => MathFn::Atan2,
+            ("fmod", 2) => MathFn::Fmod,
+            ("hypot", 2) => MathFn::Hypot,
+            ("fmin", 2) => MathFn::Fmin,
+            ("fmax", 2) => MathFn::Fmax,
+            ("fdim", 2) => MathFn::Fdim,
+            ("copysign", 2) => MathFn::Copysign,
+            ("ldexp" | "scalb" | "scalbln", 2) => MathFn::Ldexp,
+            ("nextafter" | "nexttoward", 2) => MathFn::Nextafter,
+            ("drem", 2) => MathFn::Drem,
+            ("fma", 3) => MathFn::Fma,
+            _ => return None,
+        };
+        Some(op)
+    }
+
+    // Applies the function to its numbers: the input for one-argument
+    // functions, otherwise one output of each argument
+    pub fn apply(&self, args: &[Value]) -> Result<Value, FilterError> {
+        if let [value] = args {
+            if let Some(result) = self.exact(value) {
+                return Ok(result);
+            }
+            // the classifications answer false for null, as in jq, rather
+            // than asking for a number; every other function does
+            if let (MathFn::IsNan | MathFn::IsInfinite | MathFn::IsNormal, Value::Null) =
+                (self, value)
+            {
+                return Ok(Value::Bool(false));
+            }
+        }
+        let x = args
+            .iter()
+            .map(number)
+            .collect::<Result<Vec<f64>, FilterError>>()?;
+        let result = match (self, x.as_slice()) {
+            (MathFn::Floor, &[x]) => x.floor(),
+            (MathFn::Ceil, &[x]) => x.ceil(),
+            (MathFn::Round, &[x]) => x.round(),
+            (MathFn::Trunc, &[x]) => x.trunc(),
+            (MathFn::Rint, &[x]) => x.round_ties_even(),
+            (MathFn::Fabs, &[x]) => x.abs(),
+            (MathFn::Sqrt, &[x]) => x.sqrt(),
+            (MathFn::Cbrt, &[x]) => x.cbrt(),
+            (MathFn::Exp, &[x]) => x.exp(),
+            (MathFn::Exp2, &[x]) => x.exp2(),
+            (MathFn::Exp10, &[x]) => 10f64.powf(x),
+            (MathFn::Expm1, &[x]) => x.exp_m1(),
+            (MathFn::Log, &[x]) => x.ln(),
+            (MathFn::Log2, &[x]) => x.log2(),
+            (MathFn::Log10, &[x]) => x.log10(),
+            (MathFn::Log1p, &[x]) => x.ln_1p(),
+            (MathFn::Logb, &[x]) => logb(x),
+            (MathFn::Significand, &[x]) => significand(x),
+            (MathFn::Sin, &[x]) => x.sin(),
+            (MathFn::Cos, &[x]) => x.cos(),
+            (MathFn::Tan, &[x]) => x.tan(),
+            (MathFn::Asin, &[x]) => x.asin(),
+            (MathFn::Acos, &[x]) => x.acos(),
+            (MathFn::Atan, &[x]) => x.atan(),
+            (MathFn::Sinh, &[x]) => x.sinh(),
+            (MathFn::Cosh, &[x]) => x.cosh(),
+            (MathFn::Tanh, &[x]) => x.tanh(),
+            (MathFn::Asinh, &[x]) => x.asinh(),
+            (MathFn::Acosh, &[x]) => x.acosh(),
+            (MathFn::Atanh, &[x]) => x.atanh(),
+            (MathFn::Lgamma, &[x]) => lgamma(x).0,
+            (MathFn::Tgamma, &[x]) => tgamma(x),
+            (MathFn::LgammaR, &[x]) => {
+                let (value, sign) = lgamma(x);
+                return Ok(pair(value, sign));
+            }
+            (MathFn::Frexp, &[x]) => {
+                let (mantissa, exponent) = frexp(x);
+                return Ok(pair(mantissa, exponent as f64));
+            }
+            (MathFn::Modf, &[x]) => {
+                let fraction = if x.is_infinite() { 0.0 } else { x.fract() };
+                return Ok(pair(fraction.copysign(x), x.trunc()));
+            }
+            (MathFn::IsInfinite, &[x]) => return Ok(Value::Bool(is_saturated(x))),
+            (MathFn::IsNan, &[x]) => return Ok(Value::Bool(x.is_nan())),
+            (MathFn::IsNormal, &[x]) => return Ok(Value::Bool(x.is_normal() && !is_saturated(x))),
+            (MathFn::Pow, &[x, y]) => x.powf(y),
+            (MathFn::Atan2, &[y, x]) => y.atan2(x),
+            (MathFn::Fmod, &[x, y]) => x % y,
+            (MathFn::Hypot, &[x, y]) => x.hypot(y),
+            (MathFn::Fmin, &[x, y]) => x.min(y),
+            (MathFn::Fmax, &[x, y]) => x.max(y),
+            (MathFn::Fdim, &[x, y]) => (x - y).max(0.0),
+            (MathFn::Copysign, &[x, y]) => x.copysign(y),
+            (MathFn::Ldexp, &[x, e]) => ldexp(x, e),
+            (MathFn::Nextafter, &[x, y]) => nextafter(x, y),
+            (MathFn::Drem, &[x, y]) => x - (x / y).round_ties_even() * y,
+            (MathFn::Fma, &[x, y, z]) => x.mul_add(y, z),
+            _ => unreachable!("{:?} called with {} arguments", self, args.len()),
+        };
+        Ok(float_value(result))
+    }
+
+    // Rounding an integer, or taking its absolute value, leaves it as it is;
+    // going through a double would lose precision past 2^53
+    fn exact(&self, value: &Value) -> Option<Value> {
+        let Value::Number(n) = value else {
+            return None;
+        };
+        match self {
+            MathFn::Floor | MathFn::Ceil | MathFn::Round | MathFn::Trunc | MathFn::Rint
+                if n.is_i64() || n.is_u64() =>
+            {
+                Some(value.clone())
+            }
+            MathFn::Fabs => match (n.as_i64(), n.as_u64()) {
+                (_, Some(_)) => Some(value.clone()),
+                (Some(i), None) => i.checked_abs().map(|i| Value::Number(Number::from(i))),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+fn number(value: &Value) -> Result<f64, FilterError> {
+    match value {
+        Value::Number(n) => Ok(n.as_f64().unwrap_or(0.0)),
+        other => Err(FilterError::NumberRequired(describe(other))),
+    }
+}
+
+fn pair(a: f64, b: f64) -> Value {
+    Value::Array(vec![float_value(a), float_value(b)])
+}
+
+// Infinities are stored as the largest finite double, as jq prints them
+fn is_saturated(x: f64) -> bool {
+    x.is_infinite() || x.abs() == f64::MAX
+}
+
+// Splits a finite, non-zero number into a mantissa in [0.5, 1) and a power of two
+fn frexp(x: f64) -> (f64, i32) {
+    if x == 0.0 || !x.is_finite() {
+        return (x, 0);
+    }
+    let bits = x.to_bits();
+    let exponent = ((bits >> 52) & 0x7ff) as i32;
+    if exponent == 0 {
+        // subnormal: scale into the normal range first
+        let (mantissa, exponent) = frexp(x * 2f64.powi(54));
+        return (mantissa, exponent - 54);
+    }
+    let mantissa = f64::from_bits((bits & !(0x7ff << 52)) | (1022 << 52));
+    (mantissa, exponent - 1022)
+}
+
+fn logb(x: f64) -> f64 {
+    match x {
+        _ if x == 0.0 => f64::NEG_INFINITY,
+        _ if !x.is_finite() => x.abs(),
+        _ => (frexp(x).1 - 1) as f64,
+    }
+}
+
+fn significand(x: f64) -> f64 {
+    if x == 0.0 || !x.is_finite() {
+        return x;
+    }
+    frexp(x).0 * 2.0
+}
+
+fn ldexp(x: f64, e: f64) -> f64 {
+    // powi overflows for exponents a subnormal result still needs, so scale twice
+    let e = e.clamp(-2200.0, 2200.0) as i32;
+    let half = e / 2;
+    x * 2f64.powi(half) * 2f64.powi(e - half)
+}
+
+fn nextafter(x: f64, y: f64) -> f64 {
+    if x.is_nan() || y.is_nan() {
+        return f64::NAN;
+    }
+    if x == y {
+        return y;
+    }
+    if x == 0.0 {
+        return f64::from_bits(1).copysign(y);
+    }
+    let bits = x.to_bits();
+    // moving away from zero increases the magnitude bits, towards it decreases
+    if (y > x) == (x > 0.0) {
+        f64::from_bits(bits + 1)
+    } else {
+        f64::from_bits(bits - 1)
+    }
+}
+
+// Lanczos approximation (g = 7) of the gamma function
+const LANCZOS: [f64; 9] = [
+    0.999_999_999_999_809_9,
+    676.520_368_121_885_1,
+    -1_259.139_216_722_402_8,
+    771.323_428_777_653_1,
+    -176.615_029_162_140_6,
+    12.507_343_278_686_905,
+    -0.138_571_095_265_720_12,
+    9.984_369_578_019_572e-6,
+    1.505_632_735_149_311_6e-7,
+];
+
+fn lanczos_sum(x: f64) -> f64 {
+    LANCZOS[1..]
+        .iter()
+        .enumerate()
+        .fold(LANCZOS[0], |sum, (i, c)| sum + c / (x + i as f64 + 1.0))
+}
+
+fn tgamma(x: f64) -> f64 {
+    if x == x.floor() && x <= 0.0 {
+        return if x == 0.0 {
+            f64::INFINITY.copysign(x)
+        } else {
+            f64::NAN
+        };
+    }
+    // factorials are exact, and the approximation is a few ulps off them
+    if x == x.floor() && x <= 171.0 {
+        return (2..x as u32).fold(1.0, |product, n| product * n as f64);
+    }
+    if x < 0.5 {
+        return PI / ((PI * x).sin() * tgamma(1.0 - x));
+    }
+    let x = x - 1.0;
+    let t = x + 7.5;
+    (2.0 * PI).sqrt() * t.powf(x + 0.5) * (-t).exp() * lanczos_sum(x)
+}
+
+// The logarithm of |gamma(x)| and the sign of gamma(x)
+fn lgamma(x: f64) -> (f64, f64) {
+    if x == x.floor() && x <= 0.0 {
+        return (f64::INFINITY, 1.0);
+    }
+    let sign = if x < 0.0 && x.floor() as i64 % 2 != 0 {
+        -1.0
+    } else {
+        1.0
+    };
+    if x < 0.5 {
+        return ((PI / (PI * x).sin().abs()).ln() - lgamma(1.0 - x).0, sign);
+    }
+    let gamma = tgamma(x);
+    if gamma.is_finite() {
+        return (gamma.ln(), sign);
+    }
+    let x = x - 1.0;
+    let t = x + 7.5;
+    let value = 0.5 * (2.0 * PI).ln() + (x + 0.5) * t.ln() - t + lanczos_sum(x).ln();
+    (value, sign)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn apply(name: &str, args: &[Value]) -> Result<Value, FilterError> {
+        let arity = if args.len() == 1 { 0 } else { args.len() };
+        MathFn::from_name(name, arity).unwrap().apply(args)
+    }
+
+    #[test]
+    fn test_rounding() {
+        assert_eq!(apply("floor", &[json!(-1.5)]).unwrap(), json!(-2));
+        assert_eq!(apply("ceil", &[json!(1.2)]).unwrap(), json!(2));
+        assert_eq!(apply("round", &[json!(-2.5)]).unwrap(), json!(-3));
+        assert_eq!(apply("rint", &[json!(2.5)]).unwrap(), json!(2));
+        assert_eq!(apply("trunc", &[json!(-1.7)]).unwrap(), json!(-1));
+        assert_eq!(apply("fabs", &[json!(-3)]).unwrap(), json!(3));
+        // integers stay exact past 2^53
+        assert_eq!(
+            apply("floor", &[json!(9007199254740993u64)]).unwrap(),
+            json!(9007199254740993u64)
+        );
+        assert_eq!(
+            apply("fabs", &[json!(-9007199254740993i64)]).unwrap(),
+            json!(9007199254740993i64)
+        );
+    }
+
+    #[test]
+    fn test_functions() {
+        assert_eq!(apply("sqrt", &[json!(16)]).unwrap(), json!(4));
+        assert_eq!(apply("exp10", &[json!(2)]).unwrap(), json!(100));
+        assert_eq!(apply("log2", &[json!(8)]).unwrap(), json!(3));
+        assert_eq!(apply("logb", &[json!(10)]).unwrap(), json!(3));
+        assert_eq!(apply("significand", &[json!(4.5)]).unwrap(), json!(1.125));
+        assert_eq!(apply("frexp", &[json!(8)]).unwrap(), json!([0.5, 4]));
+        assert_eq!(apply("modf", &[json!(3.5)]).unwrap(), json!([0.5, 3]));
+        assert_eq!(apply("tgamma", &[json!(5)]).unwrap(), json!(24));
+        assert_eq!(apply("lgamma", &[json!(5)]).unwrap(), json!(24f64.ln()));
+        let Value::Array(lgamma_r) = apply("lgamma_r", &[json!(-3.5)]).unwrap() else {
+            panic!("lgamma_r returns a pair");
+        };
+        assert!((lgamma_r[0].as_f64().unwrap() + 1.309006684993042).abs() < 1e-12);
+        assert_eq!(lgamma_r[1], json!(1));
+    }
+
+    #[test]
+    fn test_several_arguments() {
+        assert_eq!(apply("pow", &[json!(2), json!(10)]).unwrap(), json!(1024));
+        assert_eq!(apply("fmod", &[json!(-7), json!(3)]).unwrap(), json!(-1));
+        assert_eq!(apply("drem", &[json!(10), json!(3)]).unwrap(), json!(1));
+        assert_eq!(apply("ldexp", &[json!(3), json!(2)]).unwrap(), json!(12));
+        assert_eq!(
+            apply("copysign", &[json!(1), json!(-1)]).unwrap(),
+            json!(-1)
+        );
+        assert_eq!(
+            apply("nextafter", &[json!(1), json!(2)]).unwrap(),
+            json!(1.0000000000000002)
+        );
+        assert_eq!(
+            apply("fma", &[json!(2), json!(3), json!(4)]).unwrap(),
+            json!(10)
+        );
+    }
+
+    #[test]
+    fn test_special_values() {
+        // infinities saturate and NaN becomes null
+        assert_eq!(apply("log", &[json!(0)]).unwrap(), json!(-f64::MAX));
+        assert_eq!(apply("sqrt", &[json!(-1)]).unwrap(), json!(null));
+        assert_eq!(
+            apply("isinfinite", &[json!(f64::MAX)]).unwrap(),
+            json!(true)
+        );
+        assert_eq!(apply("isnormal", &[json!(1e308)]).unwrap(), json!(true));
+        assert_eq!(apply("isnormal", &[json!(0)]).unwrap(), json!(false));
+        assert_eq!(apply("isnormal", &[json!(1)]).unwrap(), json!(true));
+    }
+
+    #[test]
+    fn test_null_is_not_nan() {
+        // NaN results print as null, but a null input is not read as NaN
+        assert_eq!(apply("isnan", &[json!(null)]).unwrap(), json!(false));
+        assert_eq!(apply("isinfinite", &[json!(null)]).unwrap(), json!(false));
+        assert_eq!(apply("isnormal", &[json!(null)]).unwrap(), json!(false));
+        assert_eq!(
+            apply("floor", &[json!(null)]).unwrap_err().to_string(),
+            "null (null) number required"
+        );
+        assert!(apply("sqrt", &[json!(null)]).is_err());
+        assert!(apply("pow", &[json!(null), json!(2)]).is_err());
+    }
+
+    #[test]
+    fn test_number_required() {
+        assert_eq!(
+            apply("floor", &[json!("a")]).unwrap_err().to_string(),
+            "string (\"a\") number required"
+        );
+        assert!(apply("floor", &[json!(null)]).is_err());
+    }
+}
@@ -0,0 +1,436 @@
+use crate::filter::{describe, FilterError};
+use serde_json::{Map, Number, Value};
+use std::cmp::Ordering;
+
+// Binary operators with jq's type rules
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl BinOp {
+    pub fn apply(&self, lhs: Value, rhs: Value) -> Result<Value, FilterError> {
+        match self {
+            BinOp::Add => add(lhs, rhs),
+            BinOp::Sub => subtract(lhs, rhs),
+            BinOp::Mul => multiply(lhs, rhs),
+            BinOp::Div => divide(lhs, rhs),
+            BinOp::Mod => modulo(lhs, rhs),
+            BinOp::Eq => Ok(Value::Bool(compare(&lhs, &rhs) == Ordering::Equal)),
+            BinOp::Ne => Ok(Value::Bool(compare(&lhs, &rhs) != Ordering::Equal)),
+            BinOp::Lt => Ok(Value::Bool(compare(&lhs, &rhs) == Ordering::Less)),
+            BinOp::Le => Ok(Value::Bool(compare(&lhs, &rhs) != Ordering::Greater)),
+            BinOp::Gt => Ok(Value::Bool(compare(&lhs, &rhs) == Ordering::Greater)),
+            BinOp::Ge => Ok(Value::Bool(compare(&lhs, &rhs) != Ordering::Less)),
+        }
+    }
+}
+
+// Only `false` and `null` are falsy
+pub fn is_truthy(value: &Value) -> bool {
+    !matches!(value, Value::Null | Value::Bool(false))
+}
+
+fn invalid(lhs: &Value, rhs: &Value, verb: &'static str) -> FilterError {
+    FilterError::InvalidOperands(describe(lhs), describe(rhs), verb)
+}
+
+// A float result as jq stores it: NaN becomes null and infinities saturate to
+// the largest finite double. Integral results are kept as integers so that
+// they print without a fraction.
+pub fn float_value(f: f64) -> Value {
+    if f.fract() == 0.0 && f.abs() < 1e17 {
+        return Value::Number(Number::from(f as i64));
+    }
+    Number::from_f64(f.clamp(f64::MIN, f64::MAX))
+        .map(Value::Number)
+        .unwrap_or(Value::Null)
+}
+
+// Arithmetic on two numbers, staying in integers while the result is exact
+fn arithmetic(
+    lhs: &Number,
+    rhs: &Number,
+    int_op: fn(i64, i64) -> Option<i64>,
+    float_op: fn(f64, f64) -> f64,
+) -> Value {
+    if let (Some(a), Some(b)) = (lhs.as_i64(), rhs.as_i64()) {
+        if let Some(result) = int_op(a, b) {
+            return Value::Number(Number::from(result));
+        }
+    }
+    let a = lhs.as_f64().unwrap_or(0.0);
+    let b = rhs.as_f64().unwrap_or(0.0);
+    float_value(float_op(a, b))
+}
+
+// `null` is the identity, numbers sum, and strings, arrays and objects concatenate
+pub fn add(lhs: Value, rhs: Value) -> Result<Value, FilterError> {
+    match (lhs, rhs) {
+        (Value::Null, rhs) => Ok(rhs),
+        (lhs, Value::Null) => Ok(lhs),
+        (Value::Number(a), Value::Number(b)) => {
+            Ok(arithmetic(&a, &b, i64::checked_add, |a, b| a + b))
+        }
+        (Value::String(mut a), Value::String(b)) => {
+            a.push_str(&b);
+            Ok(Value::String(a))
+        }
+        (Value::Array(mut a), Value::Array(b)) => {
+            a.extend(b);
+            Ok(Value::Array(a))
+        }
+        (Value::Object(mut a), Value::Object(b)) => {
+            a.extend(b);
+            Ok(Value::Object(a))
+        }
+        (lhs, rhs) => Err(invalid(&lhs, &rhs, "added")),
+    }
+}
+
+// Numbers subtract; arrays drop every element that appears in the right side
+pub fn subtract(lhs: Value, rhs: Value) -> Result<Value, FilterError> {
+    match (lhs, rhs) {
+        (Value::Number(a), Value::Number(b)) => {
+            Ok(arithmetic(&a, &b, i64::checked_sub, |a, b| a - b))
+        }
+        (Value::Array(a), Value::Array(b)) => Ok(Value::Array(
+            a.into_iter()
+                .filter(|item| {
+                    !b.iter()
+                        .any(|other| compare(item, other) == Ordering::Equal)
+                })
+                .collect(),
+        )),
+        (lhs, rhs) => Err(invalid(&lhs, &rhs, "subtracted")),
+    }
+}
+
+// Numbers multiply, strings repeat and objects merge recursively
+pub fn multiply(lhs: Value, rhs: Value) -> Result<Value, FilterError> {
+    match (lhs, rhs) {
+        (Value::Number(a), Value::Number(b)) => {
+            Ok(arithmetic(&a, &b, i64::checked_mul, |a, b| a * b))
+        }
+        (Value::String(s), Value::Number(n)) | (Value::Number(n), Value::String(s)) => {
+            let n = n.as_f64().unwrap_or(0.0);
+            if n <= 0.0 {
+                return Ok(Value::Null);
+            }
+            let count = (n - 1.0).trunc() + 1.0;
+            // jq caps the result at the length of its own strings
+            if count * s.len() as f64 > i32::MAX as f64 {
+                return Err(FilterError::RepeatTooLong);
+            }
+            Ok(Value::String(s.repeat(count as usize)))
+        }
+        (Value::Object(a), Value::Object(b)) => Ok(Value::Object(deep_merge(a, b))),
+        (lhs, rhs) => Err(invalid(&lhs, &rhs, "multiplied")),
+    }
+}
+
+fn deep_merge(mut lhs: Map<String, Value>, rhs: Map<String, Value>) -> Map<String, Value> {
+    for (key, value) in rhs {
+        let merged = match (lhs.remove(&key), value) {
+            (Some(Value::Object(a)), Value::Object(b)) => Value::Object(deep_merge(a, b)),
+            (_, value) => value,
+        };
+        lhs.insert(key, merged);
+    }
+    lhs
+}
+
+fn is_zero(num: &Number) -> bool {
+    num.as_f64() == Some(0.0)
+}
+
+// Numbers divide and strings split on the right-hand separator
+pub fn divide(lhs: Value, rhs: Value) -> Result<Value, FilterError> {
+    match (lhs, rhs) {
+        (Value::Number(a), Value::Number(b)) => {
+            if is_zero(&b) {
+                return Err(FilterError::DivisionByZero(
+                    describe(&Value::Number(a)),
+                    describe(&Value::Number(b)),
+                    "divided",
+                ));
+            }
+            Ok(arithmetic(
+                &a,
+                &b,
+                |a, b| (a.checked_rem(b) == Some(0)).then(|| a.checked_div(b))?,
+                |a, b| a / b,
+            ))
+        }
+        (Value::String(a), Value::String(b)) => Ok(Value::Array(split(&a, &b))),
+        (lhs, rhs) => Err(invalid(&lhs, &rhs, "divided")),
+    }
+}
+
+// Split a string on a separator, an empty separator splitting into characters
+pub fn split(s: &str, separator: &str) -> Vec<Value> {
+    if s.is_empty() {
+        return Vec::new();
+    }
+    if separator.is_empty() {
+        return s.chars().map(|c| Value::String(c.to_string())).collect();
+    }
+    s.split(separator)
+        .map(|part| Value::String(part.to_string()))
+        .collect()
+}
+
+// Remainder after truncating both operands to integers
+pub fn modulo(lhs: Value, rhs: Value) -> Result<Value, FilterError> {
+    match (&lhs, &rhs) {
+        (Value::Number(a), Value::Number(b)) => {
+            let a = a.as_f64().unwrap_or(0.0) as i64;
+            let b = b.as_f64().unwrap_or(0.0) as i64;
+            if b == 0 {
+                return Err(FilterError::DivisionByZero(
+                    describe(&lhs),
+                    describe(&rhs),
+                    "divided (remainder)",
+                ));
+            }
+            Ok(Value::Number(Number::from(a.wrapping_rem(b))))
+        }
+        _ => Err(invalid(&lhs, &rhs, "divided")),
+    }
+}
+
+pub fn negate(value: Value) -> Result<Value, FilterError> {
+    match value {
+        Value::Number(num) => match num.as_i64() {
+            Some(i) if i != i64::MIN => Ok(Value::Number(Number::from(-i))),
+            _ => Ok(float_value(-num.as_f64().unwrap_or(0.0))),
+        },
+        other => Err(FilterError::CannotNegate(describe(&other))),
+    }
+}
+
+// Position of a value's type in jq's ordering:
+// null < false < true < numbers < strings < arrays < objects
+fn type_rank(value: &Value) -> u8 {
+    match value {
+        Value::Null => 0,
+        Value::Bool(false) => 1,
+        Value::Bool(true) => 2,
+        Value::Number(_) => 3,
+        Value::String(_) => 4,
+        Value::Array(_) => 5,
+        Value::Object(_) => 6,
+    }
+}
+
+fn compare_numbers(a: &Number, b: &Number) -> Ordering {
+    match (a.as_i64(), b.as_i64()) {
+        (Some(a), Some(b)) => a.cmp(&b),
+        _ => {
+            let a = a.as_f64().unwrap_or(0.0);
+            let b = b.as_f64().unwrap_or(0.0);
+            a.partial_cmp(&b).unwrap_or(Ordering::Equal)
+        }
+    }
+}
+
+// Total ordering across all JSON values. Arrays compare element-wise and
+// objects compare their sorted key sets first, then their values key by key.
+pub fn compare(a: &Value, b: &Value) -> Ordering {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => compare_numbers(a, b),
+        (Value::String(a), Value::String(b)) => a.cmp(b),
+        (Value::Array(a), Value::Array(b)) => {
+            for (x, y) in a.iter().zip(b.iter()) {
+                let ordering = compare(x, y);
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            a.len().cmp(&b.len())
+        }
+        (Value::Object(a), Value::Object(b)) => {
+            let mut a_keys: Vec<&String> = a.keys().collect();
+            let mut b_keys: Vec<&String> = b.keys().collect();
+            a_keys.sort();
+            b_keys.sort();
+            let ordering = a_keys.cmp(&b_keys);
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+            for key in a_keys {
+                let ordering = compare(&a[key], &b[key]);
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            Ordering::Equal
+        }
+        _ => type_rank(a).cmp(&type_rank(b)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_add_numbers_keeps_integers() {
+        assert_eq!(add(json!(1), json!(2)).unwrap(), json!(3));
+        assert_eq!(add(json!(1), json!(0.5)).unwrap(), json!(1.5));
+    }
+
+    #[test]
+    fn test_add_null_is_identity() {
+        assert_eq!(add(json!(null), json!("a")).unwrap(), json!("a"));
+        assert_eq!(add(json!([1]), json!(null)).unwrap(), json!([1]));
+    }
+
+    #[test]
+    fn test_add_concatenates_and_merges() {
+        assert_eq!(add(json!("ab"), json!("c")).unwrap(), json!("abc"));
+        assert_eq!(add(json!([1]), json!([2, 3])).unwrap(), json!([1, 2, 3]));
+        assert_eq!(
+            add(json!({"a": 1, "b": 2}), json!({"b": 3})).unwrap(),
+            json!({"a": 1, "b": 3})
+        );
+    }
+
+    #[test]
+    fn test_add_invalid_operands() {
+        let result = add(json!({"a": 1}), json!(1));
+        match result {
+            Err(err) => assert_eq!(
+                err.to_string(),
+                r#"object ({"a":1}) and number (1) cannot be added"#
+            ),
+            Ok(_) => panic!("Expected InvalidOperands error"),
+        }
+    }
+
+    #[test]
+    fn test_subtract_arrays() {
+        let result = subtract(json!([1, 2, 3, 1]), json!([1])).unwrap();
+        assert_eq!(result, json!([2, 3]));
+    }
+
+    #[test]
+    fn test_multiply_repeats_strings() {
+        assert_eq!(multiply(json!("ab"), json!(3)).unwrap(), json!("ababab"));
+        assert_eq!(multiply(json!(2), json!("x")).unwrap(), json!("xx"));
+        assert_eq!(multiply(json!("ab"), json!(0)).unwrap(), json!(null));
+        assert_eq!(
+            multiply(json!("x"), json!(1e12)).unwrap_err().to_string(),
+            "Repeat string result too long"
+        );
+    }
+
+    #[test]
+    fn test_multiply_deep_merges_objects() {
+        let result = multiply(
+            json!({"a": {"b": 1, "c": 2}}),
+            json!({"a": {"b": 3}, "d": 4}),
+        );
+        assert_eq!(result.unwrap(), json!({"a": {"b": 3, "c": 2}, "d": 4}));
+    }
+
+    #[test]
+    fn test_divide() {
+        assert_eq!(divide(json!(6), json!(3)).unwrap(), json!(2));
+        assert_eq!(divide(json!(1), json!(2)).unwrap(), json!(0.5));
+        assert_eq!(
+            divide(json!("a,b,c"), json!(",")).unwrap(),
+            json!(["a", "b", "c"])
+        );
+    }
+
+    #[test]
+    fn test_divide_by_zero() {
+        let result = divide(json!(1), json!(0));
+        assert!(matches!(result, Err(FilterError::DivisionByZero(_, _, _))));
+        assert_eq!(
+            modulo(json!(1), json!(0)).unwrap_err().to_string(),
+            "number (1) and number (0) cannot be divided (remainder) because the divisor is zero"
+        );
+    }
+
+    #[test]
+    fn test_modulo_truncates() {
+        assert_eq!(modulo(json!(5), json!(2)).unwrap(), json!(1));
+        assert_eq!(modulo(json!(-5), json!(3)).unwrap(), json!(-2));
+        assert_eq!(modulo(json!(5.7), json!(2.2)).unwrap(), json!(1));
+        assert!(modulo(json!(5), json!(0.5)).is_err());
+    }
+
+    #[test]
+    fn test_negate() {
+        assert_eq!(negate(json!(2)).unwrap(), json!(-2));
+        assert!(matches!(
+            negate(json!("a")),
+            Err(FilterError::CannotNegate(_))
+        ));
+    }
+
+    #[test]
+    fn test_compare_across_types() {
+        let ordered = [
+            json!(null),
+            json!(false),
+            json!(true),
+            json!(1),
+            json!("a"),
+            json!([]),
+            json!({}),
+        ];
+        for pair in ordered.windows(2) {
+            assert_eq!(compare(&pair[0], &pair[1]), Ordering::Less);
+        }
+    }
+
+    #[test]
+    fn test_compare_numbers_and_containers() {
+        assert_eq!(compare(&json!(1), &json!(1.0)), Ordering::Equal);
+        assert_eq!(compare(&json!([1, 2]), &json!([1, 2, 0])), Ordering::Less);
+        assert_eq!(
+            compare(&json!({"a": 2}), &json!({"a": 1, "b": 0})),
+            Ordering::Less
+        );
+        assert_eq!(compare(&json!({"a": 1}), &json!({"a": 2})), Ordering::Less);
+    }
+
+    #[test]
+    fn test_comparison_operators() {
+        assert_eq!(BinOp::Eq.apply(json!(1), json!(1.0)).unwrap(), json!(true));
+        assert_eq!(
+            BinOp::Ne.apply(json!("a"), json!("b")).unwrap(),
+            json!(true)
+        );
+        assert_eq!(BinOp::Le.apply(json!(2), json!(2)).unwrap(), json!(true));
+        assert_eq!(
+            BinOp::Gt.apply(json!(null), json!(false)).unwrap(),
+            json!(false)
+        );
+    }
+
+    #[test]
+    fn test_float_value_special_values() {
+        assert_eq!(float_value(f64::NAN), json!(null));
+        assert_eq!(float_value(f64::INFINITY), json!(f64::MAX));
+        assert_eq!(float_value(f64::NEG_INFINITY), json!(f64::MIN));
+        assert_eq!(
+            BinOp::Mul.apply(json!(1e308), json!(10)).unwrap(),
+            json!(f64::MAX)
+        );
+    }
+}
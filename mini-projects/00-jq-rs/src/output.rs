@@ -1,14 +1,52 @@
-use serde_json::Value;
+use serde_json::{Number, Value};
 use std::env;
-use std::io::{Result, Write};
+use std::io::{Error, ErrorKind, Result, Write};
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct OutputOptions {
     pub compact: bool,
     pub color_output: bool,
     pub monochrome_output: bool,
     pub sort_keys: bool,
     pub indent: usize,
+    // indent with one tab per level instead of `indent` spaces
+    pub tab: bool,
+    // write strings without quotes or escapes
+    pub raw_output: bool,
+    // raw output with nothing written between results
+    pub join_output: bool,
+    // raw output with a NUL byte after each result
+    pub raw_output0: bool,
+    // escape every non-ASCII character in strings
+    pub ascii_output: bool,
+}
+
+// Write one result of the filter followed by its separator
+pub fn write_result<W: Write>(
+    writer: &mut W,
+    value: &Value,
+    options: &OutputOptions,
+) -> Result<()> {
+    let raw = options.raw_output || options.join_output || options.raw_output0;
+    match value {
+        // ASCII output takes precedence, so such strings stay quoted as jq has them
+        Value::String(s) if raw && !options.ascii_output => {
+            if options.raw_output0 && s.contains('\0') {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Cannot dump a string containing NUL with --raw-output0 option",
+                ));
+            }
+            writer.write_all(s.as_bytes())?;
+        }
+        _ => write_output(writer, value, options)?,
+    }
+    if options.raw_output0 {
+        writer.write_all(b"\0")?;
+    } else if !options.join_output {
+        writer.write_all(b"\n")?;
+    }
+    Ok(())
 }
 
 pub fn write_output<W: Write>(
@@ -27,6 +65,60 @@ pub fn write_output<W: Write>(
     Ok(())
 }
 
+// A value as compact JSON, written the way the output is
+pub fn to_json(value: &Value) -> String {
+    let options = OutputOptions {
+        compact: true,
+        ..Default::default()
+    };
+    let mut output = Vec::new();
+    write_output(&mut output, value, &options).expect("writing to a Vec cannot fail");
+    String::from_utf8(output).expect("output is UTF-8")
+}
+
+// Numbers as jq prints them. Integers are written in full. Floats use the
+// shortest digits that read back the same, in exponent form when the decimal
+// point would be more than 15 places past them or 4 places before them.
+// Integral floats below 1e17 can only come from the input, since arithmetic
+// gives integers for them, and keep their `.0` as written there. Negative
+// zero reads the same as `-0` and `-0.0`, and is written `-0` as jq does.
+pub fn format_number(num: &Number) -> String {
+    let Some(f) = num.as_f64().filter(|_| num.is_f64()) else {
+        return num.to_string();
+    };
+    if f == 0.0 && f.is_sign_negative() {
+        return "-0".to_string();
+    }
+    if f.fract() == 0.0 && f.abs() < 1e17 {
+        return format!("{:.1}", f);
+    }
+    let shortest = format!("{:e}", f.abs());
+    let (mantissa, exponent) = shortest.split_once('e').expect("`{:e}` has an exponent");
+    let exponent: i32 = exponent.parse().expect("`{:e}` exponent is an integer");
+    let digits = mantissa.replace('.', "");
+    let decimal_point = exponent + 1;
+    let text = if decimal_point <= -4 || decimal_point > digits.len() as i32 + 15 {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", mantissa, sign, exponent.abs())
+    } else if decimal_point <= 0 {
+        format!("0.{}{}", "0".repeat(-decimal_point as usize), digits)
+    } else if decimal_point as usize >= digits.len() {
+        format!(
+            "{}{}",
+            digits,
+            "0".repeat(decimal_point as usize - digits.len())
+        )
+    } else {
+        let (whole, fraction) = digits.split_at(decimal_point as usize);
+        format!("{}.{}", whole, fraction)
+    };
+    if f < 0.0 {
+        format!("-{}", text)
+    } else {
+        text
+    }
+}
+
 struct Formatter<'a, W: Write> {
     writer: &'a mut W,
     options: &'a OutputOptions,
@@ -63,10 +155,10 @@ impl<'a, W: Write> Formatter<'a, W> {
             }
             Value::Number(num) => {
                 let color = self.color_config.as_ref().map(|c| c.number.clone());
-                self.write_colored(&num.to_string(), &color)?;
+                self.write_colored(&format_number(num), &color)?;
             }
             Value::String(s) => {
-                let quoted = format!("\"{}\"", s);
+                let quoted = format!("\"{}\"", self.escape(s));
                 let color = self.color_config.as_ref().map(|c| c.string.clone());
                 self.write_colored(&quoted, &color)?;
             }
@@ -130,7 +222,7 @@ impl<'a, W: Write> Formatter<'a, W> {
                 if !self.options.compact {
                     self.write_indent(new_depth)?;
                 }
-                let quoted_key = format!("\"{}\"", key);
+                let quoted_key = format!("\"{}\"", self.escape(key));
                 let key_color = self.color_config.as_ref().map(|c| c.key.clone());
                 self.write_colored(&quoted_key, &key_color)?;
                 self.write_colored(":", &brackets_color)?;
@@ -154,13 +246,38 @@ impl<'a, W: Write> Formatter<'a, W> {
         Ok(())
     }
 
+    // The body of a JSON string literal: quotes, backslashes and control
+    // characters are escaped, and non-ASCII characters too for ASCII output
+    fn escape(&self, s: &str) -> String {
+        let mut escaped = String::with_capacity(s.len());
+        for c in s.chars() {
+            match c {
+                '"' => escaped.push_str("\\\""),
+                '\\' => escaped.push_str("\\\\"),
+                '\n' => escaped.push_str("\\n"),
+                '\t' => escaped.push_str("\\t"),
+                '\r' => escaped.push_str("\\r"),
+                '\u{8}' => escaped.push_str("\\b"),
+                '\u{c}' => escaped.push_str("\\f"),
+                '\u{0}'..='\u{1f}' | '\u{7f}' => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+                c if self.options.ascii_output && !c.is_ascii() => {
+                    let mut units = [0; 2];
+                    for unit in c.encode_utf16(&mut units) {
+                        escaped.push_str(&format!("\\u{:04x}", unit));
+                    }
+                }
+                c => escaped.push(c),
+            }
+        }
+        escaped
+    }
+
     fn write_indent(&mut self, depth: usize) -> Result<()> {
-        let indent = if self.options.indent <= 7 {
-            self.options.indent
-        } else {
-            2
-        };
-        let spaces = " ".repeat(depth * indent);
+        if self.options.tab {
+            self.writer.write_all("\t".repeat(depth).as_bytes())?;
+            return Ok(());
+        }
+        let spaces = " ".repeat(depth * self.options.indent);
         self.writer.write_all(spaces.as_bytes())?;
         Ok(())
     }
@@ -274,6 +391,7 @@ mod tests {
             monochrome_output: false,
             sort_keys: false,
             indent: 2,
+            ..Default::default()
         };
         let mut output = Vec::new();
         write_output(&mut output, &value, &options).unwrap();
@@ -291,6 +409,7 @@ mod tests {
             monochrome_output: false,
             sort_keys: false,
             indent: 2,
+            ..Default::default()
         };
         let mut output = Vec::new();
         write_output(&mut output, &value, &options).unwrap();
@@ -308,6 +427,7 @@ mod tests {
             monochrome_output: false,
             sort_keys: false,
             indent: 4,
+            ..Default::default()
         };
         let mut output = Vec::new();
         write_output(&mut output, &value, &options).unwrap();
@@ -325,6 +445,7 @@ mod tests {
             monochrome_output: false,
             sort_keys: true,
             indent: 2,
+            ..Default::default()
         };
         let mut output = Vec::new();
         write_output(&mut output, &value, &options).unwrap();
@@ -344,6 +465,7 @@ mod tests {
             monochrome_output: false,
             sort_keys: false,
             indent: 2,
+            ..Default::default()
         };
         let mut output = Vec::new();
         write_output(&mut output, &value, &options).unwrap();
@@ -367,6 +489,7 @@ mod tests {
             monochrome_output: true,
             sort_keys: false,
             indent: 2,
+            ..Default::default()
         };
         let mut output = Vec::new();
         write_output(&mut output, &value, &options).unwrap();
@@ -389,6 +512,7 @@ mod tests {
             monochrome_output: false,
             sort_keys: false,
             indent: 2,
+            ..Default::default()
         };
         let mut output = Vec::new();
         write_output(&mut output, &value, &options).unwrap();
@@ -415,6 +539,7 @@ mod tests {
             monochrome_output: false,
             sort_keys: false,
             indent: 2,
+            ..Default::default()
         };
         let mut output = Vec::new();
         write_output(&mut output, &value, &options).unwrap();
@@ -424,19 +549,20 @@ mod tests {
     }
 
     #[test]
-    fn test_large_indent() {
+    fn test_max_indent() {
         let value = json!({"key": "value"});
         let options = OutputOptions {
             compact: false,
             color_output: false,
             monochrome_output: false,
             sort_keys: false,
-            indent: 8, // Exceeds the maximum of 7, should default to 2
+            indent: 7, // the command line rejects anything larger
+            ..Default::default()
         };
         let mut output = Vec::new();
         write_output(&mut output, &value, &options).unwrap();
         let output_str = String::from_utf8(output).unwrap();
-        let expected = "{\n  \"key\": \"value\"\n}";
+        let expected = "{\n       \"key\": \"value\"\n}";
         assert_eq!(output_str.trim(), expected);
     }
 
@@ -449,6 +575,7 @@ mod tests {
             monochrome_output: false,
             sort_keys: false,
             indent: 0,
+            ..Default::default()
         };
         let mut output = Vec::new();
         write_output(&mut output, &value, &options).unwrap();
@@ -456,4 +583,139 @@ mod tests {
         let expected = "{\n\"key\": \"value\",\n\"nested\": {\n\"inner\": 123\n}\n}";
         assert_eq!(output_str.trim(), expected);
     }
+
+    fn result_text(value: &Value, options: &OutputOptions) -> String {
+        let mut output = Vec::new();
+        write_result(&mut output, value, options).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_raw_and_join_output() {
+        let raw = OutputOptions {
+            compact: true,
+            raw_output: true,
+            ..Default::default()
+        };
+        assert_eq!(result_text(&json!("a b"), &raw), "a b\n");
+        assert_eq!(result_text(&json!(["a"]), &raw), "[\"a\"]\n");
+        let join = OutputOptions {
+            compact: true,
+            join_output: true,
+            ..Default::default()
+        };
+        assert_eq!(result_text(&json!("a"), &join), "a");
+        assert_eq!(result_text(&json!(1), &join), "1");
+        let compact = OutputOptions {
+            compact: true,
+            ..Default::default()
+        };
+        assert_eq!(result_text(&json!("a"), &compact), "\"a\"\n");
+    }
+
+    #[test]
+    fn test_raw_output0() {
+        let options = OutputOptions {
+            compact: true,
+            raw_output0: true,
+            ..Default::default()
+        };
+        assert_eq!(result_text(&json!("a"), &options), "a\0");
+        let mut output = Vec::new();
+        assert!(write_result(&mut output, &json!("a\u{0}b"), &options).is_err());
+    }
+
+    #[test]
+    fn test_ascii_output() {
+        let options = OutputOptions {
+            compact: true,
+            ascii_output: true,
+            raw_output: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            result_text(&json!({"é": "a😀"}), &options),
+            "{\"\\u00e9\":\"a\\ud83d\\ude00\"}\n"
+        );
+        // strings stay quoted even in raw mode
+        assert_eq!(result_text(&json!("é"), &options), "\"\\u00e9\"\n");
+    }
+
+    #[test]
+    fn test_tab_indent() {
+        let options = OutputOptions {
+            indent: 4,
+            tab: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            result_text(&json!({"a": [1]}), &options),
+            "{\n\t\"a\": [\n\t\t1\n\t]\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_string_escaping() {
+        let value = json!("q\" b\\ \u{8}\u{c}\n\r\t \u{0}\u{1f}\u{7f} / é");
+        assert_eq!(
+            to_json(&value),
+            r#""q\" b\\ \b\f\n\r\t \u0000\u001f\u007f / é""#
+        );
+        assert_eq!(to_json(&json!({"a\"b": 1})), r#"{"a\"b":1}"#);
+    }
+
+    #[test]
+    fn test_number_formatting() {
+        let cases = [
+            ("1", "1"),
+            ("-7", "-7"),
+            ("22.0", "22.0"),
+            ("-3.0", "-3.0"),
+            ("-0", "-0"),
+            ("-0.0", "-0"),
+            ("18446744073709551615", "18446744073709551615"),
+            ("1e17", "1e+17"),
+            ("1.5e300", "1.5e+300"),
+            ("12345678901234567890123", "12345678901234568000000"),
+            ("0.1", "0.1"),
+            ("100.5", "100.5"),
+            ("0.0001", "0.0001"),
+            ("0.00001", "1e-05"),
+            ("-1.5e-7", "-1.5e-07"),
+            ("5e-324", "5e-324"),
+        ];
+        for (input, expected) in cases {
+            let value: Value = serde_json::from_str(input).unwrap();
+            assert_eq!(to_json(&value), expected, "formatting {}", input);
+        }
+    }
+
+    #[test]
+    fn test_output_round_trips() {
+        let value = json!({
+            "text": "line\nbreak \"quoted\" \\ tab\t \u{1} é 😀",
+            "numbers": [0, -1, 22.0, -0.0, 22.5, 0.1, 1e-7, 1.5e300, 12345678901234567890u64],
+            "nested": {"\"key\"": [null, true, false, {}, []]},
+        });
+        for options in [
+            OutputOptions {
+                indent: 2,
+                ..Default::default()
+            },
+            OutputOptions {
+                compact: true,
+                ..Default::default()
+            },
+            OutputOptions {
+                ascii_output: true,
+                tab: true,
+                ..Default::default()
+            },
+        ] {
+            let mut output = Vec::new();
+            write_output(&mut output, &value, &options).unwrap();
+            let parsed: Value = serde_json::from_slice(&output).unwrap();
+            assert_eq!(parsed, value);
+        }
+    }
 }
@@ -1,116 +1,1108 @@
-use crate::filter::FilterFn;
-use regex::Regex;
+use crate::builtins::{self, Builtin, BuiltinArg, ByOp};
+use crate::filter::{FilterFn, ObjectPattern, Param, Pattern, StringPart};
+use crate::lexer::{Lexer, Spanned, TemplatePart, Token};
+use crate::matcher::RegexOp;
+use crate::math::MathFn;
+use crate::ops::{self, BinOp};
+use crate::path::AssignOp;
+use serde_json::{Number, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum ParseError {
-    #[error("Please enter a valid filter string")]
-    InvalidString,
+    #[error("unexpected token `{token}` at byte {offset}")]
+    UnexpectedToken { offset: usize, token: Token },
+
+    #[error("unexpected end of filter at byte {offset}")]
+    UnexpectedEnd { offset: usize },
+
+    #[error("invalid character `{character}` at byte {offset}")]
+    InvalidCharacter { offset: usize, character: char },
+
+    #[error("invalid escape `\\{character}` at byte {offset}")]
+    InvalidEscape { offset: usize, character: char },
+
+    #[error("unterminated string starting at byte {offset}")]
+    UnterminatedString { offset: usize },
+
+    #[error("{name} is not defined at byte {offset}")]
+    UnknownFunction { offset: usize, name: String },
+
+    #[error("${name} is not defined at byte {offset}")]
+    UndefinedVariable { offset: usize, name: String },
+
+    #[error("@{name} is not a valid format at byte {offset}")]
+    UnknownFormat { offset: usize, name: String },
+
+    #[error("module not found: {name} at byte {offset}")]
+    ModuleNotFound { offset: usize, name: String },
+
+    #[error("in module {path}: {source}")]
+    Module {
+        path: String,
+        source: Box<ParseError>,
+    },
 }
 
-pub struct Parse {}
+pub struct Parse {
+    library_paths: Vec<PathBuf>,
+    variables: Vec<String>,
+}
 
 impl Parse {
     pub fn new() -> Parse {
-        Parse {}
+        Parse {
+            library_paths: Vec::new(),
+            variables: Vec::new(),
+        }
+    }
+
+    // Look up `import`ed and `include`d modules in `paths`
+    pub fn with_library_paths(mut self, paths: Vec<PathBuf>) -> Parse {
+        self.library_paths = paths;
+        self
+    }
+
+    // Accept `$name` for each of `names`, which the caller binds before running
+    pub fn with_variables(mut self, names: Vec<String>) -> Parse {
+        self.variables = names;
+        self
+    }
+
+    // Parse the input string into an expression tree
+    pub fn parse(&self, input: &str) -> Result<FilterFn, ParseError> {
+        let mut parser = Parser::new(input, "", &self.library_paths)?;
+        parser.vars = self.variables.clone();
+        let definitions = parser.parse_directives()?;
+        let filter = parser.parse_pipe()?;
+        match parser.tokens.get(parser.pos) {
+            Some(spanned) => Err(parser.unexpected(spanned)),
+            None => Ok(define_all(definitions, filter)),
+        }
+    }
+}
+
+// A `def` before it is attached to the expression it is visible in
+struct Definition {
+    name: String,
+    params: Vec<Param>,
+    body: FilterFn,
+}
+
+// Wrap `rest` in the definitions, the first one outermost
+fn define_all(definitions: Vec<Definition>, rest: FilterFn) -> FilterFn {
+    definitions
+        .into_iter()
+        .rev()
+        .fold(rest, |rest, def| FilterFn::FuncDef {
+            name: def.name,
+            params: def.params,
+            body: Rc::new(def.body),
+            rest: Box::new(rest),
+        })
+}
+
+// The file for module `name`: `name.jq` or `name/<last component>.jq` in the
+// first library directory that has one
+fn find_module(library_paths: &[PathBuf], name: &str) -> Option<PathBuf> {
+    let last = Path::new(name).file_name()?.to_str()?;
+    library_paths.iter().find_map(|dir| {
+        [
+            dir.join(format!("{}.jq", name)),
+            dir.join(name).join(format!("{}.jq", last)),
+        ]
+        .into_iter()
+        .find(|path| path.is_file())
+    })
+}
+
+// Binding power of each infix operator as (left, right); higher binds tighter.
+// A right power equal to the left power makes the operator right-associative.
+// Comparisons do not associate at all, see `is_comparison`.
+fn infix_binding_power(token: &Token) -> Option<(u8, u8)> {
+    match token {
+        Token::Pipe => Some((1, 1)),
+        Token::Comma => Some((2, 3)),
+        Token::Alternative => Some((4, 4)),
+        Token::Assign
+        | Token::UpdateAssign
+        | Token::PlusAssign
+        | Token::MinusAssign
+        | Token::StarAssign
+        | Token::SlashAssign
+        | Token::PercentAssign
+        | Token::AlternativeAssign => Some((6, 7)),
+        Token::Ident(name) if name == "or" => Some((8, 9)),
+        Token::Ident(name) if name == "and" => Some((10, 11)),
+        token if is_comparison(token) => Some((12, 13)),
+        Token::Plus | Token::Minus => Some((14, 15)),
+        Token::Star | Token::Slash | Token::Percent => Some((16, 17)),
+        _ => None,
     }
+}
+
+// Comparisons cannot be chained: `1 < 2 == true` is a syntax error, as in jq
+fn is_comparison(token: &Token) -> bool {
+    matches!(
+        token,
+        Token::Eq | Token::Ne | Token::Lt | Token::Le | Token::Gt | Token::Ge
+    )
+}
+
+fn assign_op(token: &Token) -> Option<AssignOp> {
+    let op = match token {
+        Token::Assign => AssignOp::Set,
+        Token::UpdateAssign => AssignOp::Update,
+        Token::PlusAssign => AssignOp::Arithmetic(BinOp::Add),
+        Token::MinusAssign => AssignOp::Arithmetic(BinOp::Sub),
+        Token::StarAssign => AssignOp::Arithmetic(BinOp::Mul),
+        Token::SlashAssign => AssignOp::Arithmetic(BinOp::Div),
+        Token::PercentAssign => AssignOp::Arithmetic(BinOp::Mod),
+        Token::AlternativeAssign => AssignOp::Alternative,
+        _ => return None,
+    };
+    Some(op)
+}
+
+fn binary_op(token: &Token) -> BinOp {
+    match token {
+        Token::Plus => BinOp::Add,
+        Token::Minus => BinOp::Sub,
+        Token::Star => BinOp::Mul,
+        Token::Slash => BinOp::Div,
+        Token::Percent => BinOp::Mod,
+        Token::Eq => BinOp::Eq,
+        Token::Ne => BinOp::Ne,
+        Token::Lt => BinOp::Lt,
+        Token::Le => BinOp::Le,
+        Token::Gt => BinOp::Gt,
+        Token::Ge => BinOp::Ge,
+        _ => unreachable!("operator without binding power"),
+    }
+}
+
+// Minimum binding power that stops before `,` and `|`, used where commas
+// separate items such as the entries of an object
+const NO_COMMA_BP: u8 = 3;
+
+// Append a path suffix to a term; suffixes on `.` stand on their own
+fn chain(term: FilterFn, suffix: FilterFn) -> FilterFn {
+    match term {
+        FilterFn::Identity => suffix,
+        term => FilterFn::Pipe(Box::new(term), Box::new(suffix)),
+    }
+}
+
+fn negate(num: &Number) -> Value {
+    match num.as_i64() {
+        Some(i) => Value::Number(Number::from(-i)),
+        None => Number::from_f64(-num.as_f64().unwrap_or(0.0))
+            .map(Value::Number)
+            .unwrap_or(Value::Null),
+    }
+}
+
+// `..`, every value in the input from the top down
+fn recurse_values() -> FilterFn {
+    FilterFn::Recurse {
+        f: Box::new(FilterFn::Try(Box::new(FilterFn::ArrayIterator))),
+        cond: None,
+    }
+}
+
+// Integer literal usable as a fixed index or slice bound
+fn literal_integer(filter: &FilterFn) -> Option<i64> {
+    match filter {
+        FilterFn::Literal(Value::Number(num)) => num.as_i64(),
+        _ => None,
+    }
+}
+
+// `target[index]`, using the literal forms when the index is a constant
+fn index_suffix(target: FilterFn, index: FilterFn) -> FilterFn {
+    if let FilterFn::Literal(Value::String(key)) = &index {
+        return chain(target, FilterFn::KeyFilter(key.clone()));
+    }
+    if let Some(i) = literal_integer(&index) {
+        return chain(target, FilterFn::ArrayIndex(i));
+    }
+    FilterFn::Index {
+        target: Box::new(target),
+        index: Box::new(index),
+    }
+}
 
-    // Parse the input string into a sequence of filters
-    pub fn parse(&self, input: &str) -> Result<Vec<FilterFn>, ParseError> {
-        let mut filters: Vec<FilterFn> = Vec::new();
+// `target[start:end]`, using the literal form when both bounds are constants
+fn slice_suffix(target: FilterFn, start: Option<FilterFn>, end: Option<FilterFn>) -> FilterFn {
+    let literal_start = match &start {
+        Some(filter) => literal_integer(filter),
+        None => Some(0),
+    };
+    let literal_end = match &end {
+        Some(filter) => literal_integer(filter).map(Some),
+        None => Some(None),
+    };
+    if let (Some(start), Some(end)) = (literal_start, literal_end) {
+        return chain(target, FilterFn::ArraySlice { start, end });
+    }
+    FilterFn::Slice {
+        target: Box::new(target),
+        start: start.map(Box::new),
+        end: end.map(Box::new),
+    }
+}
+
+// Recursive-descent parser over the token stream of a single filter string
+struct Parser {
+    tokens: Vec<Spanned>,
+    pos: usize,
+    end: usize,
+    source: String,
+    // variables bound by the enclosing `as` patterns, innermost last
+    vars: Vec<String>,
+    // functions in scope as (name, arity, name at run time), innermost last;
+    // members of imported modules run under a `module::` prefix
+    funcs: Vec<(String, usize, String)>,
+    // prefix given to the top-level definitions of the module being parsed
+    prefix: String,
+    library_paths: Vec<PathBuf>,
+}
+
+impl Parser {
+    fn new(source: &str, prefix: &str, library_paths: &[PathBuf]) -> Result<Parser, ParseError> {
+        Ok(Parser {
+            tokens: Lexer::new(source).tokenize()?,
+            pos: 0,
+            end: source.len(),
+            source: source.to_string(),
+            vars: Vec::new(),
+            funcs: Vec::new(),
+            prefix: prefix.to_string(),
+            library_paths: library_paths.to_vec(),
+        })
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|spanned| &spanned.token)
+    }
+
+    fn next(&mut self) -> Result<Spanned, ParseError> {
+        match self.tokens.get(self.pos) {
+            Some(spanned) => {
+                self.pos += 1;
+                Ok(spanned.clone())
+            }
+            None => Err(ParseError::UnexpectedEnd { offset: self.end }),
+        }
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), ParseError> {
+        let spanned = self.next()?;
+        if spanned.token == expected {
+            Ok(())
+        } else {
+            Err(self.unexpected(&spanned))
+        }
+    }
+
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Ident(name)) if name == keyword)
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), ParseError> {
+        let spanned = self.next()?;
+        match &spanned.token {
+            Token::Ident(name) if name == keyword => Ok(()),
+            _ => Err(self.unexpected(&spanned)),
+        }
+    }
+
+    fn unexpected(&self, spanned: &Spanned) -> ParseError {
+        ParseError::UnexpectedToken {
+            offset: spanned.offset,
+            token: spanned.token.clone(),
+        }
+    }
+
+    // Lowest precedence level: a full pipeline
+    fn parse_pipe(&mut self) -> Result<FilterFn, ParseError> {
+        self.parse_expr(0)
+    }
+
+    // Precedence climbing over the infix operators
+    fn parse_expr(&mut self, min_bp: u8) -> Result<FilterFn, ParseError> {
+        if self.peek_keyword("def") {
+            // a definition is visible in the rest of the pipeline that follows it
+            self.pos += 1;
+            let scope = self.funcs.len();
+            let def = self.parse_def("")?;
+            let rest = self.parse_pipe()?;
+            self.funcs.truncate(scope);
+            return Ok(define_all(vec![def], rest));
+        }
+        let mut lhs = self.parse_postfix()?;
+        if self.peek_keyword("as") {
+            self.pos += 1;
+            return self.parse_binding(lhs);
+        }
+        while let Some(token) = self.peek() {
+            let (left_bp, right_bp) = match infix_binding_power(token) {
+                Some(bp) => bp,
+                None => break,
+            };
+            if left_bp < min_bp {
+                break;
+            }
+            let op = self.next()?.token;
+            let rhs = self.parse_expr(right_bp)?;
+            if is_comparison(&op) {
+                if let Some(next) = self
+                    .tokens
+                    .get(self.pos)
+                    .filter(|s| is_comparison(&s.token))
+                {
+                    return Err(self.unexpected(next));
+                }
+            }
+            if let Some(op) = assign_op(&op) {
+                lhs = FilterFn::Assign {
+                    op,
+                    lhs: Box::new(lhs),
+                    rhs: Box::new(rhs),
+                };
+                continue;
+            }
+            lhs = match op {
+                Token::Pipe => FilterFn::Pipe(Box::new(lhs), Box::new(rhs)),
+                Token::Comma => FilterFn::Comma(Box::new(lhs), Box::new(rhs)),
+                Token::Alternative => FilterFn::Alternative(Box::new(lhs), Box::new(rhs)),
+                Token::Ident(name) if name == "and" => FilterFn::And(Box::new(lhs), Box::new(rhs)),
+                Token::Ident(name) if name == "or" => FilterFn::Or(Box::new(lhs), Box::new(rhs)),
+                op => FilterFn::Binary(binary_op(&op), Box::new(lhs), Box::new(rhs)),
+            };
+        }
+        Ok(lhs)
+    }
+
+    // A term followed by any chain of path suffixes, e.g. `.a."b-c"[0][]`
+    fn parse_postfix(&mut self) -> Result<FilterFn, ParseError> {
+        let mut term = self.parse_term()?;
+        loop {
+            match self.peek() {
+                Some(Token::Field(name)) => {
+                    let key = FilterFn::KeyFilter(name.clone());
+                    self.pos += 1;
+                    term = chain(term, key);
+                }
+                Some(Token::Dot) => match self.tokens.get(self.pos + 1).map(|s| &s.token) {
+                    Some(Token::Str(key)) => {
+                        let key = FilterFn::KeyFilter(key.clone());
+                        self.pos += 2;
+                        term = chain(term, key);
+                    }
+                    Some(Token::LBracket) => {
+                        self.pos += 2;
+                        term = self.parse_brackets(term)?;
+                    }
+                    _ => break,
+                },
+                Some(Token::LBracket) => {
+                    self.pos += 1;
+                    term = self.parse_brackets(term)?;
+                }
+                // `term?` suppresses any error raised while evaluating the term
+                Some(Token::Question) => {
+                    self.pos += 1;
+                    term = FilterFn::Try(Box::new(term));
+                }
+                _ => break,
+            }
+        }
+        Ok(term)
+    }
+
+    fn parse_term(&mut self) -> Result<FilterFn, ParseError> {
+        let spanned = self.next()?;
+        match spanned.token {
+            Token::Dot => match self.peek() {
+                Some(Token::LBracket) => {
+                    self.pos += 1;
+                    self.parse_brackets(FilterFn::Identity)
+                }
+                Some(Token::Str(key)) => {
+                    let key = key.clone();
+                    self.pos += 1;
+                    Ok(FilterFn::KeyFilter(key))
+                }
+                _ => Ok(FilterFn::Identity),
+            },
+            Token::Field(name) => Ok(FilterFn::KeyFilter(name)),
+            Token::Variable(name) => self.variable(name, spanned.offset),
+            Token::Number(num) => Ok(FilterFn::Literal(Value::Number(num))),
+            Token::Str(s) => Ok(FilterFn::Literal(Value::String(s))),
+            Token::Template(parts) => self.parse_template(parts, "text", spanned.offset),
+            Token::Format(name) => {
+                if !builtins::is_format(&name) {
+                    return Err(ParseError::UnknownFormat {
+                        offset: spanned.offset,
+                        name,
+                    });
+                }
+                // `@name "..."` writes each interpolated value in that format
+                match self.peek().cloned() {
+                    Some(Token::Str(s)) => {
+                        self.pos += 1;
+                        Ok(FilterFn::Literal(Value::String(s)))
+                    }
+                    Some(Token::Template(parts)) => {
+                        let offset = self.tokens[self.pos].offset;
+                        self.pos += 1;
+                        self.parse_template(parts, &name, offset)
+                    }
+                    _ => Ok(FilterFn::Format(name)),
+                }
+            }
+            Token::Minus => match self.peek() {
+                Some(Token::Number(num)) => {
+                    let literal = FilterFn::Literal(negate(num));
+                    self.pos += 1;
+                    Ok(literal)
+                }
+                _ => Ok(FilterFn::Neg(Box::new(self.parse_postfix()?))),
+            },
+            Token::LParen => {
+                let inner = self.parse_pipe()?;
+                self.expect(Token::RParen)?;
+                Ok(inner)
+            }
+            Token::LBracket => {
+                if self.peek() == Some(&Token::RBracket) {
+                    self.pos += 1;
+                    return Ok(FilterFn::ArrayConstruct(None));
+                }
+                let inner = self.parse_pipe()?;
+                self.expect(Token::RBracket)?;
+                Ok(FilterFn::ArrayConstruct(Some(Box::new(inner))))
+            }
+            Token::LBrace => self.parse_object(),
+            Token::Ident(name) if name == "try" => {
+                let body = self.parse_postfix()?;
+                if self.peek_keyword("catch") {
+                    self.pos += 1;
+                    let handler = self.parse_postfix()?;
+                    return Ok(FilterFn::TryCatch(Box::new(body), Box::new(handler)));
+                }
+                Ok(FilterFn::Try(Box::new(body)))
+            }
+            Token::Ident(name) if name == "if" => self.parse_if(),
+            Token::Ident(name) if name == "reduce" || name == "foreach" => {
+                self.parse_fold(name == "foreach")
+            }
+            Token::DotDot => Ok(recurse_values()),
+            Token::Ident(name) => self.parse_function(name, spanned.offset),
+            _ => Err(self.unexpected(&spanned)),
+        }
+    }
+
+    // `import "path" as name;` and `include "path";` at the start of a program
+    // or module, returning the definitions they load
+    fn parse_directives(&mut self) -> Result<Vec<Definition>, ParseError> {
+        let mut definitions = Vec::new();
+        loop {
+            let alias = if self.peek_keyword("import") {
+                true
+            } else if self.peek_keyword("include") {
+                false
+            } else {
+                return Ok(definitions);
+            };
+            self.pos += 1;
+            let spanned = self.next()?;
+            let Token::Str(name) = spanned.token.clone() else {
+                return Err(self.unexpected(&spanned));
+            };
+            let visible_prefix = if alias {
+                self.expect_keyword("as")?;
+                let spanned = self.next()?;
+                match spanned.token {
+                    Token::Ident(alias) if !alias.contains("::") => format!("{}::", alias),
+                    _ => return Err(self.unexpected(&spanned)),
+                }
+            } else {
+                String::new()
+            };
+            self.expect(Token::Semicolon)?;
+            let path =
+                find_module(&self.library_paths, &name).ok_or(ParseError::ModuleNotFound {
+                    offset: spanned.offset,
+                    name: name.clone(),
+                })?;
+            let in_module = |source| ParseError::Module {
+                path: path.display().to_string(),
+                source: Box::new(source),
+            };
+            let text = fs::read_to_string(&path).map_err(|_| ParseError::ModuleNotFound {
+                offset: spanned.offset,
+                name,
+            })?;
+            let prefix = format!("{}{}", self.prefix, visible_prefix);
+            let mut module = Parser::new(&text, &prefix, &self.library_paths).map_err(in_module)?;
+            let loaded = module.parse_module().map_err(in_module)?;
+            for def in &loaded {
+                // members of modules the module imports itself stay private to it
+                let name = &def.name[prefix.len()..];
+                if !name.contains("::") {
+                    let visible = format!("{}{}", visible_prefix, name);
+                    self.funcs
+                        .push((visible, def.params.len(), def.name.clone()));
+                }
+            }
+            definitions.extend(loaded);
+        }
+    }
 
-        let input = input.trim();
+    // A module file: directives followed by definitions only
+    fn parse_module(&mut self) -> Result<Vec<Definition>, ParseError> {
+        let mut definitions = self.parse_directives()?;
+        while self.peek_keyword("def") {
+            self.pos += 1;
+            let prefix = self.prefix.clone();
+            definitions.push(self.parse_def(&prefix)?);
+        }
+        match self.tokens.get(self.pos) {
+            Some(spanned) => Err(self.unexpected(spanned)),
+            None => Ok(definitions),
+        }
+    }
 
-        // Handle pipes
-        let pipe_regex = Regex::new(r"\|").unwrap();
-        if pipe_regex.is_match(input) {
-            let filter_strings: Vec<&str> = input.split('|').collect();
-            let mut pipe_filters: Vec<FilterFn> = Vec::new();
-            for filter_str in filter_strings {
-                let parsed_filters = self.parse(filter_str.trim())?;
-                pipe_filters.extend(parsed_filters);
+    // `name(params): body;` after `def`, leaving the name in scope for the
+    // caller to remove; `prefix` is prepended to the name at run time
+    fn parse_def(&mut self, prefix: &str) -> Result<Definition, ParseError> {
+        let spanned = self.next()?;
+        let name = match spanned.token {
+            Token::Ident(name) if !name.contains("::") => name,
+            _ => return Err(self.unexpected(&spanned)),
+        };
+        let mut params = Vec::new();
+        if self.peek() == Some(&Token::LParen) {
+            self.pos += 1;
+            loop {
+                let spanned = self.next()?;
+                params.push(match spanned.token {
+                    Token::Ident(name) if !name.contains("::") => Param::Filter(name),
+                    Token::Variable(name) => Param::Value(name),
+                    _ => return Err(self.unexpected(&spanned)),
+                });
+                let spanned = self.next()?;
+                match spanned.token {
+                    Token::Semicolon => continue,
+                    Token::RParen => break,
+                    _ => return Err(self.unexpected(&spanned)),
+                }
             }
-            return Ok(pipe_filters);
         }
+        self.expect(Token::Colon)?;
+        let runtime = format!("{}{}", prefix, name);
+        self.funcs.push((name, params.len(), runtime.clone()));
+        let (func_scope, var_scope) = (self.funcs.len(), self.vars.len());
+        for param in &params {
+            let name = match param {
+                Param::Filter(name) => name,
+                Param::Value(name) => {
+                    self.vars.push(name.clone());
+                    name
+                }
+            };
+            self.funcs.push((name.clone(), 0, name.clone()));
+        }
+        let body = self.parse_pipe()?;
+        self.expect(Token::Semicolon)?;
+        self.funcs.truncate(func_scope);
+        self.vars.truncate(var_scope);
+        Ok(Definition {
+            name: runtime,
+            params,
+            body,
+        })
+    }
 
-        // Identity filter: Just a dot "."
-        if input == "." {
-            filters.push(FilterFn::Identity);
-            return Ok(filters);
+    // A reference to `$name`, which must be bound by an enclosing pattern
+    // unless it is one of the built-in variables
+    fn variable(&self, name: String, offset: usize) -> Result<FilterFn, ParseError> {
+        if self.vars.contains(&name) {
+            return Ok(FilterFn::Variable(name));
+        }
+        match name.as_str() {
+            "ENV" => Ok(FilterFn::Environment),
+            "__loc__" => {
+                let line = self.source[..offset].matches('\n').count() + 1;
+                let mut loc = serde_json::Map::new();
+                loc.insert("file".to_string(), Value::from("<top-level>"));
+                loc.insert("line".to_string(), Value::from(line));
+                Ok(FilterFn::Literal(Value::Object(loc)))
+            }
+            _ => Err(ParseError::UndefinedVariable { offset, name }),
         }
+    }
+
+    // `PATTERN | body` after `source as`; the pattern's variables are only
+    // visible in the body
+    fn parse_binding(&mut self, source: FilterFn) -> Result<FilterFn, ParseError> {
+        let scope = self.vars.len();
+        let pattern = self.parse_pattern()?;
+        self.expect(Token::Pipe)?;
+        let body = self.parse_pipe()?;
+        self.vars.truncate(scope);
+        Ok(FilterFn::Bind {
+            source: Box::new(source),
+            pattern,
+            body: Box::new(body),
+        })
+    }
 
-        // Object-Identifier Index: .key
-        let object_index_regex = Regex::new(r"^\.\w+$").unwrap();
-        if object_index_regex.is_match(input) {
-            let key = input.trim_start_matches('.').to_string();
-            filters.push(FilterFn::KeyFilter(key));
-            return Ok(filters);
+    // `$name`, `[p, ...]` or `{key: p, $name, ...}`, bringing each variable
+    // into scope as soon as it is declared
+    fn parse_pattern(&mut self) -> Result<Pattern, ParseError> {
+        let spanned = self.next()?;
+        match spanned.token {
+            Token::Variable(name) => {
+                self.vars.push(name.clone());
+                Ok(Pattern::Variable(name))
+            }
+            Token::LBracket => {
+                let mut items = Vec::new();
+                loop {
+                    items.push(self.parse_pattern()?);
+                    let spanned = self.next()?;
+                    match spanned.token {
+                        Token::Comma => continue,
+                        Token::RBracket => return Ok(Pattern::Array(items)),
+                        _ => return Err(self.unexpected(&spanned)),
+                    }
+                }
+            }
+            Token::LBrace => {
+                let mut entries = Vec::new();
+                loop {
+                    entries.push(self.parse_object_pattern()?);
+                    let spanned = self.next()?;
+                    match spanned.token {
+                        Token::Comma => continue,
+                        Token::RBrace => return Ok(Pattern::Object(entries)),
+                        _ => return Err(self.unexpected(&spanned)),
+                    }
+                }
+            }
+            _ => Err(self.unexpected(&spanned)),
         }
+    }
 
-        // Array Index: .[index]
-        let array_index_regex = Regex::new(r"^\.\[(\d+)\]$").unwrap();
-        if let Some(caps) = array_index_regex.captures(input) {
-            let index: usize = caps[1].parse().unwrap();
-            filters.push(FilterFn::ArrayIndex(index));
-            return Ok(filters);
+    fn parse_object_pattern(&mut self) -> Result<ObjectPattern, ParseError> {
+        let spanned = self.next()?;
+        let (key, variable) = match spanned.token {
+            Token::Variable(name) => {
+                self.vars.push(name.clone());
+                (FilterFn::Literal(Value::String(name.clone())), Some(name))
+            }
+            Token::Ident(name) | Token::Str(name) => (FilterFn::Literal(Value::String(name)), None),
+            Token::LParen => {
+                let key = self.parse_pipe()?;
+                self.expect(Token::RParen)?;
+                (key, None)
+            }
+            _ => return Err(self.unexpected(&spanned)),
+        };
+        // only `{$name}` may leave out the pattern
+        let pattern = if variable.is_none() || self.peek() == Some(&Token::Colon) {
+            self.expect(Token::Colon)?;
+            Some(self.parse_pattern()?)
+        } else {
+            None
+        };
+        Ok(ObjectPattern {
+            key,
+            variable,
+            pattern,
+        })
+    }
+
+    // `reduce SOURCE as PATTERN (INIT; UPDATE)` or
+    // `foreach SOURCE as PATTERN (INIT; UPDATE[; EXTRACT])` after the keyword;
+    // the pattern's variables are visible in the update and extract only
+    fn parse_fold(&mut self, foreach: bool) -> Result<FilterFn, ParseError> {
+        let source = self.parse_postfix()?;
+        self.expect_keyword("as")?;
+        let scope = self.vars.len();
+        let pattern = self.parse_pattern()?;
+        let bound = self.vars.split_off(scope);
+        self.expect(Token::LParen)?;
+        let init = self.parse_pipe()?;
+        self.vars.extend(bound);
+        self.expect(Token::Semicolon)?;
+        let update = self.parse_pipe()?;
+        let extract = if foreach && self.peek() == Some(&Token::Semicolon) {
+            self.pos += 1;
+            Some(self.parse_pipe()?)
+        } else {
+            None
+        };
+        self.expect(Token::RParen)?;
+        self.vars.truncate(scope);
+        let (source, init, update) = (Box::new(source), Box::new(init), Box::new(update));
+        if foreach {
+            Ok(FilterFn::Foreach {
+                source,
+                pattern,
+                init,
+                update,
+                extract: extract.map(Box::new),
+            })
+        } else {
+            Ok(FilterFn::Reduce {
+                source,
+                pattern,
+                init,
+                update,
+            })
         }
+    }
 
-        // Array Slice: .[start:end]
-        let array_slice_regex = Regex::new(r"^\.\[(\d*):(\d*)\]$").unwrap();
-        if let Some(caps) = array_slice_regex.captures(input) {
-            let start = caps
-                .get(1)
-                .map_or(0, |m| m.as_str().parse::<usize>().unwrap_or(0));
-            let end = caps.get(2).and_then(|m| m.as_str().parse::<usize>().ok());
-            filters.push(FilterFn::ArraySlice { start, end });
-            return Ok(filters);
+    // `if c then a elif c2 then b else d end` after the `if`; `elif` chains
+    // become nested conditionals and a missing `else` passes the input through
+    fn parse_if(&mut self) -> Result<FilterFn, ParseError> {
+        let cond = self.parse_pipe()?;
+        self.expect_keyword("then")?;
+        let then_branch = self.parse_pipe()?;
+        let spanned = self.next()?;
+        let else_branch = match &spanned.token {
+            Token::Ident(name) if name == "elif" => Some(self.parse_if()?),
+            Token::Ident(name) if name == "else" => {
+                let else_branch = self.parse_pipe()?;
+                self.expect_keyword("end")?;
+                Some(else_branch)
+            }
+            Token::Ident(name) if name == "end" => None,
+            _ => return Err(self.unexpected(&spanned)),
+        };
+        Ok(FilterFn::If {
+            cond: Box::new(cond),
+            then_branch: Box::new(then_branch),
+            else_branch: else_branch.map(Box::new),
+        })
+    }
+
+    // The contents of `[...]` after the opening bracket: `[]`, `[e]` or `[e:e]`,
+    // applied as a suffix to `target`
+    fn parse_brackets(&mut self, target: FilterFn) -> Result<FilterFn, ParseError> {
+        if self.peek() == Some(&Token::RBracket) {
+            self.pos += 1;
+            return Ok(chain(target, FilterFn::ArrayIterator));
+        }
+        let start = if self.peek() == Some(&Token::Colon) {
+            None
+        } else {
+            Some(self.parse_pipe()?)
+        };
+        if self.peek() == Some(&Token::Colon) {
+            self.pos += 1;
+            let end = if self.peek() == Some(&Token::RBracket) {
+                None
+            } else {
+                Some(self.parse_pipe()?)
+            };
+            self.expect(Token::RBracket)?;
+            return Ok(slice_suffix(target, start, end));
+        }
+        self.expect(Token::RBracket)?;
+        match start {
+            Some(index) => Ok(index_suffix(target, index)),
+            None => unreachable!("an empty index is handled as an iterator"),
         }
+    }
 
-        // Array Iterator: .[]
-        if input == ".[]" {
-            filters.push(FilterFn::ArrayIterator);
-            return Ok(filters);
+    // The entries of `{...}` after the opening brace
+    fn parse_object(&mut self) -> Result<FilterFn, ParseError> {
+        let mut entries = Vec::new();
+        if self.peek() == Some(&Token::RBrace) {
+            self.pos += 1;
+            return Ok(FilterFn::ObjectConstruct(entries));
+        }
+        loop {
+            let spanned = self.next()?;
+            // `{name}` and `{"name"}` are shorthand for `{name: .name}`
+            let (key, shorthand) = match spanned.token {
+                Token::Ident(name) | Token::Str(name) => (
+                    FilterFn::Literal(Value::String(name.clone())),
+                    Some(FilterFn::KeyFilter(name)),
+                ),
+                // `{$name}` is shorthand for `{name: $name}`
+                Token::Variable(name) => (
+                    FilterFn::Literal(Value::String(name.clone())),
+                    Some(self.variable(name, spanned.offset)?),
+                ),
+                Token::LParen => {
+                    let key = self.parse_pipe()?;
+                    self.expect(Token::RParen)?;
+                    (key, None)
+                }
+                Token::Template(parts) => {
+                    (self.parse_template(parts, "text", spanned.offset)?, None)
+                }
+                _ => return Err(self.unexpected(&spanned)),
+            };
+            let value = match (self.peek(), shorthand) {
+                (Some(Token::Colon), _) => {
+                    self.pos += 1;
+                    self.parse_object_value()?
+                }
+                (_, Some(shorthand)) => shorthand,
+                (_, None) => {
+                    let spanned = self.next()?;
+                    return Err(self.unexpected(&spanned));
+                }
+            };
+            entries.push((key, value));
+            let spanned = self.next()?;
+            match spanned.token {
+                Token::Comma => continue,
+                Token::RBrace => return Ok(FilterFn::ObjectConstruct(entries)),
+                _ => return Err(self.unexpected(&spanned)),
+            }
         }
+    }
 
-        // Built-in functions
-        if input == "add" {
-            filters.push(FilterFn::Add);
-            return Ok(filters);
+    // An object value may be piped but cannot contain a bare `,`
+    fn parse_object_value(&mut self) -> Result<FilterFn, ParseError> {
+        let value = self.parse_expr(NO_COMMA_BP)?;
+        if self.peek() == Some(&Token::Pipe) {
+            self.pos += 1;
+            let rest = self.parse_object_value()?;
+            return Ok(FilterFn::Pipe(Box::new(value), Box::new(rest)));
         }
+        Ok(value)
+    }
 
-        if input == "length" {
-            filters.push(FilterFn::Length);
-            return Ok(filters);
+    // The interpolations of a string literal, parsed in the current scope from
+    // the tokens the lexer collected for each of them
+    fn parse_template(
+        &mut self,
+        parts: Vec<TemplatePart>,
+        format: &str,
+        offset: usize,
+    ) -> Result<FilterFn, ParseError> {
+        let mut parsed = Vec::new();
+        for part in parts {
+            match part {
+                TemplatePart::Literal(text) => parsed.push(StringPart::Literal(text)),
+                TemplatePart::Expr(tokens) => {
+                    let tokens = std::mem::replace(&mut self.tokens, tokens);
+                    let pos = std::mem::replace(&mut self.pos, 0);
+                    let end = std::mem::replace(&mut self.end, offset);
+                    let expr = self
+                        .parse_pipe()
+                        .and_then(|expr| match self.tokens.get(self.pos) {
+                            Some(spanned) => Err(self.unexpected(spanned)),
+                            None => Ok(expr),
+                        });
+                    self.tokens = tokens;
+                    self.pos = pos;
+                    self.end = end;
+                    parsed.push(StringPart::Expr(expr?));
+                }
+            }
         }
+        Ok(FilterFn::Interpolate {
+            format: format.to_string(),
+            parts: parsed,
+        })
+    }
 
-        // Del function: del(...)
-        let del_regex = Regex::new(r"^del\((.+)\)$").unwrap();
-        if let Some(caps) = del_regex.captures(input) {
-            let arg = caps.get(1).unwrap().as_str().to_string();
-            let arg_filters = self.parse(&arg)?;
-            if arg_filters.len() != 1 {
-                return Err(ParseError::InvalidString);
+    // Functions called by name: user definitions first, then the built-ins,
+    // with arguments in parentheses
+    fn parse_function(&mut self, name: String, offset: usize) -> Result<FilterFn, ParseError> {
+        let mut args = self.parse_args()?;
+        let defined = self
+            .funcs
+            .iter()
+            .rev()
+            .find(|(n, arity, _)| *n == name && *arity == args.len());
+        if let Some((_, _, runtime)) = defined {
+            return Ok(FilterFn::Call {
+                name: runtime.clone(),
+                args: args.into_iter().map(Rc::new).collect(),
+            });
+        }
+        match (name.as_str(), args.len()) {
+            ("null", 0) => Ok(FilterFn::Literal(Value::Null)),
+            ("true", 0) => Ok(FilterFn::Literal(Value::Bool(true))),
+            ("false", 0) => Ok(FilterFn::Literal(Value::Bool(false))),
+            ("empty", 0) => Ok(FilterFn::Empty),
+            ("input", 0) => Ok(FilterFn::Input),
+            ("inputs", 0) => Ok(FilterFn::Inputs),
+            ("env", 0) => Ok(FilterFn::Environment),
+            ("tostream", 0) => Ok(FilterFn::ToStream),
+            ("fromstream", 1) => Ok(FilterFn::FromStream(Box::new(args.remove(0)))),
+            ("truncate_stream", 1) => Ok(FilterFn::TruncateStream(Box::new(args.remove(0)))),
+            ("error", 0) => Ok(FilterFn::Error(None)),
+            ("error", 1) => Ok(FilterFn::Error(Some(Box::new(args.remove(0))))),
+            ("halt", 0) => Ok(FilterFn::Halt),
+            ("halt_error", 0 | 1) => Ok(FilterFn::HaltError(args.pop().map(Box::new))),
+            ("limit", 2) => {
+                let n = args.remove(0);
+                Ok(FilterFn::Limit(Box::new(n), Box::new(args.remove(0))))
+            }
+            ("first", 0) => Ok(FilterFn::ArrayIndex(0)),
+            ("last", 0) => Ok(FilterFn::ArrayIndex(-1)),
+            ("first", 1) => Ok(FilterFn::First(Box::new(args.remove(0)))),
+            ("last", 1) => Ok(FilterFn::Last(Box::new(args.remove(0)))),
+            ("range", 1..=3) => {
+                let by = (args.len() == 3).then(|| Box::new(args.remove(2)));
+                let upto = Box::new(args.pop().expect("range has an upper bound"));
+                let from = args.pop().map(Box::new);
+                Ok(FilterFn::Range { from, upto, by })
+            }
+            ("while", 2) => {
+                let cond = args.remove(0);
+                Ok(FilterFn::While(Box::new(cond), Box::new(args.remove(0))))
+            }
+            ("until", 2) => {
+                let cond = args.remove(0);
+                Ok(FilterFn::Until(Box::new(cond), Box::new(args.remove(0))))
+            }
+            ("recurse", 0) => Ok(recurse_values()),
+            ("recurse", 1 | 2) => {
+                let f = Box::new(args.remove(0));
+                let cond = args.pop().map(Box::new);
+                Ok(FilterFn::Recurse { f, cond })
+            }
+            ("add", 0) => Ok(FilterFn::Add),
+            ("add", 1) => Ok(FilterFn::AddOf(Box::new(args.remove(0)))),
+            ("any" | "all", 0..=2) => {
+                let cond = args.pop().unwrap_or(FilterFn::Identity);
+                let generator = args.pop().unwrap_or(FilterFn::ArrayIterator);
+                Ok(FilterFn::Quantify {
+                    all: name == "all",
+                    generator: Box::new(generator),
+                    cond: Box::new(cond),
+                })
+            }
+            // the unit is not used, as in jq
+            ("dateadd" | "datesub", 2) => {
+                let op = if name == "dateadd" {
+                    BinOp::Add
+                } else {
+                    BinOp::Sub
+                };
+                Ok(FilterFn::Binary(
+                    op,
+                    Box::new(FilterFn::Identity),
+                    Box::new(args.remove(1)),
+                ))
+            }
+            ("infinite", 0) => Ok(FilterFn::Literal(ops::float_value(f64::INFINITY))),
+            ("nan", 0) => Ok(FilterFn::Literal(ops::float_value(f64::NAN))),
+            ("combinations", 0 | 1) => Ok(FilterFn::Combinations(args.pop().map(Box::new))),
+            ("length", 0) => Ok(FilterFn::Length),
+            ("not", 0) => Ok(FilterFn::Not),
+            ("del", 1) => Ok(FilterFn::Del(Box::new(args.remove(0)))),
+            ("select", 1) => Ok(FilterFn::Select(Box::new(args.remove(0)))),
+            ("map", 1) => Ok(FilterFn::Map(Box::new(args.remove(0)))),
+            ("map_values", 1) => Ok(FilterFn::MapValues(Box::new(args.remove(0)))),
+            ("has", 1) => Ok(FilterFn::Has(Box::new(args.remove(0)))),
+            ("keys", 0) => Ok(FilterFn::Keys),
+            ("keys_unsorted", 0) => Ok(FilterFn::KeysUnsorted),
+            ("values", 0) => Ok(FilterFn::Values),
+            ("scalars", 0) => Ok(FilterFn::Scalars),
+            ("type", 0) => Ok(FilterFn::Type),
+            ("objects", 0) => Ok(FilterFn::OfTypes(&["object"])),
+            ("arrays", 0) => Ok(FilterFn::OfTypes(&["array"])),
+            ("strings", 0) => Ok(FilterFn::OfTypes(&["string"])),
+            ("numbers", 0) => Ok(FilterFn::OfTypes(&["number"])),
+            ("booleans", 0) => Ok(FilterFn::OfTypes(&["boolean"])),
+            ("nulls", 0) => Ok(FilterFn::OfTypes(&["null"])),
+            ("iterables", 0) => Ok(FilterFn::OfTypes(&["array", "object"])),
+            ("path", 1) => Ok(FilterFn::Path(Box::new(args.remove(0)))),
+            ("paths", 0 | 1) => Ok(FilterFn::Paths(args.pop().map(Box::new))),
+            ("leaf_paths", 0) => Ok(FilterFn::Paths(Some(Box::new(FilterFn::Scalars)))),
+            ("getpath", 1) => Ok(FilterFn::GetPath(Box::new(args.remove(0)))),
+            ("setpath", 2) => {
+                let path = Box::new(args.remove(0));
+                Ok(FilterFn::SetPath(path, Box::new(args.remove(0))))
+            }
+            ("delpaths", 1) => Ok(FilterFn::DelPaths(Box::new(args.remove(0)))),
+            ("to_entries", 0) => Ok(FilterFn::ToEntries),
+            ("from_entries", 0) => Ok(FilterFn::FromEntries),
+            ("with_entries", 1) => Ok(FilterFn::WithEntries(Box::new(args.remove(0)))),
+            ("sub" | "gsub", 2 | 3) => {
+                let regex = Box::new(args.remove(0));
+                let replacement = Box::new(args.remove(0));
+                Ok(FilterFn::Sub {
+                    regex,
+                    replacement,
+                    flags: args.pop().map(Box::new),
+                    global: name == "gsub",
+                })
+            }
+            _ => {
+                if let (Some(builtin), 0) = (Builtin::from_name(&name), args.len()) {
+                    return Ok(FilterFn::Builtin(builtin));
+                }
+                if let (Some(builtin), 1) = (BuiltinArg::from_name(&name), args.len()) {
+                    return Ok(FilterFn::BuiltinArg(builtin, Box::new(args.remove(0))));
+                }
+                if let Some(op) = MathFn::from_name(&name, args.len()) {
+                    return Ok(FilterFn::Math(op, args));
+                }
+                if let (Some(op), 1) = (ByOp::from_name(&name), args.len()) {
+                    return Ok(FilterFn::By(op, Box::new(args.remove(0))));
+                }
+                if let (Some(op), 1 | 2) = (RegexOp::from_name(&name), args.len()) {
+                    let regex = Box::new(args.remove(0));
+                    let flags = args.pop().map(Box::new);
+                    return Ok(FilterFn::Regex { op, regex, flags });
+                }
+                Err(ParseError::UnknownFunction {
+                    offset,
+                    name: format!("{}/{}", name, args.len()),
+                })
             }
-            filters.push(FilterFn::Del(Box::new(arg_filters[0].clone())));
-            return Ok(filters);
         }
+    }
 
-        Err(ParseError::InvalidString)
+    // Optional `(a; b; ...)` argument list of a function call
+    fn parse_args(&mut self) -> Result<Vec<FilterFn>, ParseError> {
+        let mut args = Vec::new();
+        if self.peek() != Some(&Token::LParen) {
+            return Ok(args);
+        }
+        self.pos += 1;
+        loop {
+            args.push(self.parse_pipe()?);
+            let spanned = self.next()?;
+            match spanned.token {
+                Token::Semicolon => continue,
+                Token::RParen => return Ok(args),
+                _ => return Err(self.unexpected(&spanned)),
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::filter::FilterFn;
+    use crate::filter::{FilterFn, ObjectPattern, Param, Pattern, StringPart};
+    use serde_json::json;
 
     #[test]
     fn test_parse_identity() {
         let parser = Parse::new();
         let input = ".".to_string();
-        let filters = parser.parse(&input).unwrap();
-        assert_eq!(filters.len(), 1);
-        match filters[0] {
+        let filter = parser.parse(&input).unwrap();
+        match filter {
             FilterFn::Identity => {}
             _ => panic!("Expected Identity filter"),
         }
@@ -120,9 +1112,8 @@ mod tests {
     fn test_parse_key_filter() {
         let parser = Parse::new();
         let input = ".key".to_string();
-        let filters = parser.parse(&input).unwrap();
-        assert_eq!(filters.len(), 1);
-        match &filters[0] {
+        let filter = parser.parse(&input).unwrap();
+        match &filter {
             FilterFn::KeyFilter(key) => assert_eq!(key, "key"),
             _ => panic!("Expected KeyFilter"),
         }
@@ -132,9 +1123,8 @@ mod tests {
     fn test_parse_array_index() {
         let parser = Parse::new();
         let input = ".[2]".to_string();
-        let filters = parser.parse(&input).unwrap();
-        assert_eq!(filters.len(), 1);
-        match filters[0] {
+        let filter = parser.parse(&input).unwrap();
+        match filter {
             FilterFn::ArrayIndex(index) => assert_eq!(index, 2),
             _ => panic!("Expected ArrayIndex"),
         }
@@ -144,9 +1134,8 @@ mod tests {
     fn test_parse_array_slice() {
         let parser = Parse::new();
         let input = ".[1:3]".to_string();
-        let filters = parser.parse(&input).unwrap();
-        assert_eq!(filters.len(), 1);
-        match filters[0] {
+        let filter = parser.parse(&input).unwrap();
+        match filter {
             FilterFn::ArraySlice { start, end } => {
                 assert_eq!(start, 1);
                 assert_eq!(end, Some(3));
@@ -159,9 +1148,8 @@ mod tests {
     fn test_parse_array_iterator() {
         let parser = Parse::new();
         let input = ".[]".to_string();
-        let filters = parser.parse(&input).unwrap();
-        assert_eq!(filters.len(), 1);
-        match filters[0] {
+        let filter = parser.parse(&input).unwrap();
+        match filter {
             FilterFn::ArrayIterator => {}
             _ => panic!("Expected ArrayIterator"),
         }
@@ -171,9 +1159,8 @@ mod tests {
     fn test_parse_add_function() {
         let parser = Parse::new();
         let input = "add".to_string();
-        let filters = parser.parse(&input).unwrap();
-        assert_eq!(filters.len(), 1);
-        match filters[0] {
+        let filter = parser.parse(&input).unwrap();
+        match filter {
             FilterFn::Add => {}
             _ => panic!("Expected Add function"),
         }
@@ -183,9 +1170,8 @@ mod tests {
     fn test_parse_length_function() {
         let parser = Parse::new();
         let input = "length".to_string();
-        let filters = parser.parse(&input).unwrap();
-        assert_eq!(filters.len(), 1);
-        match filters[0] {
+        let filter = parser.parse(&input).unwrap();
+        match filter {
             FilterFn::Length => {}
             _ => panic!("Expected Length function"),
         }
@@ -195,9 +1181,8 @@ mod tests {
     fn test_parse_del_function_key() {
         let parser = Parse::new();
         let input = "del(.key)".to_string();
-        let filters = parser.parse(&input).unwrap();
-        assert_eq!(filters.len(), 1);
-        match &filters[0] {
+        let filter = parser.parse(&input).unwrap();
+        match &filter {
             FilterFn::Del(target) => match &**target {
                 FilterFn::KeyFilter(key) => assert_eq!(key, "key"),
                 _ => panic!("Expected KeyFilter inside Del"),
@@ -210,9 +1195,8 @@ mod tests {
     fn test_parse_del_function_array_index() {
         let parser = Parse::new();
         let input = "del(.[1])".to_string();
-        let filters = parser.parse(&input).unwrap();
-        assert_eq!(filters.len(), 1);
-        match &filters[0] {
+        let filter = parser.parse(&input).unwrap();
+        match &filter {
             FilterFn::Del(target) => match &**target {
                 FilterFn::ArrayIndex(index) => assert_eq!(*index, 1),
                 _ => panic!("Expected ArrayIndex inside Del"),
@@ -222,25 +1206,989 @@ mod tests {
     }
 
     #[test]
-    fn test_parse_pipe_operator() {
+    fn test_parse_select_and_map() {
         let parser = Parse::new();
-        let input = ".key | .[0] | .name".to_string();
-        let filters = parser.parse(&input).unwrap();
-        assert_eq!(filters.len(), 3);
-        match &filters[0] {
-            FilterFn::KeyFilter(key) => assert_eq!(key, "key"),
-            _ => panic!("Expected KeyFilter"),
+        let filter = parser.parse("map(select(.ok)) | keys").unwrap();
+        let expected = FilterFn::Pipe(
+            Box::new(FilterFn::Map(Box::new(FilterFn::Select(Box::new(
+                FilterFn::KeyFilter("ok".to_string()),
+            ))))),
+            Box::new(FilterFn::Keys),
+        );
+        assert_eq!(filter, expected);
+    }
+
+    #[test]
+    fn test_parse_builtin_wrong_arity() {
+        let parser = Parse::new();
+        let result = parser.parse("map");
+        assert!(matches!(
+            result,
+            Err(ParseError::UnknownFunction { ref name, .. }) if name == "map/0"
+        ));
+    }
+
+    #[test]
+    fn test_parse_if_elif_else() {
+        let parser = Parse::new();
+        let filter = parser
+            .parse("if .a then 1 elif .b then 2 else 3 end")
+            .unwrap();
+        let expected = FilterFn::If {
+            cond: Box::new(FilterFn::KeyFilter("a".to_string())),
+            then_branch: Box::new(FilterFn::Literal(Value::from(1))),
+            else_branch: Some(Box::new(FilterFn::If {
+                cond: Box::new(FilterFn::KeyFilter("b".to_string())),
+                then_branch: Box::new(FilterFn::Literal(Value::from(2))),
+                else_branch: Some(Box::new(FilterFn::Literal(Value::from(3)))),
+            })),
+        };
+        assert_eq!(filter, expected);
+    }
+
+    #[test]
+    fn test_parse_unterminated_if() {
+        let parser = Parse::new();
+        let result = parser.parse("if . then 1 else 2");
+        assert!(matches!(
+            result,
+            Err(ParseError::UnexpectedEnd { offset: 18 })
+        ));
+    }
+
+    #[test]
+    fn test_parse_try_catch() {
+        let parser = Parse::new();
+        let filter = parser.parse(r#"try error("x") catch . | length"#).unwrap();
+        let expected = FilterFn::Pipe(
+            Box::new(FilterFn::TryCatch(
+                Box::new(FilterFn::Error(Some(Box::new(FilterFn::Literal(
+                    Value::from("x"),
+                ))))),
+                Box::new(FilterFn::Identity),
+            )),
+            Box::new(FilterFn::Length),
+        );
+        assert_eq!(filter, expected);
+    }
+
+    #[test]
+    fn test_parse_alternative_precedence() {
+        let parser = Parse::new();
+        let filter = parser.parse(".a // .b // 1, 2").unwrap();
+        let expected = FilterFn::Comma(
+            Box::new(FilterFn::Alternative(
+                Box::new(FilterFn::KeyFilter("a".to_string())),
+                Box::new(FilterFn::Alternative(
+                    Box::new(FilterFn::KeyFilter("b".to_string())),
+                    Box::new(FilterFn::Literal(Value::from(1))),
+                )),
+            )),
+            Box::new(FilterFn::Literal(Value::from(2))),
+        );
+        assert_eq!(filter, expected);
+    }
+
+    #[test]
+    fn test_parse_binding_with_patterns() {
+        let parser = Parse::new();
+        let filter = parser.parse(". as {a: $a, $b: [$first]} | $first").unwrap();
+        let expected = FilterFn::Bind {
+            source: Box::new(FilterFn::Identity),
+            pattern: Pattern::Object(vec![
+                ObjectPattern {
+                    key: FilterFn::Literal(Value::from("a")),
+                    variable: None,
+                    pattern: Some(Pattern::Variable("a".to_string())),
+                },
+                ObjectPattern {
+                    key: FilterFn::Literal(Value::from("b")),
+                    variable: Some("b".to_string()),
+                    pattern: Some(Pattern::Array(vec![Pattern::Variable("first".to_string())])),
+                },
+            ]),
+            body: Box::new(FilterFn::Variable("first".to_string())),
+        };
+        assert_eq!(filter, expected);
+    }
+
+    #[test]
+    fn test_parse_binding_scope_ends_with_body() {
+        let parser = Parse::new();
+        assert!(parser.parse("[. as $x | $x, $x]").is_ok());
+        let result = parser.parse("(. as $x | $x), $x");
+        assert!(matches!(
+            result,
+            Err(ParseError::UndefinedVariable { offset: 16, ref name }) if name == "x"
+        ));
+    }
+
+    #[test]
+    fn test_parse_builtin_variables() {
+        let parser = Parse::new();
+        assert_eq!(parser.parse("$ENV").unwrap(), FilterFn::Environment);
+        assert_eq!(parser.parse("env").unwrap(), FilterFn::Environment);
+        let filter = parser.parse("1,\n$__loc__").unwrap();
+        let expected = FilterFn::Comma(
+            Box::new(FilterFn::Literal(Value::from(1))),
+            Box::new(FilterFn::Literal(json!({"file": "<top-level>", "line": 2}))),
+        );
+        assert_eq!(filter, expected);
+    }
+
+    #[test]
+    fn test_parse_predeclared_variables() {
+        let parser = Parse::new().with_variables(vec!["name".to_string()]);
+        assert_eq!(
+            parser.parse("$name").unwrap(),
+            FilterFn::Variable("name".to_string())
+        );
+        assert!(matches!(
+            parser.parse("$other"),
+            Err(ParseError::UndefinedVariable { ref name, .. }) if name == "other"
+        ));
+    }
+
+    #[test]
+    fn test_parse_reduce() {
+        let parser = Parse::new();
+        let filter = parser.parse("reduce .[] as $x (0; . + $x)").unwrap();
+        let expected = FilterFn::Reduce {
+            source: Box::new(FilterFn::ArrayIterator),
+            pattern: Pattern::Variable("x".to_string()),
+            init: Box::new(FilterFn::Literal(Value::from(0))),
+            update: Box::new(FilterFn::Binary(
+                BinOp::Add,
+                Box::new(FilterFn::Identity),
+                Box::new(FilterFn::Variable("x".to_string())),
+            )),
+        };
+        assert_eq!(filter, expected);
+    }
+
+    #[test]
+    fn test_parse_foreach_with_extract() {
+        let parser = Parse::new();
+        let filter = parser.parse("foreach .[] as [$k] (0; 1; $k)").unwrap();
+        let expected = FilterFn::Foreach {
+            source: Box::new(FilterFn::ArrayIterator),
+            pattern: Pattern::Array(vec![Pattern::Variable("k".to_string())]),
+            init: Box::new(FilterFn::Literal(Value::from(0))),
+            update: Box::new(FilterFn::Literal(Value::from(1))),
+            extract: Some(Box::new(FilterFn::Variable("k".to_string()))),
+        };
+        assert_eq!(filter, expected);
+    }
+
+    #[test]
+    fn test_parse_fold_variable_not_visible_in_init() {
+        let parser = Parse::new();
+        let result = parser.parse("reduce .[] as $x ($x; .)");
+        assert!(matches!(
+            result,
+            Err(ParseError::UndefinedVariable { ref name, .. }) if name == "x"
+        ));
+    }
+
+    #[test]
+    fn test_parse_range_arguments() {
+        let parser = Parse::new();
+        let literal = |n: i64| Box::new(FilterFn::Literal(Value::from(n)));
+        assert_eq!(
+            parser.parse("range(5)").unwrap(),
+            FilterFn::Range {
+                from: None,
+                upto: literal(5),
+                by: None
+            }
+        );
+        assert_eq!(
+            parser.parse("range(1; 5; 2)").unwrap(),
+            FilterFn::Range {
+                from: Some(literal(1)),
+                upto: literal(5),
+                by: Some(literal(2))
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_recursive_descent() {
+        let parser = Parse::new();
+        let filter = parser.parse("..").unwrap();
+        assert_eq!(filter, parser.parse("recurse").unwrap());
+        assert!(matches!(filter, FilterFn::Recurse { cond: None, .. }));
+    }
+
+    #[test]
+    fn test_parse_def_with_params() {
+        let parser = Parse::new();
+        let filter = parser.parse("def f(g; $x): g + $x; f(.; 1)").unwrap();
+        let expected = FilterFn::FuncDef {
+            name: "f".to_string(),
+            params: vec![
+                Param::Filter("g".to_string()),
+                Param::Value("x".to_string()),
+            ],
+            body: Rc::new(FilterFn::Binary(
+                BinOp::Add,
+                Box::new(FilterFn::Call {
+                    name: "g".to_string(),
+                    args: vec![],
+                }),
+                Box::new(FilterFn::Variable("x".to_string())),
+            )),
+            rest: Box::new(FilterFn::Call {
+                name: "f".to_string(),
+                args: vec![
+                    Rc::new(FilterFn::Identity),
+                    Rc::new(FilterFn::Literal(Value::from(1))),
+                ],
+            }),
+        };
+        assert_eq!(filter, expected);
+    }
+
+    #[test]
+    fn test_parse_string_interpolation() {
+        let parser = Parse::new();
+        let filter = parser
+            .parse(r#"."a" as $x | "\($x) is \(.b | length)""#)
+            .unwrap();
+        let FilterFn::Bind { body, .. } = filter else {
+            panic!("Expected Bind");
+        };
+        assert_eq!(
+            *body,
+            FilterFn::Interpolate {
+                format: "text".to_string(),
+                parts: vec![
+                    StringPart::Expr(FilterFn::Variable("x".to_string())),
+                    StringPart::Literal(" is ".to_string()),
+                    StringPart::Expr(FilterFn::Pipe(
+                        Box::new(FilterFn::KeyFilter("b".to_string())),
+                        Box::new(FilterFn::Length),
+                    )),
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_interpolation_errors() {
+        let parser = Parse::new();
+        assert!(matches!(
+            parser.parse(r#""\($nope)""#),
+            Err(ParseError::UndefinedVariable { offset: 3, ref name }) if name == "nope"
+        ));
+        assert!(matches!(
+            parser.parse(r#""\(1 2)""#),
+            Err(ParseError::UnexpectedToken { offset: 5, .. })
+        ));
+        assert!(matches!(
+            parser.parse(r#""\()""#),
+            Err(ParseError::UnexpectedEnd { .. })
+        ));
+    }
+
+    #[test]
+    fn test_parse_formats() {
+        let parser = Parse::new();
+        assert_eq!(
+            parser.parse("@text").unwrap(),
+            FilterFn::Format("text".to_string())
+        );
+        assert_eq!(
+            parser.parse(r#"@text "plain""#).unwrap(),
+            FilterFn::Literal(json!("plain"))
+        );
+        assert!(matches!(
+            parser.parse(r#"@text "\(.)""#).unwrap(),
+            FilterFn::Interpolate { ref format, .. } if format == "text"
+        ));
+        assert!(matches!(
+            parser.parse(r#"@csv "row: \(.)""#).unwrap(),
+            FilterFn::Interpolate { ref format, .. } if format == "csv"
+        ));
+        assert!(matches!(
+            parser.parse("@nope"),
+            Err(ParseError::UnknownFormat { offset: 0, ref name }) if name == "nope"
+        ));
+    }
+
+    #[test]
+    fn test_parse_string_builtins() {
+        let parser = Parse::new();
+        assert_eq!(
+            parser.parse("ascii_downcase").unwrap(),
+            FilterFn::Builtin(Builtin::AsciiDowncase)
+        );
+        assert_eq!(
+            parser.parse(r#"split(",")"#).unwrap(),
+            FilterFn::BuiltinArg(BuiltinArg::Split, Box::new(FilterFn::Literal(json!(","))))
+        );
+        assert!(matches!(
+            parser.parse("split"),
+            Err(ParseError::UnknownFunction { ref name, .. }) if name == "split/0"
+        ));
+    }
+
+    #[test]
+    fn test_parse_array_builtins() {
+        let parser = Parse::new();
+        assert_eq!(
+            parser.parse("sort_by(.a)").unwrap(),
+            FilterFn::By(ByOp::Sort, Box::new(FilterFn::KeyFilter("a".to_string())))
+        );
+        assert_eq!(
+            parser.parse("any").unwrap(),
+            FilterFn::Quantify {
+                all: false,
+                generator: Box::new(FilterFn::ArrayIterator),
+                cond: Box::new(FilterFn::Identity),
+            }
+        );
+        assert!(matches!(
+            parser.parse("all(.[]; . > 1)").unwrap(),
+            FilterFn::Quantify { all: true, .. }
+        ));
+        assert_eq!(
+            parser.parse("flatten(1)").unwrap(),
+            FilterFn::BuiltinArg(BuiltinArg::Flatten, Box::new(FilterFn::Literal(json!(1))))
+        );
+        assert_eq!(
+            parser.parse("flatten").unwrap(),
+            FilterFn::Builtin(Builtin::Flatten)
+        );
+    }
+
+    #[test]
+    fn test_parse_math_builtins() {
+        let parser = Parse::new();
+        assert_eq!(
+            parser.parse("floor").unwrap(),
+            FilterFn::Math(MathFn::Floor, Vec::new())
+        );
+        assert_eq!(
+            parser.parse("pow(2; 3)").unwrap(),
+            FilterFn::Math(
+                MathFn::Pow,
+                vec![FilterFn::Literal(json!(2)), FilterFn::Literal(json!(3))]
+            )
+        );
+        // the arity picks the function
+        assert!(parser.parse("pow(2)").is_err());
+        assert_eq!(
+            parser.parse("infinite").unwrap(),
+            FilterFn::Literal(json!(f64::MAX))
+        );
+        assert_eq!(parser.parse("nan").unwrap(), FilterFn::Literal(json!(null)));
+    }
+
+    #[test]
+    fn test_parse_halt() {
+        let parser = Parse::new();
+        assert_eq!(parser.parse("halt").unwrap(), FilterFn::Halt);
+        assert_eq!(
+            parser.parse("halt_error").unwrap(),
+            FilterFn::HaltError(None)
+        );
+        assert_eq!(
+            parser.parse("halt_error(1)").unwrap(),
+            FilterFn::HaltError(Some(Box::new(FilterFn::Literal(json!(1)))))
+        );
+    }
+
+    #[test]
+    fn test_parse_dateadd() {
+        let parser = Parse::new();
+        assert_eq!(
+            parser.parse("datesub(\"seconds\"; 60)").unwrap(),
+            FilterFn::Binary(
+                BinOp::Sub,
+                Box::new(FilterFn::Identity),
+                Box::new(FilterFn::Literal(json!(60)))
+            )
+        );
+        assert_eq!(
+            parser.parse("strptime(\"%Y\")").unwrap(),
+            FilterFn::BuiltinArg(
+                BuiltinArg::Strptime,
+                Box::new(FilterFn::Literal(json!("%Y")))
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_stream_builtins() {
+        let parser = Parse::new();
+        assert_eq!(
+            parser
+                .parse("fromstream(1 | truncate_stream(inputs))")
+                .unwrap(),
+            FilterFn::FromStream(Box::new(FilterFn::Pipe(
+                Box::new(FilterFn::Literal(json!(1))),
+                Box::new(FilterFn::TruncateStream(Box::new(FilterFn::Inputs))),
+            )))
+        );
+        assert_eq!(parser.parse("tostream").unwrap(), FilterFn::ToStream);
+    }
+
+    #[test]
+    fn test_parse_regex_builtins() {
+        let parser = Parse::new();
+        assert_eq!(
+            parser.parse(r#"test("a"; "i")"#).unwrap(),
+            FilterFn::Regex {
+                op: RegexOp::Test,
+                regex: Box::new(FilterFn::Literal(json!("a"))),
+                flags: Some(Box::new(FilterFn::Literal(json!("i")))),
+            }
+        );
+        // `split/1` splits on a plain string, `split/2` on a regex
+        assert!(matches!(
+            parser.parse(r#"split("a")"#).unwrap(),
+            FilterFn::BuiltinArg(BuiltinArg::Split, _)
+        ));
+        assert!(matches!(
+            parser.parse(r#"split("a"; null)"#).unwrap(),
+            FilterFn::Regex {
+                op: RegexOp::Split,
+                ..
+            }
+        ));
+        assert!(matches!(
+            parser.parse(r#"gsub("a"; "b")"#).unwrap(),
+            FilterFn::Sub {
+                flags: None,
+                global: true,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_parse_assignment_precedence() {
+        let parser = Parse::new();
+        // assignment binds tighter than `|` and `,` but looser than arithmetic
+        let filter = parser.parse(".a |= . + 1 | .b").unwrap();
+        let FilterFn::Pipe(lhs, _) = filter else {
+            panic!("Expected Pipe");
+        };
+        assert_eq!(
+            *lhs,
+            FilterFn::Assign {
+                op: AssignOp::Update,
+                lhs: Box::new(FilterFn::KeyFilter("a".to_string())),
+                rhs: Box::new(FilterFn::Binary(
+                    BinOp::Add,
+                    Box::new(FilterFn::Identity),
+                    Box::new(FilterFn::Literal(json!(1))),
+                )),
+            }
+        );
+        assert!(matches!(
+            parser.parse(".a //= 1").unwrap(),
+            FilterFn::Assign {
+                op: AssignOp::Alternative,
+                ..
+            }
+        ));
+        assert!(matches!(
+            parser.parse(".a -= 1").unwrap(),
+            FilterFn::Assign {
+                op: AssignOp::Arithmetic(BinOp::Sub),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_parse_def_shadows_builtin() {
+        let parser = Parse::new();
+        let filter = parser.parse("def length: 1; length").unwrap();
+        match filter {
+            FilterFn::FuncDef { rest, .. } => assert_eq!(
+                *rest,
+                FilterFn::Call {
+                    name: "length".to_string(),
+                    args: vec![]
+                }
+            ),
+            _ => panic!("Expected FuncDef"),
         }
-        match filters[1] {
-            FilterFn::ArrayIndex(index) => assert_eq!(index, 0),
-            _ => panic!("Expected ArrayIndex"),
+    }
+
+    #[test]
+    fn test_parse_def_scope() {
+        let parser = Parse::new();
+        assert!(parser.parse("def f: f; f").is_ok());
+        let result = parser.parse("(def f: 1; f), f");
+        assert!(matches!(
+            result,
+            Err(ParseError::UnknownFunction { offset: 15, ref name }) if name == "f/0"
+        ));
+        let result = parser.parse("def f(g): 1; g");
+        assert!(matches!(result, Err(ParseError::UnknownFunction { .. })));
+    }
+
+    // A fresh directory of module files for one test
+    fn module_dir(test: &str, modules: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("jq-rs-{}-{}", test, std::process::id()));
+        for (name, text) in modules {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, text).unwrap();
         }
-        match &filters[2] {
-            FilterFn::KeyFilter(key) => assert_eq!(key, "name"),
-            _ => panic!("Expected KeyFilter"),
+        dir
+    }
+
+    #[test]
+    fn test_parse_import_and_include() {
+        let dir = module_dir(
+            "import",
+            &[
+                ("lib.jq", "def step: 1; def inc: . + step;"),
+                ("util/util.jq", "def twice(f): f | f;"),
+            ],
+        );
+        let parser = Parse::new().with_library_paths(vec![dir]);
+        let filter = parser
+            .parse(r#"import "lib" as l; include "util"; twice(l::inc)"#)
+            .unwrap();
+        let names = |filter: &FilterFn| match filter {
+            FilterFn::FuncDef { name, rest, .. } => Some((name.clone(), (**rest).clone())),
+            _ => None,
+        };
+        let (first, rest) = names(&filter).unwrap();
+        let (second, rest) = names(&rest).unwrap();
+        let (third, rest) = names(&rest).unwrap();
+        assert_eq!([first, second, third], ["l::step", "l::inc", "twice"]);
+        assert!(matches!(rest, FilterFn::Call { ref name, .. } if name == "twice"));
+
+        // members are only visible under the module name
+        let result = parser.parse(r#"import "lib" as l; inc"#);
+        assert!(matches!(result, Err(ParseError::UnknownFunction { .. })));
+    }
+
+    #[test]
+    fn test_parse_missing_module() {
+        let parser = Parse::new();
+        let result = parser.parse(r#"import "nope" as n; 1"#);
+        assert!(matches!(
+            result,
+            Err(ParseError::ModuleNotFound { offset: 7, ref name }) if name == "nope"
+        ));
+    }
+
+    #[test]
+    fn test_parse_error_in_module() {
+        let dir = module_dir("bad-module", &[("bad.jq", "def f: ;")]);
+        let parser = Parse::new().with_library_paths(vec![dir]);
+        let result = parser.parse(r#"include "bad"; 1"#);
+        assert!(matches!(result, Err(ParseError::Module { .. })));
+    }
+
+    #[test]
+    fn test_parse_pipe_operator() {
+        let parser = Parse::new();
+        let input = ".key | .[0] | .name".to_string();
+        let filter = parser.parse(&input).unwrap();
+        let expected = FilterFn::Pipe(
+            Box::new(FilterFn::KeyFilter("key".to_string())),
+            Box::new(FilterFn::Pipe(
+                Box::new(FilterFn::ArrayIndex(0)),
+                Box::new(FilterFn::KeyFilter("name".to_string())),
+            )),
+        );
+        assert_eq!(filter, expected);
+    }
+
+    #[test]
+    fn test_parse_chained_keys() {
+        let parser = Parse::new();
+        let filter = parser.parse(".a.b").unwrap();
+        let expected = FilterFn::Pipe(
+            Box::new(FilterFn::KeyFilter("a".to_string())),
+            Box::new(FilterFn::KeyFilter("b".to_string())),
+        );
+        assert_eq!(filter, expected);
+    }
+
+    #[test]
+    fn test_parse_nested_parentheses() {
+        let parser = Parse::new();
+        let filter = parser.parse("((.a | (.b)))").unwrap();
+        let expected = FilterFn::Pipe(
+            Box::new(FilterFn::KeyFilter("a".to_string())),
+            Box::new(FilterFn::KeyFilter("b".to_string())),
+        );
+        assert_eq!(filter, expected);
+    }
+
+    #[test]
+    fn test_parse_string_literal_with_pipe() {
+        let parser = Parse::new();
+        let filter = parser.parse(r#". | "a|b""#).unwrap();
+        let expected = FilterFn::Pipe(
+            Box::new(FilterFn::Identity),
+            Box::new(FilterFn::Literal(Value::String("a|b".to_string()))),
+        );
+        assert_eq!(filter, expected);
+    }
+
+    #[test]
+    fn test_parse_del_with_pipe_argument() {
+        let parser = Parse::new();
+        let filter = parser.parse("del(.a | .b) | length").unwrap();
+        match filter {
+            FilterFn::Pipe(lhs, rhs) => {
+                assert!(matches!(*lhs, FilterFn::Del(_)));
+                assert_eq!(*rhs, FilterFn::Length);
+            }
+            _ => panic!("Expected Pipe"),
         }
     }
 
+    #[test]
+    fn test_parse_chained_path() {
+        let parser = Parse::new();
+        let filter = parser.parse(".spec.containers[0].image").unwrap();
+        let expected = FilterFn::Pipe(
+            Box::new(FilterFn::Pipe(
+                Box::new(FilterFn::Pipe(
+                    Box::new(FilterFn::KeyFilter("spec".to_string())),
+                    Box::new(FilterFn::KeyFilter("containers".to_string())),
+                )),
+                Box::new(FilterFn::ArrayIndex(0)),
+            )),
+            Box::new(FilterFn::KeyFilter("image".to_string())),
+        );
+        assert_eq!(filter, expected);
+    }
+
+    #[test]
+    fn test_parse_quoted_keys() {
+        let parser = Parse::new();
+        assert_eq!(
+            parser.parse(r#"."foo-bar""#).unwrap(),
+            FilterFn::KeyFilter("foo-bar".to_string())
+        );
+        assert_eq!(
+            parser.parse(r#".["foo bar"]"#).unwrap(),
+            FilterFn::KeyFilter("foo bar".to_string())
+        );
+        assert_eq!(
+            parser.parse(r#".a."ключ""#).unwrap(),
+            FilterFn::Pipe(
+                Box::new(FilterFn::KeyFilter("a".to_string())),
+                Box::new(FilterFn::KeyFilter("ключ".to_string())),
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_negative_index_and_open_slices() {
+        let parser = Parse::new();
+        assert_eq!(parser.parse(".[-1]").unwrap(), FilterFn::ArrayIndex(-1));
+        assert_eq!(
+            parser.parse(".[:2]").unwrap(),
+            FilterFn::ArraySlice {
+                start: 0,
+                end: Some(2)
+            }
+        );
+        assert_eq!(
+            parser.parse(".[-2:]").unwrap(),
+            FilterFn::ArraySlice {
+                start: -2,
+                end: None
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_iterator_suffixes() {
+        let parser = Parse::new();
+        let filter = parser.parse(".items[][]").unwrap();
+        let expected = FilterFn::Pipe(
+            Box::new(FilterFn::Pipe(
+                Box::new(FilterFn::KeyFilter("items".to_string())),
+                Box::new(FilterFn::ArrayIterator),
+            )),
+            Box::new(FilterFn::ArrayIterator),
+        );
+        assert_eq!(filter, expected);
+    }
+
+    #[test]
+    fn test_parse_computed_index() {
+        let parser = Parse::new();
+        let filter = parser.parse(".a[.i]").unwrap();
+        let expected = FilterFn::Index {
+            target: Box::new(FilterFn::KeyFilter("a".to_string())),
+            index: Box::new(FilterFn::KeyFilter("i".to_string())),
+        };
+        assert_eq!(filter, expected);
+    }
+
+    #[test]
+    fn test_parse_computed_slice() {
+        let parser = Parse::new();
+        let filter = parser.parse(".[.n:]").unwrap();
+        let expected = FilterFn::Slice {
+            target: Box::new(FilterFn::Identity),
+            start: Some(Box::new(FilterFn::KeyFilter("n".to_string()))),
+            end: None,
+        };
+        assert_eq!(filter, expected);
+    }
+
+    #[test]
+    fn test_parse_optional_suffixes() {
+        let parser = Parse::new();
+        let filter = parser.parse(".a?.b[]?").unwrap();
+        let expected = FilterFn::Try(Box::new(FilterFn::Pipe(
+            Box::new(FilterFn::Pipe(
+                Box::new(FilterFn::Try(Box::new(FilterFn::KeyFilter(
+                    "a".to_string(),
+                )))),
+                Box::new(FilterFn::KeyFilter("b".to_string())),
+            )),
+            Box::new(FilterFn::ArrayIterator),
+        )));
+        assert_eq!(filter, expected);
+    }
+
+    #[test]
+    fn test_parse_try_binds_to_postfix_term() {
+        let parser = Parse::new();
+        let filter = parser.parse("try .a | length").unwrap();
+        let expected = FilterFn::Pipe(
+            Box::new(FilterFn::Try(Box::new(FilterFn::KeyFilter(
+                "a".to_string(),
+            )))),
+            Box::new(FilterFn::Length),
+        );
+        assert_eq!(filter, expected);
+    }
+
+    #[test]
+    fn test_parse_comma_binds_tighter_than_pipe() {
+        let parser = Parse::new();
+        let filter = parser.parse(".a, .b | length").unwrap();
+        let expected = FilterFn::Pipe(
+            Box::new(FilterFn::Comma(
+                Box::new(FilterFn::KeyFilter("a".to_string())),
+                Box::new(FilterFn::KeyFilter("b".to_string())),
+            )),
+            Box::new(FilterFn::Length),
+        );
+        assert_eq!(filter, expected);
+    }
+
+    #[test]
+    fn test_parse_comma_is_left_associative() {
+        let parser = Parse::new();
+        let filter = parser.parse(".a, .b, .c").unwrap();
+        let expected = FilterFn::Comma(
+            Box::new(FilterFn::Comma(
+                Box::new(FilterFn::KeyFilter("a".to_string())),
+                Box::new(FilterFn::KeyFilter("b".to_string())),
+            )),
+            Box::new(FilterFn::KeyFilter("c".to_string())),
+        );
+        assert_eq!(filter, expected);
+    }
+
+    #[test]
+    fn test_parse_array_construction() {
+        let parser = Parse::new();
+        assert_eq!(parser.parse("[]").unwrap(), FilterFn::ArrayConstruct(None));
+        let filter = parser.parse("[.[] | .x]").unwrap();
+        let expected = FilterFn::ArrayConstruct(Some(Box::new(FilterFn::Pipe(
+            Box::new(FilterFn::ArrayIterator),
+            Box::new(FilterFn::KeyFilter("x".to_string())),
+        ))));
+        assert_eq!(filter, expected);
+    }
+
+    #[test]
+    fn test_parse_object_construction() {
+        let parser = Parse::new();
+        let filter = parser.parse("{name: .name, id: .meta.id}").unwrap();
+        let expected = FilterFn::ObjectConstruct(vec![
+            (
+                FilterFn::Literal(Value::String("name".to_string())),
+                FilterFn::KeyFilter("name".to_string()),
+            ),
+            (
+                FilterFn::Literal(Value::String("id".to_string())),
+                FilterFn::Pipe(
+                    Box::new(FilterFn::KeyFilter("meta".to_string())),
+                    Box::new(FilterFn::KeyFilter("id".to_string())),
+                ),
+            ),
+        ]);
+        assert_eq!(filter, expected);
+    }
+
+    #[test]
+    fn test_parse_object_shorthand_and_computed_keys() {
+        let parser = Parse::new();
+        let filter = parser.parse(r#"{name, "a b", (.k): .v}"#).unwrap();
+        let expected = FilterFn::ObjectConstruct(vec![
+            (
+                FilterFn::Literal(Value::String("name".to_string())),
+                FilterFn::KeyFilter("name".to_string()),
+            ),
+            (
+                FilterFn::Literal(Value::String("a b".to_string())),
+                FilterFn::KeyFilter("a b".to_string()),
+            ),
+            (
+                FilterFn::KeyFilter("k".to_string()),
+                FilterFn::KeyFilter("v".to_string()),
+            ),
+        ]);
+        assert_eq!(filter, expected);
+    }
+
+    #[test]
+    fn test_parse_object_value_with_pipe() {
+        let parser = Parse::new();
+        let filter = parser.parse("{a: .b | length}").unwrap();
+        let expected = FilterFn::ObjectConstruct(vec![(
+            FilterFn::Literal(Value::String("a".to_string())),
+            FilterFn::Pipe(
+                Box::new(FilterFn::KeyFilter("b".to_string())),
+                Box::new(FilterFn::Length),
+            ),
+        )]);
+        assert_eq!(filter, expected);
+    }
+
+    #[test]
+    fn test_parse_computed_key_requires_value() {
+        let parser = Parse::new();
+        let result = parser.parse("{(.k)}");
+        assert!(matches!(
+            result,
+            Err(ParseError::UnexpectedToken {
+                offset: 5,
+                token: Token::RBrace
+            })
+        ));
+    }
+
+    #[test]
+    fn test_parse_arithmetic_precedence() {
+        let parser = Parse::new();
+        let filter = parser.parse("1 + 2 * 3 - 4").unwrap();
+        let expected = FilterFn::Binary(
+            BinOp::Sub,
+            Box::new(FilterFn::Binary(
+                BinOp::Add,
+                Box::new(FilterFn::Literal(Value::from(1))),
+                Box::new(FilterFn::Binary(
+                    BinOp::Mul,
+                    Box::new(FilterFn::Literal(Value::from(2))),
+                    Box::new(FilterFn::Literal(Value::from(3))),
+                )),
+            )),
+            Box::new(FilterFn::Literal(Value::from(4))),
+        );
+        assert_eq!(filter, expected);
+    }
+
+    #[test]
+    fn test_parse_boolean_precedence() {
+        let parser = Parse::new();
+        let filter = parser.parse(".a == 1 or .b and .c | not").unwrap();
+        let expected = FilterFn::Pipe(
+            Box::new(FilterFn::Or(
+                Box::new(FilterFn::Binary(
+                    BinOp::Eq,
+                    Box::new(FilterFn::KeyFilter("a".to_string())),
+                    Box::new(FilterFn::Literal(Value::from(1))),
+                )),
+                Box::new(FilterFn::And(
+                    Box::new(FilterFn::KeyFilter("b".to_string())),
+                    Box::new(FilterFn::KeyFilter("c".to_string())),
+                )),
+            )),
+            Box::new(FilterFn::Not),
+        );
+        assert_eq!(filter, expected);
+    }
+
+    #[test]
+    fn test_parse_comparisons_do_not_chain() {
+        let parser = Parse::new();
+        assert!(matches!(
+            parser.parse("1 < 2 == true"),
+            Err(ParseError::UnexpectedToken {
+                offset: 6,
+                token: Token::Eq
+            })
+        ));
+        assert!(parser.parse(".a == .b == .c").is_err());
+        assert!(parser.parse("(1 < 2) == true").is_ok());
+        assert!(parser.parse("1 < 2 and 2 > 1").is_ok());
+    }
+
+    #[test]
+    fn test_parse_type_builtins() {
+        let parser = Parse::new();
+        assert_eq!(
+            parser.parse("iterables").unwrap(),
+            FilterFn::OfTypes(&["array", "object"])
+        );
+        assert_eq!(
+            parser.parse("select(type == \"object\")").unwrap(),
+            FilterFn::Select(Box::new(FilterFn::Binary(
+                BinOp::Eq,
+                Box::new(FilterFn::Type),
+                Box::new(FilterFn::Literal(json!("object"))),
+            )))
+        );
+        assert!(parser.parse("paths(numbers)").is_ok());
+        assert!(parser.parse("with_entries(.value |= type)").is_ok());
+        assert!(parser.parse("keys_unsorted, tojson, fromjson").is_ok());
+    }
+
+    #[test]
+    fn test_parse_boolean_literals() {
+        let parser = Parse::new();
+        assert_eq!(
+            parser.parse("true").unwrap(),
+            FilterFn::Literal(Value::Bool(true))
+        );
+        assert_eq!(
+            parser.parse("null").unwrap(),
+            FilterFn::Literal(Value::Null)
+        );
+    }
+
+    #[test]
+    fn test_parse_negation() {
+        let parser = Parse::new();
+        assert_eq!(
+            parser.parse("-1").unwrap(),
+            FilterFn::Literal(Value::from(-1))
+        );
+        assert_eq!(
+            parser.parse("-.a - 1").unwrap(),
+            FilterFn::Binary(
+                BinOp::Sub,
+                Box::new(FilterFn::Neg(Box::new(FilterFn::KeyFilter(
+                    "a".to_string()
+                )))),
+                Box::new(FilterFn::Literal(Value::from(1))),
+            )
+        );
+    }
+
     #[test]
     fn test_parse_invalid_input() {
         let parser = Parse::new();
@@ -248,4 +2196,50 @@ mod tests {
         let result = parser.parse(&input);
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_parse_error_reports_offset_and_token() {
+        let parser = Parse::new();
+        let result = parser.parse(".a | ]");
+        match result {
+            Err(ParseError::UnexpectedToken { offset, token }) => {
+                assert_eq!(offset, 5);
+                assert_eq!(token, Token::RBracket);
+            }
+            _ => panic!("Expected UnexpectedToken"),
+        }
+    }
+
+    #[test]
+    fn test_parse_error_unclosed_parenthesis() {
+        let parser = Parse::new();
+        let result = parser.parse("(.a | .b");
+        assert!(matches!(
+            result,
+            Err(ParseError::UnexpectedEnd { offset: 8 })
+        ));
+    }
+
+    #[test]
+    fn test_parse_error_trailing_pipe() {
+        let parser = Parse::new();
+        let result = parser.parse(".a |");
+        assert!(matches!(
+            result,
+            Err(ParseError::UnexpectedEnd { offset: 4 })
+        ));
+    }
+
+    #[test]
+    fn test_parse_unknown_function_reports_name() {
+        let parser = Parse::new();
+        let result = parser.parse(". | nope");
+        match result {
+            Err(ParseError::UnknownFunction { offset, name }) => {
+                assert_eq!(offset, 4);
+                assert_eq!(name, "nope/0");
+            }
+            _ => panic!("Expected UnknownFunction"),
+        }
+    }
 }
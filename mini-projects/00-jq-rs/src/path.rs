@@ -0,0 +1,664 @@
+use crate::filter::{
+    abbreviate, clamp_position, describe, is_slice, slice_bound, type_name, Filter, FilterError,
+    FilterFn, Sink,
+};
+use crate::ops::{self, is_truthy, BinOp};
+use serde_json::{json, Map, Value};
+
+// The largest index an array may grow to, the same bound as jq's
+pub const MAX_ARRAY_INDEX: i64 = (i32::MAX >> 2) as i64;
+
+// Receives each path a path expression reaches, with the value found there
+pub type PathSink<'a> = dyn FnMut(Vec<Value>, Value) -> Result<(), FilterError> + 'a;
+
+// How `lhs op rhs` changes the values at the paths of `lhs`
+#[derive(Clone, Debug, PartialEq)]
+pub enum AssignOp {
+    // `=`: set them to each output of `rhs`
+    Set,
+    // `|=`: replace them with the first output of `rhs` run on them
+    Update,
+    // `+=`, `-=`, ...: combine them with each output of `rhs`
+    Arithmetic(BinOp),
+    // `//=`: keep them unless false or null
+    Alternative,
+}
+
+fn extend(path: &[Value], component: Value) -> Vec<Value> {
+    let mut path = path.to_vec();
+    path.push(component);
+    path
+}
+
+fn slice_component(start: Option<i64>, end: Option<i64>) -> Value {
+    json!({"start": start, "end": end})
+}
+
+impl FilterFn {
+    // Evaluate the filter as a path expression: instead of values, `out`
+    // receives the location of each output within the input. `path` is where
+    // `value` itself was found.
+    pub fn run_paths(
+        &self,
+        filter: &Filter,
+        path: Vec<Value>,
+        value: Value,
+        out: &mut PathSink,
+    ) -> Result<(), FilterError> {
+        match self {
+            FilterFn::Identity => out(path, value),
+            FilterFn::KeyFilter(key) => {
+                let found = filter.key_filter(value, key)?;
+                out(extend(&path, Value::String(key.clone())), found)
+            }
+            FilterFn::ArrayIndex(index) => {
+                let found = filter.array_index(value, *index)?;
+                out(extend(&path, Value::from(*index)), found)
+            }
+            FilterFn::ArraySlice { start, end } => {
+                let found = filter.array_slice(value, *start, *end)?;
+                out(extend(&path, slice_component(Some(*start), *end)), found)
+            }
+            FilterFn::ArrayIterator => match value {
+                Value::Array(items) => {
+                    for (i, item) in items.into_iter().enumerate() {
+                        out(extend(&path, Value::from(i)), item)?;
+                    }
+                    Ok(())
+                }
+                Value::Object(map) => {
+                    for (key, item) in map {
+                        out(extend(&path, Value::String(key)), item)?;
+                    }
+                    Ok(())
+                }
+                other => Err(FilterError::CannotIterate(describe(&other))),
+            },
+            FilterFn::Index { target, index } => index.run(filter, value.clone(), &mut |key| {
+                target.run_paths(filter, path.clone(), value.clone(), &mut |path, found| {
+                    let found = filter.index(found, &key)?;
+                    out(extend(&path, key.clone()), found)
+                })
+            }),
+            FilterFn::Slice { target, start, end } => {
+                let bounds = |bound: Option<&FilterFn>| match bound {
+                    Some(bound) => bound.apply(filter, vec![value.clone()]),
+                    None => Ok(vec![Value::Null]),
+                };
+                let (starts, ends) = (bounds(start.as_deref())?, bounds(end.as_deref())?);
+                for end in &ends {
+                    for start in &starts {
+                        let start = slice_bound(start, f64::floor)?;
+                        let end = slice_bound(end, f64::ceil)?;
+                        let component = slice_component(start, end);
+                        target.run_paths(
+                            filter,
+                            path.clone(),
+                            value.clone(),
+                            &mut |path, found| {
+                                let found = filter.array_slice(found, start.unwrap_or(0), end)?;
+                                out(extend(&path, component.clone()), found)
+                            },
+                        )?;
+                    }
+                }
+                Ok(())
+            }
+            FilterFn::Pipe(lhs, rhs) => lhs.run_paths(filter, path, value, &mut |path, found| {
+                rhs.run_paths(filter, path, found, out)
+            }),
+            FilterFn::Comma(lhs, rhs) => {
+                lhs.run_paths(filter, path.clone(), value.clone(), out)?;
+                rhs.run_paths(filter, path, value, out)
+            }
+            FilterFn::Select(cond) => cond.run(filter, value.clone(), &mut |c| {
+                if is_truthy(&c) {
+                    out(path.clone(), value.clone())?;
+                }
+                Ok(())
+            }),
+            FilterFn::Values if value.is_null() => Ok(()),
+            FilterFn::Scalars if value.is_array() || value.is_object() => Ok(()),
+            FilterFn::Values | FilterFn::Scalars => out(path, value),
+            FilterFn::Empty => Ok(()),
+            FilterFn::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => cond.run(filter, value.clone(), &mut |c| match else_branch {
+                _ if is_truthy(&c) => {
+                    then_branch.run_paths(filter, path.clone(), value.clone(), out)
+                }
+                Some(else_branch) => {
+                    else_branch.run_paths(filter, path.clone(), value.clone(), out)
+                }
+                None => out(path.clone(), value.clone()),
+            }),
+            FilterFn::Alternative(lhs, rhs) => {
+                let mut found = false;
+                let mut downstream_failed = false;
+                let result = lhs.run_paths(filter, path.clone(), value.clone(), &mut |p, v| {
+                    if !is_truthy(&v) {
+                        return Ok(());
+                    }
+                    found = true;
+                    out(p, v).inspect_err(|_| downstream_failed = true)
+                });
+                match result {
+                    Err(err) if downstream_failed => Err(err),
+                    _ if found => Ok(()),
+                    _ => rhs.run_paths(filter, path, value, out),
+                }
+            }
+            FilterFn::Try(body) => {
+                let mut downstream_failed = false;
+                let result = body.run_paths(filter, path, value, &mut |p, v| {
+                    out(p, v).inspect_err(|_| downstream_failed = true)
+                });
+                match result {
+                    Err(err) if downstream_failed => Err(err),
+                    _ => Ok(()),
+                }
+            }
+            FilterFn::Bind {
+                source,
+                pattern,
+                body,
+            } => source.run(filter, value.clone(), &mut |bound| {
+                filter.destructure(pattern, bound, &value, &mut |scope| {
+                    body.run_paths(&scope, path.clone(), value.clone(), out)
+                })
+            }),
+            FilterFn::FuncDef {
+                name,
+                params,
+                body,
+                rest,
+            } => rest.run_paths(&filter.define_def(name, params, body), path, value, out),
+            FilterFn::Call { name, args } => {
+                filter.with_function(name, args, &value, &mut |scope, body| {
+                    body.run_paths(scope, path.clone(), value.clone(), out)
+                })
+            }
+            FilterFn::First(f) => paths_limited(f, filter, path, value, 1, out),
+            FilterFn::Limit(n, f) => n.run(filter, value.clone(), &mut |n| {
+                let n = match n {
+                    Value::Number(n) => n.as_f64().unwrap_or(0.0),
+                    other => return Err(FilterError::InvalidLimit(describe(&other))),
+                };
+                if n < 0.0 {
+                    return f.run_paths(filter, path.clone(), value.clone(), out);
+                }
+                paths_limited(
+                    f,
+                    filter,
+                    path.clone(),
+                    value.clone(),
+                    n.ceil() as usize,
+                    out,
+                )
+            }),
+            FilterFn::Last(f) => {
+                let mut last = None;
+                f.run_paths(filter, path, value, &mut |p, v| {
+                    last = Some((p, v));
+                    Ok(())
+                })?;
+                match last {
+                    Some((p, v)) => out(p, v),
+                    None => Ok(()),
+                }
+            }
+            FilterFn::Recurse { f, cond } => {
+                let mut stack = vec![(path, value)];
+                while let Some((path, value)) = stack.pop() {
+                    out(path.clone(), value.clone())?;
+                    let mut children = Vec::new();
+                    f.run_paths(filter, path, value, &mut |p, v| {
+                        let keep = match cond {
+                            Some(cond) => {
+                                cond.apply(filter, vec![v.clone()])?.iter().any(is_truthy)
+                            }
+                            None => true,
+                        };
+                        if keep {
+                            children.push((p, v));
+                        }
+                        Ok(())
+                    })?;
+                    stack.extend(children.into_iter().rev());
+                }
+                Ok(())
+            }
+            FilterFn::GetPath(target) => target.run(filter, value.clone(), &mut |target| {
+                let Value::Array(components) = &target else {
+                    return Err(FilterError::PathNotArray);
+                };
+                let found = filter.getpath(&value, components)?;
+                let mut path = path.clone();
+                path.extend(components.iter().cloned());
+                out(path, found)
+            }),
+            // anything else computes values that are not found in the input
+            other => other.run(filter, value, &mut |result| {
+                Err(FilterError::InvalidPath(abbreviate(&result)))
+            }),
+        }
+    }
+
+    // Every path reached by the filter in `input`, in order
+    pub fn collect_paths(
+        &self,
+        filter: &Filter,
+        input: &Value,
+    ) -> Result<Vec<Vec<Value>>, FilterError> {
+        let mut paths = Vec::new();
+        self.run_paths(filter, Vec::new(), input.clone(), &mut |path, _| {
+            paths.push(path);
+            Ok(())
+        })?;
+        Ok(paths)
+    }
+}
+
+// The first `n` paths of `f`, like `limit`
+fn paths_limited(
+    f: &FilterFn,
+    filter: &Filter,
+    path: Vec<Value>,
+    value: Value,
+    n: usize,
+    out: &mut PathSink,
+) -> Result<(), FilterError> {
+    if n == 0 {
+        return Ok(());
+    }
+    let mut count = 0;
+    let mut stopped = false;
+    let result = f.run_paths(filter, path, value, &mut |p, v| {
+        count += 1;
+        out(p, v)?;
+        if count == n {
+            stopped = true;
+            return Err(FilterError::Break);
+        }
+        Ok(())
+    });
+    match result {
+        Err(FilterError::Break) if stopped => Ok(()),
+        other => other,
+    }
+}
+
+// `lhs op rhs`: every output is the input with the paths of `lhs` changed
+pub fn run_assign(
+    op: &AssignOp,
+    lhs: &FilterFn,
+    rhs: &FilterFn,
+    filter: &Filter,
+    input: Value,
+    out: &mut Sink,
+) -> Result<(), FilterError> {
+    let paths = lhs.collect_paths(filter, &input)?;
+    if *op == AssignOp::Update {
+        let mut result = input;
+        let mut deleted = Vec::new();
+        for path in paths {
+            let current = filter.getpath(&result, &path)?;
+            // jq 1.7 semantics: an update producing nothing deletes the path
+            match rhs.run_first(filter, current)? {
+                Some(updated) => result = filter.setpath(result, &path, updated)?,
+                None => deleted.push(Value::Array(path)),
+            }
+        }
+        return out(filter.delpaths(result, deleted)?);
+    }
+    rhs.run(filter, input.clone(), &mut |r| {
+        let mut result = input.clone();
+        for path in &paths {
+            let updated = match op {
+                AssignOp::Set => r.clone(),
+                AssignOp::Arithmetic(op) => op.apply(filter.getpath(&result, path)?, r.clone())?,
+                AssignOp::Alternative => match filter.getpath(&result, path)? {
+                    current if is_truthy(&current) => current,
+                    _ => r.clone(),
+                },
+                AssignOp::Update => unreachable!("updates are handled above"),
+            };
+            result = filter.setpath(result, path, updated)?;
+        }
+        out(result)
+    })
+}
+
+impl Filter {
+    // the value at `path`, null where the path leads past missing keys or null
+    pub fn getpath(&self, value: &Value, path: &[Value]) -> Result<Value, FilterError> {
+        let mut current = value.clone();
+        for component in path {
+            current = match (current, component) {
+                (Value::Null, _) => return Ok(Value::Null),
+                (current, slice) if is_slice(slice) => {
+                    let start = slice_bound(&slice["start"], f64::floor)?;
+                    let end = slice_bound(&slice["end"], f64::ceil)?;
+                    self.array_slice(current, start.unwrap_or(0), end)?
+                }
+                (current, key) => self.index(current, key)?,
+            };
+        }
+        Ok(current)
+    }
+
+    // `value` with whatever is at `path` replaced by `new`, creating the
+    // objects and arrays it passes through where they are missing
+    pub fn setpath(&self, value: Value, path: &[Value], new: Value) -> Result<Value, FilterError> {
+        let Some((component, rest)) = path.split_first() else {
+            return Ok(new);
+        };
+        match (value, component) {
+            (Value::Object(mut map), Value::String(key)) => {
+                let slot = map.entry(key.clone()).or_insert(Value::Null);
+                *slot = self.setpath(std::mem::take(slot), rest, new)?;
+                Ok(Value::Object(map))
+            }
+            (Value::Null, Value::String(_)) => self.setpath(Value::Object(Map::new()), path, new),
+            (Value::Array(mut items), Value::Number(num)) => {
+                let index = num.as_f64().unwrap_or(0.0).floor() as i64;
+                let index = if index < 0 {
+                    index + items.len() as i64
+                } else {
+                    index
+                };
+                if index < 0 {
+                    return Err(FilterError::NegativeIndexOutOfBounds);
+                }
+                if index > MAX_ARRAY_INDEX {
+                    return Err(FilterError::ArrayIndexTooLarge);
+                }
+                let index = index as usize;
+                if index >= items.len() {
+                    items.resize(index + 1, Value::Null);
+                }
+                items[index] = self.setpath(std::mem::take(&mut items[index]), rest, new)?;
+                Ok(Value::Array(items))
+            }
+            (Value::Null, Value::Number(_)) => self.setpath(Value::Array(Vec::new()), path, new),
+            (Value::Array(mut items), slice) if is_slice(slice) => {
+                let range = slice_range(slice, items.len())?;
+                let current = Value::Array(items[range.clone()].to_vec());
+                let Value::Array(replacement) = self.setpath(current, rest, new)? else {
+                    return Err(FilterError::SliceAssignment);
+                };
+                items.splice(range, replacement);
+                Ok(Value::Array(items))
+            }
+            (Value::Null, slice) if is_slice(slice) => {
+                self.setpath(Value::Array(Vec::new()), path, new)
+            }
+            (value, Value::String(key)) => Err(FilterError::CannotIndex(
+                type_name(&value),
+                format!("string {:?}", key),
+            )),
+            (value, component) => Err(FilterError::CannotIndex(
+                type_name(&value),
+                type_name(component).to_string(),
+            )),
+        }
+    }
+
+    // `value` without any of `paths`; later paths are deleted first so that
+    // removing an array element does not shift the ones still to be deleted
+    pub fn delpaths(&self, value: Value, paths: Vec<Value>) -> Result<Value, FilterError> {
+        let mut paths = paths
+            .into_iter()
+            .map(|path| match path {
+                Value::Array(path) => Ok(path),
+                _ => Err(FilterError::PathNotArray),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        paths.sort_by(|a, b| ops::compare(&Value::from(a.clone()), &Value::from(b.clone())));
+        paths.dedup();
+        let mut result = value;
+        for path in paths.iter().rev() {
+            result = self.delpath(result, path)?;
+        }
+        Ok(result)
+    }
+
+    fn delpath(&self, value: Value, path: &[Value]) -> Result<Value, FilterError> {
+        let Some((component, rest)) = path.split_first() else {
+            return Ok(Value::Null);
+        };
+        if value.is_null() {
+            return Ok(Value::Null);
+        }
+        if !rest.is_empty() {
+            // descend, leaving the value alone if there is nothing below
+            let child = self.getpath(&value, std::slice::from_ref(component))?;
+            if child.is_null() {
+                return Ok(value);
+            }
+            let child = self.delpath(child, rest)?;
+            return self.setpath(value, std::slice::from_ref(component), child);
+        }
+        match (value, component) {
+            (Value::Object(mut map), Value::String(key)) => {
+                map.shift_remove(key);
+                Ok(Value::Object(map))
+            }
+            (Value::Array(mut items), Value::Number(num)) => {
+                let index = num.as_f64().unwrap_or(0.0).floor() as i64;
+                let index = if index < 0 {
+                    index + items.len() as i64
+                } else {
+                    index
+                };
+                if (0..items.len() as i64).contains(&index) {
+                    items.remove(index as usize);
+                }
+                Ok(Value::Array(items))
+            }
+            (Value::Array(mut items), slice) if is_slice(slice) => {
+                let range = slice_range(slice, items.len())?;
+                items.drain(range);
+                Ok(Value::Array(items))
+            }
+            (value, component) => Err(FilterError::CannotDelete(
+                type_name(component),
+                type_name(&value),
+            )),
+        }
+    }
+}
+
+// The element range of an array of length `len` covered by a slice component
+fn slice_range(slice: &Value, len: usize) -> Result<std::ops::Range<usize>, FilterError> {
+    let start = slice_bound(&slice["start"], f64::floor)?.unwrap_or(0);
+    let end = slice_bound(&slice["end"], f64::ceil)?;
+    let start = clamp_position(start, len);
+    let end = end.map_or(len, |end| clamp_position(end, len));
+    Ok(start..end.max(start))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn key(name: &str) -> FilterFn {
+        FilterFn::KeyFilter(name.to_string())
+    }
+
+    fn pipe(lhs: FilterFn, rhs: FilterFn) -> FilterFn {
+        FilterFn::Pipe(Box::new(lhs), Box::new(rhs))
+    }
+
+    fn input() -> Value {
+        json!({"a": [{"b": 1}, {"b": 2}], "c": 3})
+    }
+
+    #[test]
+    fn test_paths_of_path_expressions() {
+        let filter = Filter::new();
+        let expr = pipe(pipe(key("a"), FilterFn::ArrayIterator), key("b"));
+        assert_eq!(
+            expr.collect_paths(&filter, &input()).unwrap(),
+            vec![
+                vec![json!("a"), json!(0), json!("b")],
+                vec![json!("a"), json!(1), json!("b")]
+            ]
+        );
+        let select = pipe(
+            FilterFn::ArrayIterator,
+            FilterFn::Select(Box::new(FilterFn::Binary(
+                BinOp::Eq,
+                Box::new(FilterFn::Identity),
+                Box::new(FilterFn::Literal(json!(3))),
+            ))),
+        );
+        assert_eq!(
+            select.collect_paths(&filter, &input()).unwrap(),
+            vec![vec![json!("c")]]
+        );
+    }
+
+    #[test]
+    fn test_paths_through_recursion_and_slices() {
+        let filter = Filter::new();
+        let all = FilterFn::Recurse {
+            f: Box::new(FilterFn::Try(Box::new(FilterFn::ArrayIterator))),
+            cond: None,
+        };
+        assert_eq!(all.collect_paths(&filter, &input()).unwrap().len(), 7);
+        let slice = pipe(
+            key("a"),
+            FilterFn::ArraySlice {
+                start: 1,
+                end: None,
+            },
+        );
+        assert_eq!(
+            slice.collect_paths(&filter, &input()).unwrap(),
+            vec![vec![json!("a"), json!({"start": 1, "end": null})]]
+        );
+    }
+
+    #[test]
+    fn test_invalid_path_expression() {
+        let filter = Filter::new();
+        let result = FilterFn::Literal(json!(1)).collect_paths(&filter, &input());
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Invalid path expression with result 1"
+        );
+        assert!(FilterFn::Empty
+            .collect_paths(&filter, &input())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_getpath() {
+        let filter = Filter::new();
+        let get = |path: Value| filter.getpath(&input(), path.as_array().unwrap());
+        assert_eq!(get(json!(["a", 0, "b"])).unwrap(), json!(1));
+        assert_eq!(get(json!(["x", "y"])).unwrap(), json!(null));
+        assert_eq!(get(json!(["a", -1])).unwrap(), json!({"b": 2}));
+        assert!(get(json!(["a", "b"])).is_err());
+    }
+
+    #[test]
+    fn test_setpath_creates_missing_containers() {
+        let filter = Filter::new();
+        let set = |path: Value, new: Value| filter.setpath(input(), path.as_array().unwrap(), new);
+        assert_eq!(
+            set(json!(["a", 0, "b"]), json!(9)).unwrap(),
+            json!({"a": [{"b": 9}, {"b": 2}], "c": 3})
+        );
+        assert_eq!(
+            set(json!(["z", 2]), json!(1)).unwrap(),
+            json!({"a": [{"b": 1}, {"b": 2}], "c": 3, "z": [null, null, 1]})
+        );
+        assert_eq!(
+            set(json!(["a", {"start": 1, "end": null}]), json!(["x"])).unwrap(),
+            json!({"a": [{"b": 1}, "x"], "c": 3})
+        );
+        assert!(set(json!(["c", "d"]), json!(1)).is_err());
+        assert!(set(json!(["a", -3]), json!(1)).is_err());
+        assert_eq!(
+            set(json!(["a", 1e11]), json!(1)).unwrap_err().to_string(),
+            "Array index too large"
+        );
+    }
+
+    #[test]
+    fn test_delpaths() {
+        let filter = Filter::new();
+        let result = filter
+            .delpaths(
+                input(),
+                vec![json!(["a", 0]), json!(["a", 1, "b"]), json!(["c"])],
+            )
+            .unwrap();
+        assert_eq!(result, json!({"a": [{}]}));
+        let result = filter.delpaths(json!([1, 2, 3]), vec![json!([-1]), json!([5])]);
+        assert_eq!(result.unwrap(), json!([1, 2]));
+        assert!(filter.delpaths(json!({"a": 1}), vec![json!([0])]).is_err());
+    }
+
+    #[test]
+    fn test_delpaths_keeps_key_order() {
+        let filter = Filter::new();
+        let result = filter
+            .delpaths(json!({"a": 1, "b": 2, "c": 3}), vec![json!(["a"])])
+            .unwrap();
+        let keys: Vec<&String> = result.as_object().unwrap().keys().collect();
+        assert_eq!(keys, vec!["b", "c"]);
+    }
+
+    #[test]
+    fn test_assignment_operators() {
+        let filter = Filter::new();
+        let assign = |op: AssignOp, lhs: FilterFn, rhs: FilterFn| {
+            let mut results = Vec::new();
+            run_assign(&op, &lhs, &rhs, &filter, input(), &mut |value| {
+                results.push(value);
+                Ok(())
+            })
+            .map(|_| results)
+        };
+        let each_b = pipe(pipe(key("a"), FilterFn::ArrayIterator), key("b"));
+        let increment = FilterFn::Binary(
+            BinOp::Add,
+            Box::new(FilterFn::Identity),
+            Box::new(FilterFn::Literal(json!(1))),
+        );
+        assert_eq!(
+            assign(AssignOp::Update, each_b.clone(), increment).unwrap(),
+            vec![json!({"a": [{"b": 2}, {"b": 3}], "c": 3})]
+        );
+        assert_eq!(
+            assign(AssignOp::Update, each_b.clone(), FilterFn::Empty).unwrap(),
+            vec![json!({"a": [{}, {}], "c": 3})]
+        );
+        assert_eq!(
+            assign(AssignOp::Set, key("a"), key("c")).unwrap(),
+            vec![json!({"a": 3, "c": 3})]
+        );
+        let one_two = FilterFn::Comma(
+            Box::new(FilterFn::Literal(json!(1))),
+            Box::new(FilterFn::Literal(json!(2))),
+        );
+        assert_eq!(
+            assign(AssignOp::Arithmetic(BinOp::Add), key("c"), one_two).unwrap(),
+            vec![
+                json!({"a": [{"b": 1}, {"b": 2}], "c": 4}),
+                json!({"a": [{"b": 1}, {"b": 2}], "c": 5})
+            ]
+        );
+        assert_eq!(
+            assign(AssignOp::Alternative, key("q"), FilterFn::Literal(json!(5))).unwrap(),
+            vec![json!({"a": [{"b": 1}, {"b": 2}], "c": 3, "q": 5})]
+        );
+    }
+}
@@ -0,0 +1,417 @@
+use crate::filter::{abbreviate, Filter, FilterError, Sink};
+use crate::input::{InputError, Values};
+use serde_json::Value;
+use std::io::BufRead;
+
+// jq's streaming form of a value is a sequence of events: `[path, leaf]` for
+// every scalar and empty container, and `[path]` once a non-empty container
+// ends, `path` then being the path of its last child
+
+// A container being read, holding the key of the child being read
+enum Frame {
+    Array(usize),
+    Object(String),
+}
+
+// Reads JSON as stream events without building the values, so memory use
+// only grows with the nesting depth of the input
+pub struct Events<R> {
+    reader: R,
+    frames: Vec<Frame>,
+    // whether the innermost container has just read a child
+    after_value: bool,
+    line: usize,
+    column: usize,
+    failed: bool,
+}
+
+// The stream events of every JSON value in `reader`, stopping after the first error
+pub fn events<R: BufRead + 'static>(reader: R) -> Values {
+    Box::new(Events::new(reader))
+}
+
+impl<R: BufRead> Events<R> {
+    pub fn new(reader: R) -> Events<R> {
+        Events {
+            reader,
+            frames: Vec::new(),
+            after_value: false,
+            line: 1,
+            column: 0,
+            failed: false,
+        }
+    }
+
+    fn next_event(&mut self) -> Result<Option<Value>, InputError> {
+        loop {
+            self.skip_whitespace()?;
+            if self.frames.is_empty() {
+                if self.peek()?.is_none() {
+                    return Ok(None);
+                }
+            } else if self.after_value {
+                let closing = match self.frames.last() {
+                    Some(Frame::Array(_)) => b']',
+                    _ => b'}',
+                };
+                match self.next_byte()? {
+                    Some(b',') => {
+                        self.after_value = false;
+                        if let Some(Frame::Array(index)) = self.frames.last_mut() {
+                            *index += 1;
+                            continue;
+                        }
+                        let key = self.key()?;
+                        if let Some(Frame::Object(current)) = self.frames.last_mut() {
+                            *current = key;
+                        }
+                        continue;
+                    }
+                    Some(byte) if byte == closing => {
+                        let event = Value::Array(vec![self.path()]);
+                        self.frames.pop();
+                        return Ok(Some(event));
+                    }
+                    Some(_) if closing == b']' => return Err(self.error("expected ',' or ']'")),
+                    Some(_) => return Err(self.error("expected ',' or '}'")),
+                    None => return Err(self.error("unexpected end of input")),
+                }
+            }
+            if let Some(event) = self.value()? {
+                return Ok(Some(event));
+            }
+        }
+    }
+
+    // Read the start of a value: a leaf gives its event and a non-empty
+    // container is entered
+    fn value(&mut self) -> Result<Option<Value>, InputError> {
+        let leaf = match self.peek()? {
+            Some(b'[') => {
+                self.next_byte()?;
+                self.skip_whitespace()?;
+                if self.peek()? != Some(b']') {
+                    self.frames.push(Frame::Array(0));
+                    self.after_value = false;
+                    return Ok(None);
+                }
+                self.next_byte()?;
+                Value::Array(Vec::new())
+            }
+            Some(b'{') => {
+                self.next_byte()?;
+                self.skip_whitespace()?;
+                if self.peek()? != Some(b'}') {
+                    let key = self.key()?;
+                    self.frames.push(Frame::Object(key));
+                    self.after_value = false;
+                    return Ok(None);
+                }
+                self.next_byte()?;
+                Value::Object(Default::default())
+            }
+            Some(b'"') => Value::String(self.string()?),
+            Some(b'-' | b'0'..=b'9') => self.number()?,
+            Some(b'a'..=b'z') => self.literal()?,
+            Some(_) => return Err(self.error("expected a value")),
+            None => return Err(self.error("unexpected end of input")),
+        };
+        self.after_value = true;
+        Ok(Some(Value::Array(vec![self.path(), leaf])))
+    }
+
+    // An object key and the colon after it
+    fn key(&mut self) -> Result<String, InputError> {
+        self.skip_whitespace()?;
+        if self.peek()? != Some(b'"') {
+            return Err(self.error("expected a string key"));
+        }
+        let key = self.string()?;
+        self.skip_whitespace()?;
+        if self.next_byte()? != Some(b':') {
+            return Err(self.error("expected ':'"));
+        }
+        Ok(key)
+    }
+
+    // strings and numbers are delimited here and decoded by serde_json
+    fn string(&mut self) -> Result<String, InputError> {
+        let mut raw = Vec::new();
+        raw.extend(self.next_byte()?);
+        loop {
+            match self.next_byte()? {
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    raw.push(b'\\');
+                    match self.next_byte()? {
+                        Some(byte) => raw.push(byte),
+                        None => return Err(self.error("unterminated string")),
+                    }
+                }
+                Some(byte) => raw.push(byte),
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+        raw.push(b'"');
+        Ok(serde_json::from_slice(&raw)?)
+    }
+
+    fn number(&mut self) -> Result<Value, InputError> {
+        let mut raw = Vec::new();
+        while let Some(byte @ (b'0'..=b'9' | b'-' | b'+' | b'.' | b'e' | b'E')) = self.peek()? {
+            raw.push(byte);
+            self.next_byte()?;
+        }
+        Ok(serde_json::from_slice(&raw)?)
+    }
+
+    fn literal(&mut self) -> Result<Value, InputError> {
+        let mut raw = Vec::new();
+        while let Some(byte @ b'a'..=b'z') = self.peek()? {
+            raw.push(byte);
+            self.next_byte()?;
+        }
+        match raw.as_slice() {
+            b"true" => Ok(Value::Bool(true)),
+            b"false" => Ok(Value::Bool(false)),
+            b"null" => Ok(Value::Null),
+            _ => Err(self.error("invalid literal")),
+        }
+    }
+
+    // The path of the value being read
+    fn path(&self) -> Value {
+        self.frames
+            .iter()
+            .map(|frame| match frame {
+                Frame::Array(index) => Value::from(*index),
+                Frame::Object(key) => Value::String(key.clone()),
+            })
+            .collect()
+    }
+
+    fn skip_whitespace(&mut self) -> Result<(), InputError> {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.peek()? {
+            self.next_byte()?;
+        }
+        Ok(())
+    }
+
+    fn peek(&mut self) -> Result<Option<u8>, InputError> {
+        Ok(self.reader.fill_buf()?.first().copied())
+    }
+
+    fn next_byte(&mut self) -> Result<Option<u8>, InputError> {
+        let byte = self.peek()?;
+        if let Some(byte) = byte {
+            self.reader.consume(1);
+            if byte == b'\n' {
+                self.line += 1;
+                self.column = 0;
+            } else {
+                self.column += 1;
+            }
+        }
+        Ok(byte)
+    }
+
+    fn error(&self, message: &'static str) -> InputError {
+        InputError::Syntax(message, self.line, self.column)
+    }
+}
+
+impl<R: BufRead> Iterator for Events<R> {
+    type Item = Result<Value, InputError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let event = self.next_event();
+        self.failed = event.is_err();
+        event.transpose()
+    }
+}
+
+// The stream events of `value`, as `tostream` emits them
+pub fn to_stream(value: &Value, out: &mut Sink) -> Result<(), FilterError> {
+    stream_value(&mut Vec::new(), value, out)
+}
+
+fn stream_value(path: &mut Vec<Value>, value: &Value, out: &mut Sink) -> Result<(), FilterError> {
+    let children: Vec<(Value, &Value)> = match value {
+        Value::Array(items) if !items.is_empty() => items
+            .iter()
+            .enumerate()
+            .map(|(i, item)| (Value::from(i), item))
+            .collect(),
+        Value::Object(map) if !map.is_empty() => map
+            .iter()
+            .map(|(key, item)| (Value::String(key.clone()), item))
+            .collect(),
+        _ => {
+            return out(Value::Array(vec![
+                Value::Array(path.clone()),
+                value.clone(),
+            ]))
+        }
+    };
+    for (key, child) in &children {
+        path.push(key.clone());
+        stream_value(path, child, out)?;
+        path.pop();
+    }
+    let mut closing = path.clone();
+    closing.extend(children.last().map(|(key, _)| key.clone()));
+    out(Value::Array(vec![Value::Array(closing)]))
+}
+
+// Rebuilds values from their stream events, as `fromstream` does
+#[derive(Default)]
+pub struct FromStream {
+    value: Value,
+}
+
+impl FromStream {
+    // Take one event, giving the value it completes if any
+    pub fn push(&mut self, filter: &Filter, event: Value) -> Result<Option<Value>, FilterError> {
+        let (path, leaf) = split_event(event)?;
+        match leaf {
+            Some(leaf) if path.is_empty() => {
+                self.value = Value::Null;
+                Ok(Some(leaf))
+            }
+            Some(leaf) => {
+                let value = std::mem::take(&mut self.value);
+                self.value = filter.setpath(value, &path, leaf)?;
+                Ok(None)
+            }
+            None if path.len() == 1 => Ok(Some(std::mem::take(&mut self.value))),
+            None => Ok(None),
+        }
+    }
+}
+
+// `event` with the first `depth` components of its path removed, or nothing
+// if its path is not longer than that, as `truncate_stream` emits it
+pub fn truncate(event: Value, depth: usize) -> Result<Option<Value>, FilterError> {
+    let (path, leaf) = split_event(event)?;
+    if path.len() <= depth {
+        return Ok(None);
+    }
+    let mut event = vec![Value::Array(path[depth..].to_vec())];
+    event.extend(leaf);
+    Ok(Some(Value::Array(event)))
+}
+
+fn split_event(event: Value) -> Result<(Vec<Value>, Option<Value>), FilterError> {
+    if let Value::Array(parts) = &event {
+        if let (1 | 2, Some(Value::Array(path))) = (parts.len(), parts.first()) {
+            return Ok((path.clone(), parts.get(1).cloned()));
+        }
+    }
+    Err(FilterError::InvalidStreamEvent(abbreviate(&event)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::io::Cursor;
+
+    fn read_events(content: &str) -> Vec<Value> {
+        events(Cursor::new(content.to_string()))
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    fn to_events(value: &Value) -> Vec<Value> {
+        let mut events = Vec::new();
+        to_stream(value, &mut |event| {
+            events.push(event);
+            Ok(())
+        })
+        .unwrap();
+        events
+    }
+
+    #[test]
+    fn test_events_of_nested_value() {
+        let content = r#"{"a": [1, {"b": null}], "c": [], "d": {}}"#;
+        let expected = vec![
+            json!([["a", 0], 1]),
+            json!([["a", 1, "b"], null]),
+            json!([["a", 1, "b"]]),
+            json!([["a", 1]]),
+            json!([["c"], []]),
+            json!([["d"], {}]),
+            json!([["d"]]),
+        ];
+        assert_eq!(read_events(content), expected);
+        let value: Value = serde_json::from_str(content).unwrap();
+        assert_eq!(to_events(&value), expected);
+    }
+
+    #[test]
+    fn test_events_of_top_level_values() {
+        assert_eq!(
+            read_events("3 \"x\\n\" [] {}\n[true]"),
+            vec![
+                json!([[], 3]),
+                json!([[], "x\n"]),
+                json!([[], []]),
+                json!([[], {}]),
+                json!([[0], true]),
+                json!([[0]]),
+            ]
+        );
+        assert!(read_events(" \n").is_empty());
+    }
+
+    #[test]
+    fn test_events_stop_at_syntax_error() {
+        let mut events = events(Cursor::new("[1, 2 3]"));
+        assert_eq!(events.next().unwrap().unwrap(), json!([[0], 1]));
+        assert_eq!(events.next().unwrap().unwrap(), json!([[1], 2]));
+        let err = events.next().unwrap().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "JSON syntax error: expected ',' or ']' at line 1, column 7"
+        );
+        assert!(events.next().is_none());
+        assert!(read_events_err("[1,"));
+        assert!(read_events_err("{\"a\" 1}"));
+        assert!(read_events_err("nul"));
+        assert!(read_events_err("[\"\\q\"]"));
+    }
+
+    fn read_events_err(content: &str) -> bool {
+        events(Cursor::new(content.to_string())).any(|event| event.is_err())
+    }
+
+    #[test]
+    fn test_from_stream_rebuilds_values() {
+        let filter = Filter::new();
+        let values = vec![json!({"a": [1, {"b": [2]}], "c": "x"}), json!(3), json!([])];
+        let mut from = FromStream::default();
+        let mut rebuilt = Vec::new();
+        for value in &values {
+            for event in to_events(value) {
+                rebuilt.extend(from.push(&filter, event).unwrap());
+            }
+        }
+        assert_eq!(rebuilt, values);
+        assert!(from.push(&filter, json!(1)).is_err());
+    }
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(truncate(json!([[0], 1]), 1).unwrap(), None);
+        assert_eq!(
+            truncate(json!([[1, 0], 2]), 1).unwrap(),
+            Some(json!([[0], 2]))
+        );
+        assert_eq!(truncate(json!([[1, 0]]), 1).unwrap(), Some(json!([[0]])));
+        assert_eq!(truncate(json!([[1]]), 1).unwrap(), None);
+    }
+}
@@ -0,0 +1,212 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+// Filters from the README run against the sample data, each with its
+// expected output in `tests/golden/<name>.out`. The golden files are taken
+// from the README's examples, or from jq where the README has none, and are
+// edited by hand rather than written from this binary's output.
+const CASES: &[(&str, &[&str])] = &[
+    ("all_types_field", &[".fizz", "sample_data/all_types.json"]),
+    (
+        "all_types_pipe",
+        &[".fizzes | .[1]", "sample_data/all_types.json"],
+    ),
+    (
+        "all_types_del",
+        &[". | del(.fizzes)", "sample_data/all_types.json"],
+    ),
+    (
+        "all_types_sort_keys",
+        &[".", "sample_data/all_types.json", "--sort-keys"],
+    ),
+    (
+        "all_types_indent_7",
+        &[".", "sample_data/all_types.json", "--indent", "7"],
+    ),
+    (
+        "all_types_color",
+        &["-C", ".", "sample_data/all_types.json"],
+    ),
+    (
+        "all_types_compact",
+        &[".", "sample_data/all_types.json", "--compact-output"],
+    ),
+    (
+        "annoying_compact",
+        &["-c", ".", "sample_data/annoying.json"],
+    ),
+    ("array_index", &[".[0]", "sample_data/array.json"]),
+    ("array_slice", &[".[0:2]", "sample_data/array.json"]),
+    ("array_add", &[". | add", "sample_data/array.json"]),
+    ("array_length", &[". | length", "sample_data/array.json"]),
+    (
+        "football_names",
+        &[".[] | .name", "sample_data/football.json"],
+    ),
+    (
+        "football_positions",
+        &[
+            "-c",
+            "group_by(.position) | map({position: .[0].position, goals: map(.goals) | add})",
+            "sample_data/football.json",
+        ],
+    ),
+    (
+        "students_names",
+        &[
+            "-r",
+            ".metadata[] | \"\\(.Name): \\(.GPA)\"",
+            "sample_data/students.json",
+        ],
+    ),
+];
+
+fn root() -> &'static Path {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+}
+
+// Runs the binary from the package root, where `sample_data` lives
+fn jq_rs(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_jq-rs"))
+        .args(args)
+        .current_dir(root())
+        .output()
+        .expect("failed to run jq-rs")
+}
+
+// The differences from the golden file, if any
+fn check_golden(name: &str, args: &[&str]) -> Option<String> {
+    let output = jq_rs(args);
+    let golden: PathBuf = root().join("tests/golden").join(format!("{}.out", name));
+    let expected = fs::read(&golden).unwrap_or_default();
+    if !output.status.success() || output.stdout != expected {
+        return Some(format!(
+            "{} ({:?}) exited with {}:\n--- expected\n{}--- actual\n{}{}",
+            name,
+            args,
+            output.status,
+            String::from_utf8_lossy(&expected),
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr),
+        ));
+    }
+    None
+}
+
+#[test]
+fn test_sample_data_identity() {
+    let mut failures = Vec::new();
+    let mut files: Vec<PathBuf> = fs::read_dir(root().join("sample_data"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    files.sort();
+    assert!(!files.is_empty());
+    for path in files {
+        let stem = path.file_stem().unwrap().to_string_lossy();
+        let file = format!("sample_data/{}.json", stem);
+        failures.extend(check_golden(&stem, &[".", &file]));
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+#[test]
+fn test_sample_data_filters() {
+    let failures: Vec<String> = CASES
+        .iter()
+        .filter_map(|(name, args)| check_golden(name, args))
+        .collect();
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+#[test]
+fn test_conflicting_flags() {
+    for args in [
+        &["-C", "-M", ".", "sample_data/array.json"][..],
+        &[
+            "--compact-output",
+            "--indent",
+            "3",
+            ".",
+            "sample_data/array.json",
+        ],
+    ] {
+        let output = jq_rs(args);
+        assert_eq!(output.status.code(), Some(2), "{:?}", args);
+        assert!(String::from_utf8_lossy(&output.stderr).contains("cannot be used with"));
+        assert!(output.stdout.is_empty());
+    }
+}
+
+#[test]
+fn test_indent_range() {
+    for indent in ["8", "-1", "two"] {
+        let output = jq_rs(&["--indent", indent, ".", "sample_data/array.json"]);
+        assert_eq!(output.status.code(), Some(2), "--indent {}", indent);
+    }
+    let output = jq_rs(&["--indent", "0", ".[0:1]", "sample_data/array.json"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "[\n\"one\"\n]\n");
+}
+
+#[test]
+fn test_exit_codes() {
+    let status = |args: &[&str]| jq_rs(args).status.code();
+    assert_eq!(status(&["-n", "1"]), Some(0));
+    assert_eq!(status(&["-n", ".["]), Some(3));
+    assert_eq!(status(&["-n", "error(\"x\")"]), Some(5));
+    assert_eq!(status(&[".", "sample_data/missing.json"]), Some(2));
+    assert_eq!(status(&["-en", "1, null"]), Some(1));
+    assert_eq!(status(&["-en", "empty"]), Some(4));
+    assert_eq!(status(&["-n", "\"bye\" | halt_error(7)"]), Some(7));
+}
+
+#[test]
+fn test_filter_starting_with_hyphen() {
+    let output = jq_rs(&["-n", "-1|length"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "1\n");
+    let output = jq_rs(&["-.bizz", "sample_data/all_types.json", "-c"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "-22\n");
+}
+
+#[test]
+fn test_positional_arguments_follow_their_flag() {
+    let output = jq_rs(&[
+        "-nc",
+        "[$ARGS.positional, input[0]]",
+        "sample_data/array.json",
+        "--args",
+        "a",
+        "--jsonargs",
+        "1",
+    ]);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "[[\"a\",1],\"one\"]\n"
+    );
+}
+
+#[test]
+fn test_program_argument_aliases() {
+    let output = jq_rs(&[
+        "-nc",
+        "[$__prog_args == $ARGS, $named, $ARGS.named]",
+        "--arg",
+        "a",
+        "1",
+        "--args",
+        "x",
+    ]);
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "[true,{\"a\":\"1\"},{\"a\":\"1\"}]\n"
+    );
+    let output = jq_rs(&["-n", "$named", "--arg", "named", "mine"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "\"mine\"\n");
+}